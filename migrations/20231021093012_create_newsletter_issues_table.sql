@@ -0,0 +1,10 @@
+-- migrations/20231021093012_create_newsletter_issues_table.sql
+CREATE TABLE newsletter_issues (
+    newsletter_issue_id uuid NOT NULL,
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    tracking_enabled BOOLEAN NOT NULL,
+    published_at timestamptz NOT NULL,
+    PRIMARY KEY (newsletter_issue_id)
+);
@@ -0,0 +1,15 @@
+-- migrations/20231021094517_create_newsletter_tracking_events_table.sql
+CREATE TABLE newsletter_tracking_events (
+    event_id uuid NOT NULL,
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    -- Either 'open' or 'click'
+    kind TEXT NOT NULL,
+    -- The original link target, only set for clicks
+    url TEXT NULL,
+    occurred_at timestamptz NOT NULL,
+    PRIMARY KEY (event_id)
+);
+CREATE INDEX newsletter_tracking_events_issue_idx
+    ON newsletter_tracking_events (newsletter_issue_id);
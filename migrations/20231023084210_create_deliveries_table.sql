@@ -0,0 +1,15 @@
+-- migrations/20231023084210_create_deliveries_table.sql
+CREATE TABLE deliveries (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    n_attempts INT NOT NULL,
+    -- One of 'pending', 'sent' or 'failed'
+    status TEXT NOT NULL,
+    provider_message_id TEXT NULL,
+    last_error TEXT NULL,
+    created_at timestamptz NOT NULL,
+    updated_at timestamptz NOT NULL,
+    sent_at timestamptz NULL,
+    PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
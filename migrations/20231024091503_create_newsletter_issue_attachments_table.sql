@@ -0,0 +1,12 @@
+-- migrations/20231024091503_create_newsletter_issue_attachments_table.sql
+CREATE TABLE newsletter_issue_attachments (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    -- Set for images embedded in the HTML body as `cid:<content_id>`
+    content_id TEXT NULL,
+    -- Base64-encoded, as sent to the email provider
+    content TEXT NOT NULL,
+    PRIMARY KEY (newsletter_issue_id, name)
+);
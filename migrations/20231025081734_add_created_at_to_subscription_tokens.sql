@@ -0,0 +1,3 @@
+-- migrations/20231025081734_add_created_at_to_subscription_tokens.sql
+-- Tokens issued before this migration start their lifetime now.
+ALTER TABLE subscription_tokens ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
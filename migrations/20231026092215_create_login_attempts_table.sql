@@ -0,0 +1,11 @@
+-- migrations/20231026092215_create_login_attempts_table.sql
+-- Failed login attempts, tracked per username and per client IP.
+CREATE TABLE login_attempts (
+    -- One of 'username' or 'ip'
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    failed_attempts INT NOT NULL,
+    last_failed_at timestamptz NOT NULL,
+    locked_until timestamptz NULL,
+    PRIMARY KEY (scope, key)
+);
@@ -0,0 +1,14 @@
+-- migrations/20231027083040_add_two_factor_authentication.sql
+-- The base32 TOTP secret, set once enrollment started.
+ALTER TABLE users ADD COLUMN totp_secret TEXT NULL;
+-- Only true once a first code was verified.
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT false;
+-- The time step of the last code used, so that codes cannot be replayed.
+ALTER TABLE users ADD COLUMN totp_last_used_step BIGINT NULL;
+
+CREATE TABLE recovery_codes (
+    user_id uuid NOT NULL REFERENCES users (user_id),
+    code_hash TEXT NOT NULL,
+    used_at timestamptz NULL,
+    PRIMARY KEY (user_id, code_hash)
+);
@@ -0,0 +1,12 @@
+-- migrations/20231028101522_create_api_tokens_table.sql
+CREATE TABLE api_tokens (
+    token_id uuid PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id),
+    name TEXT NOT NULL,
+    -- The SHA-256 of the token: tokens are random enough not to need a salt.
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    created_at timestamptz NOT NULL,
+    last_used_at timestamptz NULL,
+    revoked_at timestamptz NULL
+);
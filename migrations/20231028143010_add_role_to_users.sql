@@ -0,0 +1,6 @@
+-- migrations/20231028143010_add_role_to_users.sql
+-- Existing users were allowed to do everything: they become owners.
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'owner'
+    CHECK (role IN ('owner', 'editor', 'viewer'));
+-- Users added from now on are only given the rights they are granted.
+ALTER TABLE users ALTER COLUMN role SET DEFAULT 'viewer';
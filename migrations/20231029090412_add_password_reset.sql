@@ -0,0 +1,13 @@
+-- migrations/20231029090412_add_password_reset.sql
+-- Where password reset links are sent. Users without one cannot reset their password.
+ALTER TABLE users ADD COLUMN email TEXT NULL UNIQUE;
+-- Logins started before it are no longer valid.
+ALTER TABLE users ADD COLUMN password_changed_at timestamptz NULL;
+
+CREATE TABLE password_reset_tokens (
+    token_hash TEXT PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id),
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    used_at timestamptz NULL
+);
@@ -0,0 +1,13 @@
+-- migrations/20231029143551_create_invitations_table.sql
+CREATE TABLE invitations (
+    invitation_id uuid PRIMARY KEY,
+    email TEXT NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+    invited_by uuid NOT NULL REFERENCES users (user_id),
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    -- The user created when the invitation was accepted.
+    accepted_by uuid NULL REFERENCES users (user_id),
+    accepted_at timestamptz NULL,
+    revoked_at timestamptz NULL
+);
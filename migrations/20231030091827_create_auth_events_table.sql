@@ -0,0 +1,25 @@
+-- migrations/20231030091827_create_auth_events_table.sql
+CREATE TABLE auth_events (
+    event_id uuid PRIMARY KEY,
+    kind TEXT NOT NULL CHECK (
+        kind IN (
+            'login_succeeded',
+            'login_failed',
+            'locked_out',
+            'password_changed',
+            'api_token_created',
+            'api_token_revoked'
+        )
+    ),
+    -- NULL when the username tried does not exist, or for lockouts of a client IP.
+    user_id uuid NULL REFERENCES users (user_id),
+    username TEXT NULL,
+    -- NULL for events caused by the admin command line.
+    ip TEXT NULL,
+    user_agent TEXT NULL,
+    request_id TEXT NULL,
+    detail TEXT NULL,
+    occurred_at timestamptz NOT NULL
+);
+CREATE INDEX auth_events_occurred_at_idx ON auth_events (occurred_at);
+CREATE INDEX auth_events_user_id_idx ON auth_events (user_id, occurred_at);
@@ -0,0 +1,4 @@
+-- migrations/20231030154210_add_oidc_subject_to_users.sql
+-- The subject identifier of the user at the OpenID Connect provider, once
+-- they logged in with it.
+ALTER TABLE users ADD COLUMN oidc_subject TEXT NULL UNIQUE;
@@ -0,0 +1,6 @@
+-- migrations/20231031081530_add_repeats_to_auth_events.sql
+-- Attempts turned away by the login throttle are counted on a single event
+-- per username and client, rather than each adding a row.
+ALTER TABLE auth_events ADD COLUMN occurrences INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE auth_events ADD COLUMN last_occurred_at timestamptz NULL;
+CREATE INDEX auth_events_username_idx ON auth_events (username, occurred_at);
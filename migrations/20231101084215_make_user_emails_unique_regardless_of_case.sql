@@ -0,0 +1,5 @@
+-- migrations/20231101084215_make_user_emails_unique_regardless_of_case.sql
+-- Users are looked up by email address regardless of case, so no two of them
+-- may have the same address in different cases.
+ALTER TABLE users DROP CONSTRAINT users_email_key;
+CREATE UNIQUE INDEX users_lower_email_key ON users (lower(email));
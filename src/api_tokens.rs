@@ -0,0 +1,239 @@
+//! src/api_tokens.rs
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Tokens start with it, so that they are easy to recognise, e.g. by secret scanners.
+const TOKEN_PREFIX: &str = "bb_";
+const TOKEN_LENGTH: usize = 40;
+
+/// What an API token may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiTokenScope {
+    Publish,
+    ReadSubscribers,
+}
+
+impl ApiTokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Publish => "publish",
+            Self::ReadSubscribers => "read_subscribers",
+        }
+    }
+}
+
+impl std::fmt::Display for ApiTokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for ApiTokenScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "publish" => Ok(Self::Publish),
+            "read_subscribers" => Ok(Self::ReadSubscribers),
+            other => Err(format!("{} is not a known API token scope.", other)),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApiTokenError {
+    #[error("The API token is invalid or has been revoked.")]
+    InvalidToken,
+    #[error("The API token is missing the {0} scope.")]
+    MissingScope(ApiTokenScope),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// An API token as listed to its owner, without the token itself.
+#[derive(serde::Serialize)]
+pub struct ApiTokenSummary {
+    pub token_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiTokenScope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// How random tokens are stored: they are too long to guess for a fast hash
+/// not to be enough.
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn parse_scopes(scopes: Vec<String>) -> Vec<ApiTokenScope> {
+    // Scopes this version does not know about grant nothing.
+    scopes.iter().filter_map(|s| s.parse().ok()).collect()
+}
+
+/// Creates an API token for a user. The token is only ever returned here:
+/// only its hash is stored.
+#[tracing::instrument(name = "Create an API token", skip(pool))]
+pub async fn create_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    name: &str,
+    scopes: &[ApiTokenScope],
+) -> Result<(Uuid, Secret<String>), anyhow::Error> {
+    let token_id = Uuid::new_v4();
+    let random: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let token = format!("{}{}", TOKEN_PREFIX, random);
+    let scopes: Vec<String> = scopes.iter().map(|s| s.as_str().to_owned()).collect();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO api_tokens (token_id, user_id, name, token_hash, scopes, created_at)
+        VALUES ($1, $2, $3, $4, $5, now())
+        "#,
+        token_id,
+        user_id,
+        name,
+        hash_token(&token),
+        &scopes
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the API token.")?;
+
+    tracing::info!(target: "audit", %user_id, %token_id, name, ?scopes, "Created an API token");
+    Ok((token_id, Secret::new(token)))
+}
+
+/// Revokes one of the tokens of a user, returning whether there was one to revoke.
+#[tracing::instrument(name = "Revoke an API token", skip(pool))]
+pub async fn revoke_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    token_id: Uuid,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE api_tokens
+        SET revoked_at = now()
+        WHERE token_id = $1 AND user_id = $2 AND revoked_at IS NULL
+        "#,
+        token_id,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to revoke the API token.")?;
+
+    let revoked = result.rows_affected() > 0;
+    if revoked {
+        tracing::info!(target: "audit", %user_id, %token_id, "Revoked an API token");
+    }
+    Ok(revoked)
+}
+
+/// The tokens of a user that have not been revoked.
+#[tracing::instrument(name = "List API tokens", skip(pool))]
+pub async fn list_tokens(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Vec<ApiTokenSummary>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT token_id, name, scopes, created_at, last_used_at
+        FROM api_tokens
+        WHERE user_id = $1 AND revoked_at IS NULL
+        ORDER BY created_at
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve the API tokens.")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| ApiTokenSummary {
+            token_id: row.token_id,
+            name: row.name,
+            scopes: parse_scopes(row.scopes),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        })
+        .collect())
+}
+
+/// Returns the user a token belongs to, if it is valid and has the scope,
+/// and records that it has been used.
+#[tracing::instrument(
+    name = "Authenticate an API token",
+    skip(pool, token),
+    fields(token_id=tracing::field::Empty)
+)]
+pub async fn authenticate_token(
+    pool: &PgPool,
+    token: &Secret<String>,
+    scope: ApiTokenScope,
+) -> Result<Uuid, ApiTokenError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT token_id, user_id, scopes
+        FROM api_tokens
+        WHERE token_hash = $1 AND revoked_at IS NULL
+        "#,
+        hash_token(token.expose_secret())
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the API token.")?
+    .ok_or(ApiTokenError::InvalidToken)?;
+    tracing::Span::current().record("token_id", tracing::field::display(&row.token_id));
+
+    if !parse_scopes(row.scopes).contains(&scope) {
+        return Err(ApiTokenError::MissingScope(scope));
+    }
+
+    sqlx::query!(
+        "UPDATE api_tokens SET last_used_at = now() WHERE token_id = $1",
+        row.token_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record the API token use.")?;
+
+    Ok(row.user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_token, parse_scopes, ApiTokenScope};
+
+    #[test]
+    fn scopes_round_trip_through_their_names() {
+        for scope in [ApiTokenScope::Publish, ApiTokenScope::ReadSubscribers] {
+            assert_eq!(scope.as_str().parse::<ApiTokenScope>(), Ok(scope));
+        }
+    }
+
+    #[test]
+    fn unknown_scopes_grant_nothing() {
+        let scopes = parse_scopes(vec!["publish".into(), "delete_everything".into()]);
+        assert_eq!(scopes, vec![ApiTokenScope::Publish]);
+    }
+
+    #[test]
+    fn tokens_are_hashed_consistently() {
+        assert_eq!(hash_token("bb_token"), hash_token("bb_token"));
+        assert_ne!(hash_token("bb_token"), hash_token("bb_other"));
+    }
+}
@@ -1,10 +1,18 @@
 use axum::extract::FromRef;
+use axum_extra::extract::cookie::Key;
 use sqlx::PgPool;
 use std::sync::Arc;
 
 use crate::{
+    authentication::PasswordHashing,
+    background_tasks::BackgroundTasks,
+    configuration::{HealthCheckSettings, NewsletterSettings, PasswordHashingSettings},
     email_client::EmailClient,
-    startup::{ApplicationBaseUrl, HmacSecret},
+    login_throttle::LoginThrottle,
+    metrics::Metrics,
+    oidc::OidcClient,
+    startup::{ApplicationBaseUrl, HmacSecret, MetricsToken, WebhookSecret},
+    subscription_protection::SubscriptionProtection,
 };
 
 #[derive(Clone)]
@@ -13,22 +21,18 @@ pub struct ApplicationState {
     pub email_client: EmailClientState,
     pub base_url: BaseUrlState,
     pub hmac_secret: HmacSecret,
-}
-
-impl ApplicationState {
-    pub fn new(
-        db_pool: PgPool,
-        email_client: Arc<EmailClient>,
-        base_url: Arc<ApplicationBaseUrl>,
-        hmac_secret: HmacSecret,
-    ) -> Self {
-        Self {
-            db_pool,
-            email_client: EmailClientState::new(email_client),
-            base_url: BaseUrlState::new(base_url),
-            hmac_secret,
-        }
-    }
+    pub newsletter: NewsletterSettings,
+    pub webhook_secret: WebhookSecret,
+    pub metrics_token: MetricsToken,
+    pub subscription_protection: Arc<SubscriptionProtection>,
+    pub login_throttle: LoginThrottle,
+    pub password_hashing: PasswordHashing,
+    /// Signs the cookies the application relies on.
+    pub cookie_key: Key,
+    pub oidc: OidcClientState,
+    pub health_checks: HealthCheckSettings,
+    pub metrics: Arc<Metrics>,
+    pub background_tasks: BackgroundTasks,
 }
 
 impl FromRef<ApplicationState> for EmailClientState {
@@ -43,12 +47,78 @@ impl FromRef<ApplicationState> for PgPool {
     }
 }
 
+impl FromRef<ApplicationState> for NewsletterSettings {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.newsletter.clone()
+    }
+}
+
 impl FromRef<ApplicationState> for HmacSecret {
     fn from_ref(input: &ApplicationState) -> Self {
         input.hmac_secret.clone()
     }
 }
 
+impl FromRef<ApplicationState> for WebhookSecret {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.webhook_secret.clone()
+    }
+}
+
+impl FromRef<ApplicationState> for MetricsToken {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.metrics_token.clone()
+    }
+}
+
+impl FromRef<ApplicationState> for Arc<SubscriptionProtection> {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.subscription_protection.clone()
+    }
+}
+
+impl FromRef<ApplicationState> for Arc<Metrics> {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.metrics.clone()
+    }
+}
+
+impl FromRef<ApplicationState> for PasswordHashing {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.password_hashing.clone()
+    }
+}
+
+impl FromRef<ApplicationState> for PasswordHashingSettings {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.password_hashing.settings
+    }
+}
+
+impl FromRef<ApplicationState> for HealthCheckSettings {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.health_checks
+    }
+}
+
+impl FromRef<ApplicationState> for LoginThrottle {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.login_throttle.clone()
+    }
+}
+
+impl FromRef<ApplicationState> for Key {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.cookie_key.clone()
+    }
+}
+
+impl FromRef<ApplicationState> for OidcClientState {
+    fn from_ref(input: &ApplicationState) -> Self {
+        input.oidc.clone()
+    }
+}
+
 #[derive(Clone)]
 pub struct EmailClientState(pub Arc<EmailClient>);
 
@@ -66,3 +136,7 @@ impl BaseUrlState {
         Self(base_url)
     }
 }
+
+/// The OpenID Connect client, if logging in through a provider is enabled.
+#[derive(Clone)]
+pub struct OidcClientState(pub Option<Arc<OidcClient>>);
@@ -0,0 +1,350 @@
+//! src/auth_events.rs
+
+use std::net::IpAddr;
+
+use anyhow::Context;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts, StatusCode};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use tower_http::request_id::RequestId;
+use uuid::Uuid;
+
+use crate::client_ip::ClientIp;
+
+/// The most events listed at once, whatever the caller asks for.
+const MAX_EVENTS_LISTED: i64 = 1000;
+const DEFAULT_EVENTS_LISTED: i64 = 100;
+/// How long repeated events are counted on the first one.
+const REPEATED_EVENTS_WINDOW_MINUTES: i32 = 60;
+
+/// What happened, as far as the security of accounts goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthEventKind {
+    LoginSucceeded,
+    LoginFailed,
+    LockedOut,
+    PasswordChanged,
+    ApiTokenCreated,
+    ApiTokenRevoked,
+}
+
+impl AuthEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::LoginSucceeded => "login_succeeded",
+            Self::LoginFailed => "login_failed",
+            Self::LockedOut => "locked_out",
+            Self::PasswordChanged => "password_changed",
+            Self::ApiTokenCreated => "api_token_created",
+            Self::ApiTokenRevoked => "api_token_revoked",
+        }
+    }
+}
+
+impl std::fmt::Display for AuthEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for AuthEventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "login_succeeded" => Ok(Self::LoginSucceeded),
+            "login_failed" => Ok(Self::LoginFailed),
+            "locked_out" => Ok(Self::LockedOut),
+            "password_changed" => Ok(Self::PasswordChanged),
+            "api_token_created" => Ok(Self::ApiTokenCreated),
+            "api_token_revoked" => Ok(Self::ApiTokenRevoked),
+            other => Err(format!("{} is not a known authentication event.", other)),
+        }
+    }
+}
+
+/// Who sent the request an event happened during: the client address, its
+/// user agent, and the id the request was given by the `x-request-id` layer.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub client_ip: IpAddr,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl RequestContext {
+    pub fn from_parts(parts: &Parts) -> Result<Self, anyhow::Error> {
+        let ClientIp(client_ip) =
+            ClientIp::from_parts(parts).context("The client address is missing.")?;
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let request_id = parts
+            .extensions
+            .get::<RequestId>()
+            .and_then(|id| id.header_value().to_str().ok())
+            .map(str::to_owned);
+        Ok(Self {
+            client_ip,
+            user_agent,
+            request_id,
+        })
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for RequestContext
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_parts(parts).map_err(|e| {
+            tracing::error!(error.cause_chain = ?e, "Failed to read the request context");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+}
+
+/// An event to record. Events caused by the admin command line have no request.
+pub struct AuthEvent<'a> {
+    pub kind: AuthEventKind,
+    /// The user id and the username are looked up from one another when
+    /// only one is known.
+    pub user_id: Option<Uuid>,
+    pub username: Option<&'a str>,
+    pub request: Option<&'a RequestContext>,
+    pub detail: Option<String>,
+}
+
+/// Persists an event, on top of logging it.
+#[tracing::instrument(
+    name = "Record an authentication event",
+    skip(pool, event),
+    fields(kind = %event.kind)
+)]
+pub async fn record(pool: &PgPool, event: AuthEvent<'_>) -> Result<(), anyhow::Error> {
+    let request = event.request;
+    sqlx::query!(
+        r#"
+        INSERT INTO auth_events (
+            event_id, kind, user_id, username, ip, user_agent, request_id, detail, occurred_at
+        )
+        VALUES (
+            $1,
+            $2,
+            COALESCE($3, (SELECT user_id FROM users WHERE username = $4)),
+            COALESCE($4, (SELECT username FROM users WHERE user_id = $3)),
+            $5,
+            $6,
+            $7,
+            $8,
+            now()
+        )
+        "#,
+        Uuid::new_v4(),
+        event.kind.as_str(),
+        event.user_id,
+        event.username,
+        request.map(|r| r.client_ip.to_string()),
+        request.and_then(|r| r.user_agent.as_deref()),
+        request.and_then(|r| r.request_id.as_deref()),
+        event.detail.as_deref()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record an authentication event.")?;
+
+    tracing::info!(
+        target: "audit",
+        kind = %event.kind,
+        user_id = ?event.user_id,
+        username = ?event.username,
+        detail = ?event.detail,
+        "Recorded an authentication event"
+    );
+    Ok(())
+}
+
+/// Records an event, unless the same one happened from the same client within
+/// the last hour: that one is counted once more instead. Requests turned away
+/// by the login throttle would otherwise add a row each, for as long as a
+/// client keeps trying.
+#[tracing::instrument(
+    name = "Record a repeated authentication event",
+    skip(pool, event),
+    fields(kind = %event.kind)
+)]
+pub async fn record_repeated(pool: &PgPool, event: AuthEvent<'_>) -> Result<(), anyhow::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE auth_events
+        SET occurrences = occurrences + 1, last_occurred_at = now()
+        WHERE event_id = (
+            SELECT event_id FROM auth_events
+            WHERE kind = $1
+                AND username IS NOT DISTINCT FROM $2
+                AND ip IS NOT DISTINCT FROM $3
+                AND detail IS NOT DISTINCT FROM $4
+                AND occurred_at > now() - make_interval(mins => $5)
+            ORDER BY occurred_at DESC
+            LIMIT 1
+        )
+        "#,
+        event.kind.as_str(),
+        event.username,
+        event.request.map(|r| r.client_ip.to_string()),
+        event.detail.as_deref(),
+        REPEATED_EVENTS_WINDOW_MINUTES
+    )
+    .execute(pool)
+    .await
+    .context("Failed to count a repeated authentication event.")?;
+
+    if result.rows_affected() == 0 {
+        record(pool, event).await
+    } else {
+        Ok(())
+    }
+}
+
+/// Which events to list. Every filter is optional.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct AuthEventFilter {
+    pub kind: Option<AuthEventKind>,
+    pub user_id: Option<Uuid>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RecordedAuthEvent {
+    pub event_id: Uuid,
+    pub kind: AuthEventKind,
+    pub user_id: Option<Uuid>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub detail: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+    /// How many times the event happened, when repeats were counted on it.
+    pub occurrences: i32,
+    pub last_occurred_at: Option<DateTime<Utc>>,
+}
+
+/// Lists the events matching a filter, most recent first.
+#[tracing::instrument(name = "List authentication events", skip(pool))]
+pub async fn list_events(
+    pool: &PgPool,
+    filter: &AuthEventFilter,
+) -> Result<Vec<RecordedAuthEvent>, anyhow::Error> {
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_EVENTS_LISTED)
+        .clamp(1, MAX_EVENTS_LISTED);
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            event_id, kind, user_id, username, ip, user_agent, request_id, detail, occurred_at,
+            occurrences, last_occurred_at
+        FROM auth_events
+        WHERE ($1::TEXT IS NULL OR kind = $1)
+            AND ($2::uuid IS NULL OR user_id = $2)
+            AND ($3::TEXT IS NULL OR username = $3)
+            AND ($4::TEXT IS NULL OR ip = $4)
+            AND ($5::timestamptz IS NULL OR occurred_at >= $5)
+            AND ($6::timestamptz IS NULL OR occurred_at < $6)
+        ORDER BY occurred_at DESC
+        LIMIT $7
+        "#,
+        filter.kind.map(|kind| kind.as_str()),
+        filter.user_id,
+        filter.username.as_deref(),
+        filter.ip.as_deref(),
+        filter.since,
+        filter.until,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve authentication events.")?;
+
+    rows.into_iter()
+        .map(|r| {
+            Ok(RecordedAuthEvent {
+                event_id: r.event_id,
+                kind: r.kind.parse().map_err(anyhow::Error::msg)?,
+                user_id: r.user_id,
+                username: r.username,
+                ip: r.ip,
+                user_agent: r.user_agent,
+                request_id: r.request_id,
+                detail: r.detail,
+                occurred_at: r.occurred_at,
+                occurrences: r.occurrences,
+                last_occurred_at: r.last_occurred_at,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthEventKind, RequestContext};
+    use axum::extract::ConnectInfo;
+    use axum::http::Request;
+    use claims::{assert_err, assert_ok};
+    use std::net::SocketAddr;
+    use tower_http::request_id::RequestId;
+
+    #[test]
+    fn kinds_round_trip_through_their_name() {
+        for kind in [
+            AuthEventKind::LoginSucceeded,
+            AuthEventKind::LoginFailed,
+            AuthEventKind::LockedOut,
+            AuthEventKind::PasswordChanged,
+            AuthEventKind::ApiTokenCreated,
+            AuthEventKind::ApiTokenRevoked,
+        ] {
+            assert_eq!(kind.as_str().parse::<AuthEventKind>(), Ok(kind));
+        }
+        assert_err!("logged_in".parse::<AuthEventKind>());
+    }
+
+    #[test]
+    fn the_context_is_read_from_the_request() {
+        let (mut parts, _) = Request::builder()
+            .header("User-Agent", "curl/8.0")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let client: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        parts.extensions.insert(ConnectInfo(client));
+        parts
+            .extensions
+            .insert(RequestId::new("a-request-id".parse().unwrap()));
+
+        let context = assert_ok!(RequestContext::from_parts(&parts));
+
+        assert_eq!(context.client_ip, client.ip());
+        assert_eq!(context.user_agent.as_deref(), Some("curl/8.0"));
+        assert_eq!(context.request_id.as_deref(), Some("a-request-id"));
+    }
+
+    #[test]
+    fn the_context_needs_the_client_address() {
+        let (parts, _) = Request::builder().body(()).unwrap().into_parts();
+
+        assert_err!(RequestContext::from_parts(&parts));
+    }
+}
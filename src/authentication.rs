@@ -4,34 +4,336 @@
 pub enum AuthError {
     #[error("Invalid credentials")]
     InvalidCredentials(#[source] anyhow::Error),
+    #[error("Too many failed login attempts")]
+    TooManyAttempts { retry_after: std::time::Duration },
+    #[error("Two-factor authentication is not enabled")]
+    SecondFactorNotEnabled,
+    #[error("The API token is missing the {0} scope")]
+    MissingScope(ApiTokenScope),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
 
 use anyhow::Context;
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+    ARGON2ID_IDENT,
+};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use secrecy::{ExposeSecret, Secret};
 use sqlx::PgPool;
 
+use crate::api_tokens::{authenticate_token, ApiTokenError, ApiTokenScope};
+use crate::auth_events::{self, AuthEvent, AuthEventKind, RequestContext};
+use crate::configuration::PasswordHashingSettings;
+use crate::login_throttle::{AttemptKey, LoginThrottle};
 use crate::telemetry::spawn_blocking_with_tracing;
+use crate::two_factor::{self, CodeReuse};
+
+/// Where callers of API endpoints requiring two-factor authentication put their code.
+pub const TWO_FACTOR_CODE_HEADER: &str = "X-Two-Factor-Code";
+
+/// The response to callers that have to wait before trying to log in again.
+pub fn too_many_attempts_response(retry_after: std::time::Duration) -> Response {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+        "Too many failed login attempts",
+    )
+        .into_response()
+}
 
 pub struct Credientials {
     pub username: String,
     pub password: Secret<String>,
 }
 
-#[tracing::instrument(name = "Validate credentials", skip(credential, pool))]
-pub async fn validate_credentials(
+/// Authenticate the caller of an API endpoint via the 'Basic' authentication scheme.
+#[tracing::instrument(
+    name = "Authenticate with basic auth",
+    skip(headers, request, pool, throttle, hashing),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn authenticate_basic(
+    headers: &HeaderMap,
+    request: &RequestContext,
+    pool: &PgPool,
+    throttle: &LoginThrottle,
+    hashing: &PasswordHashing,
+) -> Result<uuid::Uuid, AuthError> {
+    let credentials = basic_authentication(headers).map_err(AuthError::InvalidCredentials)?;
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+
+    let user_id = authenticate(credentials, request, pool, throttle, hashing).await?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    Ok(user_id)
+}
+
+/// Authenticate the caller of an API endpoint via an API token in the 'Bearer'
+/// authentication scheme, if they use it: `None` is returned otherwise.
+///
+/// Tokens are too long to be guessed, so failures are not throttled.
+#[tracing::instrument(
+    name = "Authenticate with an API token",
+    skip(headers, pool),
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn authenticate_bearer(
+    headers: &HeaderMap,
+    pool: &PgPool,
+    scope: ApiTokenScope,
+) -> Result<Option<uuid::Uuid>, AuthError> {
+    let Some(token) = bearer_token(headers).map_err(AuthError::InvalidCredentials)? else {
+        return Ok(None);
+    };
+
+    let user_id = authenticate_token(pool, &token, scope)
+        .await
+        .map_err(|e| match e {
+            ApiTokenError::InvalidToken => AuthError::InvalidCredentials(e.into()),
+            ApiTokenError::MissingScope(scope) => AuthError::MissingScope(scope),
+            ApiTokenError::UnexpectedError(e) => AuthError::UnexpectedError(e),
+        })?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    Ok(Some(user_id))
+}
+
+/// Like `authenticate_basic`, also asking for a two-factor code in the
+/// `X-Two-Factor-Code` header. Users without two-factor authentication are refused.
+#[tracing::instrument(
+    name = "Authenticate with basic auth and a second factor",
+    skip(headers, request, pool, throttle, hashing),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn authenticate_basic_with_second_factor(
+    headers: &HeaderMap,
+    request: &RequestContext,
+    pool: &PgPool,
+    throttle: &LoginThrottle,
+    hashing: &PasswordHashing,
+) -> Result<uuid::Uuid, AuthError> {
+    let credentials = basic_authentication(headers).map_err(AuthError::InvalidCredentials)?;
+    let username = credentials.username.clone();
+    tracing::Span::current().record("username", tracing::field::display(&username));
+
+    let user_id = authenticate(credentials, request, pool, throttle, hashing).await?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    if !two_factor::is_enabled(pool, user_id).await? {
+        return Err(AuthError::SecondFactorNotEnabled);
+    }
+    let code = headers
+        .get(TWO_FACTOR_CODE_HEADER)
+        .context("The two-factor code header was missing.")
+        .and_then(|value| {
+            value
+                .to_str()
+                .context("The two-factor code header was not a valid UTF8 string.")
+        })
+        .map_err(AuthError::InvalidCredentials)?;
+    authenticate_second_factor(
+        user_id,
+        &username,
+        code,
+        CodeReuse::WithinTimeStep,
+        request,
+        pool,
+        throttle,
+    )
+    .await?;
+
+    Ok(user_id)
+}
+
+/// Checks the two-factor code of a user whose password was validated.
+/// Wrong codes are counted as failed login attempts.
+#[tracing::instrument(
+    name = "Authenticate a second factor",
+    skip(code, request, pool, throttle)
+)]
+pub async fn authenticate_second_factor(
+    user_id: uuid::Uuid,
+    username: &str,
+    code: &str,
+    reuse: CodeReuse,
+    request: &RequestContext,
+    pool: &PgPool,
+    throttle: &LoginThrottle,
+) -> Result<(), AuthError> {
+    let keys = [
+        AttemptKey::Username(username.to_owned()),
+        AttemptKey::Ip(request.client_ip),
+    ];
+    if let Some(retry_after) = throttle.check(pool, &keys).await? {
+        let e = AuthError::TooManyAttempts { retry_after };
+        record_login_failure(pool, username, request, &e).await?;
+        return Err(e);
+    }
+
+    if two_factor::verify_code(pool, user_id, code, reuse).await? {
+        throttle.record_success(pool, username).await?;
+        Ok(())
+    } else {
+        let e = AuthError::InvalidCredentials(anyhow::anyhow!("Invalid two-factor code."));
+        record_failed_attempt(pool, throttle, &keys, username, request, &e).await?;
+        Err(e)
+    }
+}
+
+/// Validates credentials, unless the username or the client IP failed to log in
+/// too many times recently. Every entry point must go through here, so that
+/// failures are counted wherever they happen.
+///
+/// Failures are recorded as authentication events. Successes are left to the
+/// callers, since API callers authenticate on every request.
+#[tracing::instrument(
+    name = "Authenticate",
+    skip(credentials, request, pool, throttle, hashing)
+)]
+pub async fn authenticate(
+    credentials: Credientials,
+    request: &RequestContext,
+    pool: &PgPool,
+    throttle: &LoginThrottle,
+    hashing: &PasswordHashing,
+) -> Result<uuid::Uuid, AuthError> {
+    let username = credentials.username.clone();
+    let keys = [
+        AttemptKey::Username(username.clone()),
+        AttemptKey::Ip(request.client_ip),
+    ];
+    if let Some(retry_after) = throttle.check(pool, &keys).await? {
+        let e = AuthError::TooManyAttempts { retry_after };
+        record_login_failure(pool, &username, request, &e).await?;
+        return Err(e);
+    }
+
+    match validate_credentials(credentials, pool, hashing).await {
+        Ok(user_id) => {
+            // With two-factor authentication, failures are only forgotten once
+            // the code is checked too, so that codes cannot be guessed endlessly.
+            if !two_factor::is_enabled(pool, user_id).await? {
+                throttle.record_success(pool, &username).await?;
+            }
+            Ok(user_id)
+        }
+        Err(e @ AuthError::InvalidCredentials(_)) => {
+            record_failed_attempt(pool, throttle, &keys, &username, request, &e).await?;
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Counts a failed attempt, recording it and the lockouts it causes.
+async fn record_failed_attempt(
+    pool: &PgPool,
+    throttle: &LoginThrottle,
+    keys: &[AttemptKey],
+    username: &str,
+    request: &RequestContext,
+    e: &AuthError,
+) -> Result<(), anyhow::Error> {
+    let locked_out = throttle.record_failure(pool, keys).await?;
+    record_login_failure(pool, username, request, e).await?;
+    for key in locked_out {
+        let username = match &key {
+            AttemptKey::Username(username) => Some(username.as_str()),
+            AttemptKey::Ip(_) => None,
+        };
+        let event = AuthEvent {
+            kind: AuthEventKind::LockedOut,
+            user_id: None,
+            username,
+            request: Some(request),
+            detail: Some(key.to_string()),
+        };
+        auth_events::record(pool, event).await?;
+    }
+    Ok(())
+}
+
+async fn record_login_failure(
+    pool: &PgPool,
+    username: &str,
+    request: &RequestContext,
+    e: &AuthError,
+) -> Result<(), anyhow::Error> {
+    let detail = match e {
+        AuthError::InvalidCredentials(source) => source.to_string(),
+        e => e.to_string(),
+    };
+    let event = AuthEvent {
+        kind: AuthEventKind::LoginFailed,
+        user_id: None,
+        username: Some(username),
+        request: Some(request),
+        detail: Some(detail),
+    };
+    match e {
+        AuthError::TooManyAttempts { .. } => auth_events::record_repeated(pool, event).await,
+        _ => auth_events::record(pool, event).await,
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<Option<Secret<String>>, anyhow::Error> {
+    let Some(header_value) = headers.get("Authorization") else {
+        return Ok(None);
+    };
+    let header_value = header_value
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    Ok(header_value
+        .strip_prefix("Bearer ")
+        .map(|token| Secret::new(token.trim().to_owned())))
+}
+
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credientials, anyhow::Error> {
+    let header_value = headers
+        .get("Authorization")
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encoded_segment)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+
+    let mut credientials = decoded_credentials.splitn(2, ':');
+    let username = credientials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
+        .to_string();
+    let password = credientials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
+        .to_string();
+
+    Ok(Credientials {
+        username,
+        password: Secret::new(password),
+    })
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credential, pool, hashing))]
+async fn validate_credentials(
     credential: Credientials,
     pool: &PgPool,
+    hashing: &PasswordHashing,
 ) -> Result<uuid::Uuid, AuthError> {
     let mut user_id = None;
-    let mut expected_password_hash = Secret::new(
-        "$argon2id$v=19$m=15000,t=2,p=1$\
-        gZiV/M1gPc22ElAH/Jh1Hw$\
-        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
-            .to_string(),
-    );
+    let mut expected_password_hash = hashing.dummy_hash.clone();
 
     if let Some((stored_user_id, stored_password_hash)) =
         get_stored_credentials(&credential.username, pool).await?
@@ -40,22 +342,135 @@ pub async fn validate_credentials(
         expected_password_hash = stored_password_hash;
     }
 
+    let stored_password_hash = expected_password_hash.clone();
+    let password = credential.password.clone();
     spawn_blocking_with_tracing(move || {
-        verfiy_password_hash(expected_password_hash, credential.password)
+        verify_password_hash(expected_password_hash, credential.password)
     })
     .await
     .context("Failed to spawn blocking task.")??;
 
-    user_id
+    let user_id = user_id
         .ok_or_else(|| anyhow::anyhow!("Unknown username."))
-        .map_err(AuthError::InvalidCredentials)
+        .map_err(AuthError::InvalidCredentials)?;
+
+    // The password is only ever known here: it is the one chance to hash it again.
+    if let Err(e) = upgrade_password_hash(
+        user_id,
+        stored_password_hash,
+        password,
+        hashing.settings,
+        pool,
+    )
+    .await
+    {
+        tracing::warn!(error.cause_chain = ?e, "Failed to upgrade a password hash");
+    }
+
+    Ok(user_id)
+}
+
+/// The configured password hashing parameters, along with a hash computed
+/// with them at startup. Unknown usernames are checked against that hash, so
+/// that they take as long to reject as wrong passwords.
+#[derive(Clone)]
+pub struct PasswordHashing {
+    pub settings: PasswordHashingSettings,
+    dummy_hash: Secret<String>,
+}
+
+impl PasswordHashing {
+    pub fn new(settings: PasswordHashingSettings) -> Result<Self, anyhow::Error> {
+        let password: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let dummy_hash = compute_password_hash(Secret::new(password), &settings)?;
+        Ok(Self {
+            settings,
+            dummy_hash,
+        })
+    }
+}
+
+/// Hashes a password with Argon2id and the configured parameters.
+pub fn compute_password_hash(
+    password: Secret<String>,
+    hashing: &PasswordHashingSettings,
+) -> Result<Secret<String>, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let params = hashing
+        .params()
+        .context("Invalid password hashing parameters.")?;
+    let password_hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.expose_secret().as_bytes(), &salt)
+        .context("Failed to hash the password.")?
+        .to_string();
+    Ok(Secret::new(password_hash))
+}
+
+/// Whether a stored hash was computed with another algorithm or other
+/// parameters than the configured ones.
+fn needs_rehash(
+    password_hash: &str,
+    hashing: &PasswordHashingSettings,
+) -> Result<bool, anyhow::Error> {
+    let password_hash =
+        PasswordHash::new(password_hash).context("Failed to parse hash in PHC string format.")?;
+    let params = Params::try_from(&password_hash)
+        .context("Failed to read the parameters of the password hash.")?;
+
+    Ok(password_hash.algorithm != ARGON2ID_IDENT
+        || password_hash.version != Some(Version::V0x13.into())
+        || params.m_cost() != hashing.memory_size_kib
+        || params.t_cost() != hashing.iterations
+        || params.p_cost() != hashing.parallelism)
+}
+
+#[tracing::instrument(
+    name = "Upgrade password hash",
+    skip(stored_password_hash, password, hashing, pool)
+)]
+async fn upgrade_password_hash(
+    user_id: uuid::Uuid,
+    stored_password_hash: Secret<String>,
+    password: Secret<String>,
+    hashing: PasswordHashingSettings,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    if !needs_rehash(stored_password_hash.expose_secret(), &hashing)? {
+        return Ok(());
+    }
+
+    let password_hash =
+        spawn_blocking_with_tracing(move || compute_password_hash(password, &hashing))
+            .await
+            .context("Failed to spawn blocking task.")??;
+    // Unless the password was changed in the meantime.
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = $1
+        WHERE user_id = $2 AND password_hash = $3
+        "#,
+        password_hash.expose_secret(),
+        user_id,
+        stored_password_hash.expose_secret()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the upgraded password hash.")?;
+
+    tracing::info!("Upgraded a password hash to the configured parameters");
+    Ok(())
 }
 
 #[tracing::instrument(
     name = "Verify password hash",
     skip(expected_password_hash, password_candidate)
 )]
-fn verfiy_password_hash(
+fn verify_password_hash(
     expected_password_hash: Secret<String>,
     password_candidate: Secret<String>,
 ) -> Result<(), AuthError> {
@@ -63,6 +478,7 @@ fn verfiy_password_hash(
         .context("Failed to parse hash in PHC string format.")
         .map_err(AuthError::UnexpectedError)?;
 
+    // The algorithm and its parameters are read from the hash itself.
     Argon2::default()
         .verify_password(
             password_candidate.expose_secret().as_bytes(),
@@ -92,3 +508,43 @@ async fn get_stored_credentials(
 
     Ok(row)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_password_hash, needs_rehash, PasswordHashing};
+    use crate::configuration::PasswordHashingSettings;
+    use secrecy::{ExposeSecret, Secret};
+
+    fn hashing(memory_size_kib: u32) -> PasswordHashingSettings {
+        PasswordHashingSettings {
+            memory_size_kib,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn hashes_with_the_configured_parameters_are_kept() {
+        let hash = compute_password_hash(Secret::new("password".into()), &hashing(8)).unwrap();
+        assert!(!needs_rehash(hash.expose_secret(), &hashing(8)).unwrap());
+    }
+
+    #[test]
+    fn hashes_with_other_parameters_are_upgraded() {
+        let hash = compute_password_hash(Secret::new("password".into()), &hashing(8)).unwrap();
+        assert!(needs_rehash(hash.expose_secret(), &hashing(16)).unwrap());
+    }
+
+    #[test]
+    fn hashes_with_another_algorithm_are_upgraded() {
+        let argon2i_hash = "$argon2i$v=19$m=8,t=1,p=1$c29tZXNhbHQ$X3uRYmJ1JVsNA4MdrcKfAWPxAmVyeoYa";
+        assert!(needs_rehash(argon2i_hash, &hashing(8)).unwrap());
+    }
+
+    #[test]
+    fn unknown_usernames_are_checked_against_a_hash_with_the_configured_parameters() {
+        let hashing = PasswordHashing::new(hashing(16)).unwrap();
+        let dummy_hash = hashing.dummy_hash.expose_secret();
+        assert!(!needs_rehash(dummy_hash, &hashing.settings).unwrap());
+    }
+}
@@ -0,0 +1,393 @@
+//! src/authorization.rs
+
+use std::marker::PhantomData;
+
+use anyhow::Context;
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::{header, request::Parts, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::api_tokens::ApiTokenScope;
+use crate::auth_events::RequestContext;
+use crate::authentication::{
+    authenticate_basic, authenticate_basic_with_second_factor, authenticate_bearer,
+    too_many_attempts_response, AuthError, PasswordHashing,
+};
+use crate::login_throttle::LoginThrottle;
+use crate::routes::error_chain_fmt;
+
+/// What a user is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can do everything, including publishing and managing users.
+    Owner,
+    /// Can prepare newsletter issues and read subscribers, but not publish.
+    Editor,
+    /// Can only look at how newsletter issues did.
+    Viewer,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Owner => "owner",
+            Self::Editor => "editor",
+            Self::Viewer => "viewer",
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        use Permission::*;
+        match self {
+            Self::Owner => true,
+            Self::Editor => !matches!(
+                permission,
+                PublishNewsletters | ManageUsers | ViewAuthEvents
+            ),
+            Self::Viewer => matches!(permission, ViewNewsletterStats | ManageOwnAccount),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Self::Owner),
+            "editor" => Ok(Self::Editor),
+            "viewer" => Ok(Self::Viewer),
+            other => Err(format!("{} is not a known role.", other)),
+        }
+    }
+}
+
+/// What a route requires of its callers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ViewNewsletterStats,
+    ReadSubscribers,
+    DraftNewsletters,
+    PublishNewsletters,
+    /// Two-factor enrollment, and listing or revoking one's API tokens.
+    ManageOwnAccount,
+    CreateApiTokens,
+    ManageUsers,
+    /// Reading the log of logins, lockouts, password changes and API tokens.
+    ViewAuthEvents,
+}
+
+impl Permission {
+    /// The scope API tokens need to be used for this, if they can be at all.
+    fn api_token_scope(&self) -> Option<ApiTokenScope> {
+        match self {
+            Self::PublishNewsletters => Some(ApiTokenScope::Publish),
+            Self::ReadSubscribers => Some(ApiTokenScope::ReadSubscribers),
+            _ => None,
+        }
+    }
+
+    /// Whether a two-factor code is needed on top of the password.
+    /// API tokens act without one, so creating them needs one as well.
+    fn requires_second_factor(&self) -> bool {
+        matches!(self, Self::PublishNewsletters | Self::CreateApiTokens)
+    }
+
+    fn realm(&self) -> &'static str {
+        match self {
+            Self::PublishNewsletters => "publish",
+            _ => "admin",
+        }
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ViewNewsletterStats => "view newsletter stats",
+            Self::ReadSubscribers => "read subscribers",
+            Self::DraftNewsletters => "draft newsletter issues",
+            Self::PublishNewsletters => "publish newsletter issues",
+            Self::ManageOwnAccount => "manage their account",
+            Self::CreateApiTokens => "create API tokens",
+            Self::ManageUsers => "manage users",
+            Self::ViewAuthEvents => "view authentication events",
+        })
+    }
+}
+
+/// Ties a marker type to a permission, so that routes can require it in their
+/// signature with `Authorized<require::PublishNewsletters>`.
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+pub mod require {
+    use super::{Permission, RequiredPermission};
+
+    macro_rules! required_permissions {
+        ($($permission:ident),* $(,)?) => {
+            $(
+                pub struct $permission;
+
+                impl RequiredPermission for $permission {
+                    const PERMISSION: Permission = Permission::$permission;
+                }
+            )*
+        };
+    }
+
+    required_permissions!(
+        ViewNewsletterStats,
+        ReadSubscribers,
+        DraftNewsletters,
+        PublishNewsletters,
+        ManageOwnAccount,
+        CreateApiTokens,
+        ManageUsers,
+        ViewAuthEvents,
+    );
+}
+
+#[derive(thiserror::Error)]
+pub enum AuthorizationError {
+    #[error("Authentication failed")]
+    AuthError {
+        #[source]
+        source: AuthError,
+        realm: &'static str,
+    },
+    #[error("Users with the {role} role are not allowed to {permission}.")]
+    Forbidden { role: Role, permission: Permission },
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthorizationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for AuthorizationError {
+    fn into_response(self) -> Response {
+        match self {
+            AuthorizationError::AuthError { source, realm } => match source {
+                AuthError::InvalidCredentials(_) => {
+                    let mut response = StatusCode::UNAUTHORIZED.into_response();
+                    response.headers_mut().insert(
+                        header::WWW_AUTHENTICATE,
+                        HeaderValue::from_str(&format!(r#"Basic realm="{}""#, realm)).unwrap(),
+                    );
+                    response
+                }
+                AuthError::TooManyAttempts { retry_after } => {
+                    too_many_attempts_response(retry_after)
+                }
+                AuthError::SecondFactorNotEnabled | AuthError::MissingScope(_) => {
+                    (StatusCode::FORBIDDEN, format!("{}.", source)).into_response()
+                }
+                AuthError::UnexpectedError(error) => {
+                    tracing::error!("Unexpected error caused by {}", error);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            AuthorizationError::Forbidden { .. } => {
+                (StatusCode::FORBIDDEN, self.to_string()).into_response()
+            }
+            AuthorizationError::UnexpectedError(error) => {
+                tracing::error!("Unexpected error caused by {}", error);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// An authenticated caller whose role grants the permission `P`.
+///
+/// Callers authenticate with an API token when the permission has a matching
+/// scope, and with their password otherwise.
+pub struct Authorized<P> {
+    pub user_id: Uuid,
+    pub role: Role,
+    permission: PhantomData<P>,
+}
+
+#[axum::async_trait]
+impl<S, P> FromRequestParts<S> for Authorized<P>
+where
+    S: Send + Sync,
+    PgPool: FromRef<S>,
+    LoginThrottle: FromRef<S>,
+    PasswordHashing: FromRef<S>,
+    P: RequiredPermission,
+{
+    type Rejection = AuthorizationError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let permission = P::PERMISSION;
+        let pool = PgPool::from_ref(state);
+        let throttle = LoginThrottle::from_ref(state);
+        let hashing = PasswordHashing::from_ref(state);
+        let request = RequestContext::from_parts(parts)?;
+
+        let authenticated = match permission.api_token_scope() {
+            Some(scope) => authenticate_bearer(&parts.headers, &pool, scope).await,
+            None => Ok(None),
+        };
+        let authenticated = match authenticated {
+            Ok(Some(user_id)) => Ok(user_id),
+            Ok(None) if permission.requires_second_factor() => {
+                authenticate_basic_with_second_factor(
+                    &parts.headers,
+                    &request,
+                    &pool,
+                    &throttle,
+                    &hashing,
+                )
+                .await
+            }
+            Ok(None) => {
+                authenticate_basic(&parts.headers, &request, &pool, &throttle, &hashing).await
+            }
+            Err(e) => Err(e),
+        };
+        let user_id = authenticated.map_err(|source| AuthorizationError::AuthError {
+            source,
+            realm: permission.realm(),
+        })?;
+
+        let role = get_role(&pool, user_id).await?;
+        if !role.can(permission) {
+            tracing::warn!(
+                target: "audit",
+                %user_id,
+                %role,
+                %permission,
+                "Refused an action the role does not allow"
+            );
+            return Err(AuthorizationError::Forbidden { role, permission });
+        }
+
+        Ok(Self {
+            user_id,
+            role,
+            permission: PhantomData,
+        })
+    }
+}
+
+#[tracing::instrument(name = "Get the role of a user", skip(pool))]
+async fn get_role(pool: &PgPool, user_id: Uuid) -> Result<Role, anyhow::Error> {
+    let role = sqlx::query_scalar!("SELECT role FROM users WHERE user_id = $1", user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to retrieve the role of the user.")?;
+    role.parse().map_err(anyhow::Error::msg)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SetRoleError {
+    #[error("There is no such user.")]
+    UnknownUser,
+    #[error("There must be at least one owner left.")]
+    LastOwner,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// Changes the role of a user, refusing to leave nobody able to manage users.
+#[tracing::instrument(name = "Set the role of a user", skip(pool))]
+pub async fn set_role(pool: &PgPool, username: &str, role: Role) -> Result<(), SetRoleError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    // Serialises role changes, so that two owners cannot demote each other at once.
+    sqlx::query!("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to lock the users table.")?;
+
+    let current_role = sqlx::query_scalar!("SELECT role FROM users WHERE username = $1", username)
+        .fetch_optional(&mut *transaction)
+        .await
+        .context("Failed to retrieve the role of the user.")?
+        .ok_or(SetRoleError::UnknownUser)?;
+    if current_role == Role::Owner.as_str() && role != Role::Owner {
+        let owners =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM users WHERE role = 'owner'"#)
+                .fetch_one(&mut *transaction)
+                .await
+                .context("Failed to count owners.")?;
+        if owners <= 1 {
+            return Err(SetRoleError::LastOwner);
+        }
+    }
+
+    sqlx::query!(
+        "UPDATE users SET role = $1 WHERE username = $2",
+        role.as_str(),
+        username
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to update the role of the user.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the role change.")?;
+
+    tracing::info!(target: "audit", username, %role, "Changed the role of a user");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Permission, Role};
+
+    #[test]
+    fn only_owners_publish_and_manage_users() {
+        for permission in [
+            Permission::PublishNewsletters,
+            Permission::ManageUsers,
+            Permission::ViewAuthEvents,
+        ] {
+            assert!(Role::Owner.can(permission));
+            assert!(!Role::Editor.can(permission));
+            assert!(!Role::Viewer.can(permission));
+        }
+    }
+
+    #[test]
+    fn editors_draft_and_read_subscribers() {
+        assert!(Role::Editor.can(Permission::DraftNewsletters));
+        assert!(Role::Editor.can(Permission::ReadSubscribers));
+        assert!(!Role::Viewer.can(Permission::DraftNewsletters));
+        assert!(!Role::Viewer.can(Permission::ReadSubscribers));
+    }
+
+    #[test]
+    fn everyone_views_stats_and_manages_their_account() {
+        for role in [Role::Owner, Role::Editor, Role::Viewer] {
+            assert!(role.can(Permission::ViewNewsletterStats));
+            assert!(role.can(Permission::ManageOwnAccount));
+        }
+    }
+
+    #[test]
+    fn roles_round_trip_through_their_names() {
+        for role in [Role::Owner, Role::Editor, Role::Viewer] {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(role));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! src/background_tasks.rs
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use futures::FutureExt;
+use tokio::task::JoinSet;
+
+/// Work requests start without waiting for it, such as sending an email.
+/// Unlike a detached task, it is waited for when the application shuts down.
+#[derive(Clone, Default)]
+pub struct BackgroundTasks(Arc<Mutex<JoinSet<()>>>);
+
+impl BackgroundTasks {
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.0.lock().unwrap();
+        // Finished tasks are only let go of here, so that they do not pile up.
+        while let Some(Some(_)) = tasks.join_next().now_or_never() {}
+        tasks.spawn(task);
+    }
+
+    /// Completes once every task spawned so far has.
+    pub async fn wait(&self) {
+        let mut tasks = std::mem::take(&mut *self.0.lock().unwrap());
+        while tasks.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BackgroundTasks;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn waiting_lets_every_task_complete() {
+        let tasks = BackgroundTasks::default();
+        let completed = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let completed = completed.clone();
+            tasks.spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        tasks.wait().await;
+
+        assert_eq!(completed.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn finished_tasks_are_let_go_of() {
+        let tasks = BackgroundTasks::default();
+        tasks.spawn(async {});
+        tokio::task::yield_now().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        tasks.spawn(std::future::pending());
+
+        assert_eq!(tasks.0.lock().unwrap().len(), 1);
+    }
+}
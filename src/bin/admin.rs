@@ -0,0 +1,178 @@
+//! src/bin/admin.rs
+//!
+//! Administration commands, run against the database of the configured environment:
+//!
+//! - `admin unlock username <username>` lifts the login lockout of a username;
+//! - `admin unlock ip <ip>` lifts the login lockout of a client IP;
+//! - `admin tokens list <username>` lists the API tokens of a user;
+//! - `admin tokens create <username> <name> <scope>...` creates an API token;
+//! - `admin tokens revoke <username> <token id>` revokes an API token;
+//! - `admin users set-role <username> (owner | editor | viewer)` changes the role of a user.
+
+use std::error::Error;
+
+use anyhow::Context;
+use blog_backend::{
+    api_tokens::{create_token, list_tokens, revoke_token, ApiTokenScope},
+    auth_events::{self, AuthEvent, AuthEventKind},
+    authorization::{set_role, Role},
+    configuration::get_configuration,
+    login_throttle::{unlock, AttemptKey},
+    startup::get_connection_pool,
+    telemetry::{get_subscriber, init_subscriber},
+};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const USAGE: &str = "\
+Usage: admin unlock (username <username> | ip <ip>)
+       admin tokens list <username>
+       admin tokens create <username> <name> <scope>...
+       admin tokens revoke <username> <token id>
+       admin users set-role <username> (owner | editor | viewer)
+
+Scopes: publish, read_subscribers";
+
+enum Command {
+    Unlock(AttemptKey),
+    ListTokens {
+        username: String,
+    },
+    CreateToken {
+        username: String,
+        name: String,
+        scopes: Vec<ApiTokenScope>,
+    },
+    RevokeToken {
+        username: String,
+        token_id: Uuid,
+    },
+    SetRole {
+        username: String,
+        role: Role,
+    },
+}
+
+fn parse_command(args: &[&str]) -> Result<Option<Command>, Box<dyn Error>> {
+    let command = match args {
+        ["unlock", "username", username] => {
+            Command::Unlock(AttemptKey::Username(username.to_string()))
+        }
+        ["unlock", "ip", ip] => Command::Unlock(AttemptKey::Ip(ip.parse()?)),
+        ["tokens", "list", username] => Command::ListTokens {
+            username: username.to_string(),
+        },
+        ["tokens", "create", username, name, scopes @ ..] if !scopes.is_empty() => {
+            Command::CreateToken {
+                username: username.to_string(),
+                name: name.to_string(),
+                scopes: scopes
+                    .iter()
+                    .map(|s| s.parse())
+                    .collect::<Result<_, String>>()?,
+            }
+        }
+        ["tokens", "revoke", username, token_id] => Command::RevokeToken {
+            username: username.to_string(),
+            token_id: token_id.parse()?,
+        },
+        ["users", "set-role", username, role] => Command::SetRole {
+            username: username.to_string(),
+            role: role.parse()?,
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(command))
+}
+
+async fn get_user_id(pool: &PgPool, username: &str) -> Result<Uuid, anyhow::Error> {
+    sqlx::query_scalar!("SELECT user_id FROM users WHERE username = $1", username)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to retrieve the user.")?
+        .with_context(|| format!("There is no user named {}.", username))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let subscriber = get_subscriber("admin".to_string(), "info".into(), std::io::stdout);
+    init_subscriber(subscriber);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let Some(command) = parse_command(&args)? else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+
+    let configuration = get_configuration().expect("Failed to read configuration");
+    let pool = get_connection_pool(&configuration.database);
+    match command {
+        Command::Unlock(key) => {
+            if unlock(&pool, &key).await? {
+                tracing::info!(target: "audit", %key, "Lifted a login lockout");
+                println!("Unlocked {}.", key);
+            } else {
+                println!("The {} had no failed login attempts.", key);
+            }
+        }
+        Command::ListTokens { username } => {
+            let user_id = get_user_id(&pool, &username).await?;
+            for token in list_tokens(&pool, user_id).await? {
+                let scopes: Vec<_> = token.scopes.iter().map(ApiTokenScope::as_str).collect();
+                let last_used_at = token
+                    .last_used_at
+                    .map_or_else(|| "never".to_string(), |at| at.to_rfc3339());
+                println!(
+                    "{}\t{}\t{}\tlast used: {}",
+                    token.token_id,
+                    token.name,
+                    scopes.join(","),
+                    last_used_at
+                );
+            }
+        }
+        Command::CreateToken {
+            username,
+            name,
+            scopes,
+        } => {
+            let user_id = get_user_id(&pool, &username).await?;
+            let (token_id, token) = create_token(&pool, user_id, &name, &scopes).await?;
+            let event = AuthEvent {
+                kind: AuthEventKind::ApiTokenCreated,
+                user_id: Some(user_id),
+                username: Some(&username),
+                request: None,
+                detail: Some(format!("{} ({})", token_id, name)),
+            };
+            auth_events::record(&pool, event).await?;
+            println!("Created the API token {}:", token_id);
+            println!("{}", token.expose_secret());
+            println!("It will not be shown again.");
+        }
+        Command::RevokeToken { username, token_id } => {
+            let user_id = get_user_id(&pool, &username).await?;
+            if revoke_token(&pool, user_id, token_id).await? {
+                let event = AuthEvent {
+                    kind: AuthEventKind::ApiTokenRevoked,
+                    user_id: Some(user_id),
+                    username: Some(&username),
+                    request: None,
+                    detail: Some(token_id.to_string()),
+                };
+                auth_events::record(&pool, event).await?;
+                println!("Revoked the API token {}.", token_id);
+            } else {
+                println!("{} has no API token {}.", username, token_id);
+            }
+        }
+        Command::SetRole { username, role } => {
+            set_role(&pool, &username, role).await?;
+            println!("{} is now {}.", username, role);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,270 @@
+//! src/client_ip.rs
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::http::{request::Parts, HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// The address of the client that sent a request, as seen through the
+/// reverse proxies we trust. It is what rate limits and audit logs key on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// The address resolved by [`resolve_client_ip`], or the address of the
+    /// peer if the layer did not run.
+    pub fn from_parts(parts: &Parts) -> Option<Self> {
+        parts.extensions.get::<ClientIp>().copied().or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| Self(peer.ip()))
+        })
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_parts(parts).ok_or_else(|| {
+            tracing::error!("The client address is missing");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+}
+
+/// What limits on clients are keyed on: IPv4 addresses, and the /64 network of
+/// IPv6 addresses, since a single host is usually handed a whole /64 to pick
+/// addresses from. IPv4-mapped IPv6 addresses count as their IPv4 address.
+pub fn network_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => {
+                let network = Ipv6Addr::from(u128::from(v6) & !u128::from(u64::MAX));
+                format!("{}/64", network)
+            }
+        },
+    }
+}
+
+/// The reverse proxies allowed to tell us who the client is, with the
+/// `X-Forwarded-For` header: addresses, or networks in CIDR notation.
+#[derive(Debug, Default)]
+pub struct TrustedProxies(Vec<Network>);
+
+#[derive(Debug)]
+struct Network {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl TrustedProxies {
+    pub fn parse(proxies: &[String]) -> Result<Self, anyhow::Error> {
+        proxies
+            .iter()
+            .map(|proxy| Network::parse(proxy))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+
+    /// The client behind the proxies: the right-most address of
+    /// `X-Forwarded-For` that no trusted proxy appended. Requests that did not
+    /// come through a trusted proxy are from their peer, whatever they claim.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+        let forwarded_for: Vec<&str> = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let mut client = peer;
+        for address in forwarded_for.into_iter().rev() {
+            // What comes before an invalid address cannot be trusted either.
+            let Ok(address) = address.trim().parse() else {
+                break;
+            };
+            client = address;
+            if !self.trusts(address) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+impl Network {
+    fn parse(network: &str) -> Result<Self, anyhow::Error> {
+        let (address, prefix_length) = match network.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (network, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("{} is not a valid IP address.", address))?;
+        let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .parse()
+                .ok()
+                .filter(|length| *length <= max_prefix_length)
+                .with_context(|| format!("{} is not a valid network.", network))?,
+            None => max_prefix_length,
+        };
+        Ok(Self {
+            address,
+            prefix_length,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_length));
+                let mask = mask.unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_length));
+                let mask = mask.unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Resolves the [`ClientIp`] of every request once, for the extractors.
+pub async fn resolve_client_ip(
+    State(trusted_proxies): State<Arc<TrustedProxies>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let client_ip = trusted_proxies.client_ip(peer.ip(), request.headers());
+        request.extensions_mut().insert(ClientIp(client_ip));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{network_key, TrustedProxies};
+    use axum::http::HeaderMap;
+    use claims::assert_err;
+    use std::net::IpAddr;
+
+    fn proxies(proxies: &[&str]) -> TrustedProxies {
+        let proxies: Vec<_> = proxies.iter().map(|p| p.to_string()).collect();
+        TrustedProxies::parse(&proxies).unwrap()
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", value.parse().unwrap());
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn the_header_is_ignored_unless_the_peer_is_a_trusted_proxy() {
+        let proxies = proxies(&["10.0.0.1"]);
+        let headers = forwarded_for("203.0.113.7");
+
+        assert_eq!(
+            proxies.client_ip(ip("198.51.100.1"), &headers),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn addresses_spoofed_by_the_client_are_ignored() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        // The client sent the first address, the proxies appended the others.
+        let headers = forwarded_for("192.0.2.1, 203.0.113.7, 10.1.2.3");
+
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn the_peer_is_the_client_without_a_valid_header() {
+        let proxies = proxies(&["10.0.0.1"]);
+
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &forwarded_for("203.0.113.7, unknown")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn networks_match_the_addresses_they_contain() {
+        let proxies = proxies(&["172.16.0.0/12", "fd00::/8"]);
+
+        assert!(proxies.trusts(ip("172.31.255.255")));
+        assert!(!proxies.trusts(ip("172.32.0.0")));
+        assert!(proxies.trusts(ip("fd12::1")));
+        assert!(proxies.trusts(ip("::ffff:172.16.0.1")));
+        assert!(!proxies.trusts(ip("fe80::1")));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        assert_err!(TrustedProxies::parse(&["10.0.0.0/33".into()]));
+        assert_err!(TrustedProxies::parse(&["localhost".into()]));
+    }
+
+    #[test]
+    fn ipv6_clients_are_keyed_by_their_64_network() {
+        assert_eq!(network_key(ip("2001:db8:1:2:aaaa::1")), "2001:db8:1:2::/64");
+        assert_eq!(
+            network_key(ip("2001:db8:1:2:bbbb::2")),
+            network_key(ip("2001:db8:1:2:aaaa::1"))
+        );
+        assert_ne!(
+            network_key(ip("2001:db8:1:3::1")),
+            network_key(ip("2001:db8:1:2::1"))
+        );
+    }
+
+    #[test]
+    fn ipv4_clients_are_keyed_by_their_address() {
+        assert_eq!(network_key(ip("203.0.113.7")), "203.0.113.7");
+        assert_eq!(network_key(ip("::ffff:203.0.113.7")), "203.0.113.7");
+    }
+}
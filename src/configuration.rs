@@ -1,17 +1,25 @@
 //! src/configuration.rs
 
+use std::collections::HashMap;
+
 use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 
-use crate::domain::SubscriberEmail;
+use crate::domain::{SenderIdentity, SubscriberEmail};
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub newsletter: NewsletterSettings,
+    pub subscriptions: SubscriptionSettings,
+    pub login_throttle: LoginThrottleSettings,
+    pub password_hashing: PasswordHashingSettings,
+    pub security_headers: SecurityHeadersSettings,
+    pub health_checks: HealthCheckSettings,
 }
 
 #[derive(Deserialize, Clone)]
@@ -21,6 +29,41 @@ pub struct ApplicationSettings {
     pub host: String,
     pub base_url: String,
     pub hmac_secret: Secret<String>,
+    /// How long in-flight requests get to complete once we are asked to stop.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub shutdown_deadline_seconds: u64,
+    /// Lets admins log in through an OpenID Connect provider, on top of
+    /// their password. Disabled if it is unset.
+    pub oidc: Option<OidcSettings>,
+    /// The reverse proxies, as addresses or CIDR networks, whose
+    /// `X-Forwarded-For` header tells us the client address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// The bearer token `/metrics` must be scraped with.
+    pub metrics_token: Secret<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OidcSettings {
+    /// The provider's issuer identifier, where its discovery document is found.
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Secret<String>,
+    /// How the provider is named on the login form.
+    pub display_name: String,
+    pub timeout_milliseconds: u64,
+}
+
+impl ApplicationSettings {
+    pub fn shutdown_deadline(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_deadline_seconds)
+    }
+}
+
+impl OidcSettings {
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_milliseconds)
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -57,16 +100,153 @@ impl DatabaseSettings {
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
     pub base_url: String,
-    pub sender_email: String,
+    /// The name of the sender identity used when no other one is selected.
+    pub default_sender: String,
+    /// Sender identities, by name.
+    pub senders: HashMap<String, SenderSettings>,
     pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
+    /// Shared secret used by the email provider to sign webhook payloads.
+    pub webhook_secret: Secret<String>,
+    /// How many requests to the email API can be in flight at the same time.
+    pub max_concurrent_requests: usize,
+    /// How many requests to the email API can be started every second.
+    pub max_requests_per_second: u32,
+    /// Provider message stream for emails triggered by a user action.
+    pub transactional_message_stream: String,
+    /// Provider message stream for newsletter issues.
+    pub broadcast_message_stream: String,
 }
 
 impl EmailClientSettings {
-    pub fn sender(&self) -> Result<SubscriberEmail, String> {
-        SubscriberEmail::parse(self.sender_email.clone())
+    /// The configured sender identities, by name.
+    pub fn sender_identities(&self) -> Result<HashMap<String, SenderIdentity>, String> {
+        self.senders
+            .iter()
+            .map(|(name, sender)| {
+                let email = SubscriberEmail::parse(sender.email.clone())?;
+                let identity = SenderIdentity::parse(sender.display_name.clone(), email)?;
+                Ok((name.clone(), identity))
+            })
+            .collect()
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SenderSettings {
+    pub display_name: Option<String>,
+    pub email: String,
+}
+
+/// Settings of the (single) mailing list that newsletter issues are sent to.
+#[derive(serde::Deserialize, Clone)]
+pub struct NewsletterSettings {
+    /// Whether open and click tracking may be used for issues sent to this list.
+    /// Individual issues can still opt out when it is enabled.
+    pub tracking_enabled: bool,
+    /// The sender identity issues are sent from, unless they pick another one.
+    /// The default sender is used if it is unset.
+    pub sender: Option<String>,
+    /// How long a confirmation link stays valid after subscribing.
+    pub confirmation_link_lifetime_hours: u32,
+}
+
+impl NewsletterSettings {
+    pub fn confirmation_link_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.confirmation_link_lifetime_hours.into())
     }
+}
+
+/// Protection of the subscription form against bots and abuse.
+#[derive(serde::Deserialize, Clone)]
+pub struct SubscriptionSettings {
+    /// How many subscription attempts a client IP can make per hour.
+    pub max_attempts_per_ip_per_hour: u32,
+    /// How many subscriptions can be made per hour for the same email domain.
+    pub max_subscriptions_per_domain_per_hour: u32,
+    /// Leading zero bits required from proof-of-work solutions.
+    /// No proof of work is asked for if it is unset.
+    pub proof_of_work_difficulty: Option<u8>,
+    /// Email domains that cannot be used to subscribe, e.g. disposable ones.
+    pub blocked_email_domains: Vec<String>,
+}
+
+/// Protection of the login entry points against password guessing.
+#[derive(serde::Deserialize, Clone)]
+pub struct LoginThrottleSettings {
+    /// Failures after which a username is locked out.
+    pub max_failed_attempts_per_username: u32,
+    /// Failures after which a client IP is locked out.
+    pub max_failed_attempts_per_ip: u32,
+    /// How long to wait after the first failure; it doubles after each other one.
+    pub base_delay_milliseconds: u64,
+    /// The longest wait between two attempts, short of a lockout.
+    pub max_delay_seconds: u64,
+    /// How long a lockout lasts. Failures older than that are forgotten.
+    pub lockout_minutes: u32,
+}
+
+impl LoginThrottleSettings {
+    pub fn base_delay(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(self.base_delay_milliseconds as i64)
+    }
+
+    pub fn max_delay(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.max_delay_seconds as i64)
+    }
+
+    pub fn lockout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.lockout_minutes.into())
+    }
+}
+
+/// The Argon2id cost of password hashes. Stored hashes with other parameters
+/// are upgraded when their owner logs in.
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+pub struct PasswordHashingSettings {
+    pub memory_size_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl PasswordHashingSettings {
+    pub fn params(&self) -> Result<argon2::Params, argon2::Error> {
+        argon2::Params::new(
+            self.memory_size_kib,
+            self.iterations,
+            self.parallelism,
+            None,
+        )
+    }
+}
+
+/// The headers sent with every response to harden how browsers handle them.
+/// Production is strict by default, and `local.json` relaxes it for plain HTTP.
+#[derive(serde::Deserialize, Clone)]
+pub struct SecurityHeadersSettings {
+    pub content_security_policy: String,
+    /// `DENY` or `SAMEORIGIN`.
+    pub frame_options: String,
+    pub referrer_policy: String,
+    /// How long browsers must only use HTTPS for the application.
+    /// No `Strict-Transport-Security` header is sent if it is unset.
+    pub hsts_max_age_seconds: Option<u64>,
+    /// Whether cookies are marked `Secure`, i.e. only sent over HTTPS.
+    pub secure_cookies: bool,
+}
+
+/// How the readiness endpoint checks the dependencies of the application.
+#[derive(serde::Deserialize, Clone, Copy)]
+pub struct HealthCheckSettings {
+    /// How long each dependency has to answer before it is deemed unavailable.
+    pub timeout_milliseconds: u64,
+}
 
+impl HealthCheckSettings {
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
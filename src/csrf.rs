@@ -0,0 +1,233 @@
+//! src/csrf.rs
+//!
+//! Protection against cross-site request forgery for the forms the
+//! application serves, with signed double-submit tokens: browsers get a random
+//! value in a cookie, and forms carry it signed with the HMAC secret. Another
+//! site can neither read the cookie nor sign a value of its own.
+
+use std::convert::Infallible;
+
+use axum::body::Body;
+use axum::extract::{FromRef, FromRequestParts, State};
+use axum::http::{header, request::Parts, HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, IntoResponseParts, Response, ResponseParts};
+use axum::{Form, RequestExt};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use axum_extra::extract::CookieJar;
+use rand::{thread_rng, Rng};
+use secrecy::Secret;
+
+use crate::signed_token::{self, Purpose};
+use crate::startup::HmacSecret;
+
+const CSRF_COOKIE: &str = "_csrf";
+/// The name of the form field carrying the signed token.
+pub const CSRF_FIELD: &str = "csrf_token";
+/// Where scripts can put the signed token instead of a form field.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// The CSRF token of a browser, taken from its cookie, or a new one if it has
+/// none yet. Handlers rendering forms embed it with `form_field` and return it
+/// as part of their response, so that new tokens get their cookie.
+pub struct CsrfToken {
+    nonce: String,
+    is_new: bool,
+    secret: Secret<String>,
+}
+
+impl CsrfToken {
+    /// The hidden input to put in every form posting back to the application.
+    pub fn form_field(&self) -> String {
+        format!(
+            r#"<input type="hidden" name="{}" value="{}">"#,
+            CSRF_FIELD,
+            self.signed()
+        )
+    }
+
+    pub fn signed(&self) -> String {
+        sign(&self.nonce, &self.secret)
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+    HmacSecret: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let HmacSecret(secret) = HmacSecret::from_ref(state);
+        let token = match cookie_nonce(&parts.headers) {
+            Some(nonce) => Self {
+                nonce,
+                is_new: false,
+                secret,
+            },
+            None => Self {
+                nonce: hex::encode(thread_rng().gen::<[u8; 16]>()),
+                is_new: true,
+                secret,
+            },
+        };
+        Ok(token)
+    }
+}
+
+impl IntoResponseParts for CsrfToken {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        if self.is_new {
+            let cookie = Cookie::build((CSRF_COOKIE, self.nonce))
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .build();
+            let value = HeaderValue::from_str(&cookie.to_string())
+                .expect("CSRF cookies are valid header values");
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+        Ok(res)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+/// Rejects the state-changing requests that do not carry a token matching
+/// their cookie, either in the `csrf_token` field of a form or in the
+/// `X-CSRF-Token` header.
+///
+/// Meant for the routes serving forms to browsers: API clients, which
+/// authenticate with every request, have no cookie to forge requests with.
+pub async fn require_csrf_token(
+    State(HmacSecret(secret)): State<HmacSecret>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) {
+        return next.run(request).await;
+    }
+    let Some(nonce) = cookie_nonce(request.headers()) else {
+        return rejection();
+    };
+
+    let header_token = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let (token, request) = match header_token {
+        Some(token) => (Some(token), request),
+        None => match form_token(request).await {
+            Ok(outcome) => outcome,
+            Err(response) => return response,
+        },
+    };
+
+    match token {
+        Some(token) if verify(&nonce, &token, &secret) => next.run(request).await,
+        _ => rejection(),
+    }
+}
+
+/// Reads the token out of a form body, handing back a request with the same body.
+async fn form_token(request: Request<Body>) -> Result<(Option<String>, Request<Body>), Response> {
+    let (parts, bytes) = match request.with_limited_body() {
+        Ok(request) => {
+            let (parts, body) = request.into_parts();
+            (parts, hyper::body::to_bytes(body).await.map_err(|_| ()))
+        }
+        Err(request) => {
+            let (parts, body) = request.into_parts();
+            (parts, hyper::body::to_bytes(body).await.map_err(|_| ()))
+        }
+    };
+    let Ok(bytes) = bytes else {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    };
+
+    let token = match parts.headers.get(header::CONTENT_TYPE) {
+        Some(content_type) => {
+            let form_request = Request::builder()
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(bytes.clone()))
+                .expect("The form request is valid");
+            form_request
+                .extract::<Form<CsrfForm>, _>()
+                .await
+                .ok()
+                .and_then(|Form(form)| form.csrf_token)
+        }
+        None => None,
+    };
+    Ok((token, Request::from_parts(parts, Body::from(bytes))))
+}
+
+fn cookie_nonce(headers: &HeaderMap) -> Option<String> {
+    CookieJar::from_headers(headers)
+        .get(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_owned())
+        .filter(|nonce| !nonce.is_empty())
+}
+
+fn rejection() -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        "The form is missing its CSRF token, or it has expired. Please reload the page and try again.",
+    )
+        .into_response()
+}
+
+fn sign(nonce: &str, secret: &Secret<String>) -> String {
+    signed_token::sign(Purpose::Csrf, nonce.as_bytes(), secret)
+}
+
+fn verify(nonce: &str, token: &str, secret: &Secret<String>) -> bool {
+    signed_token::verify(Purpose::Csrf, token, secret)
+        .is_ok_and(|signed_nonce| signed_nonce == nonce.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, verify};
+    use secrecy::Secret;
+
+    fn secret() -> Secret<String> {
+        Secret::new("a-csrf-secret".to_string())
+    }
+
+    #[test]
+    fn a_signed_nonce_is_verified() {
+        let token = sign("a-nonce", &secret());
+        assert!(verify("a-nonce", &token, &secret()));
+    }
+
+    #[test]
+    fn a_token_for_another_nonce_is_rejected() {
+        let token = sign("another-nonce", &secret());
+        assert!(!verify("a-nonce", &token, &secret()));
+    }
+
+    #[test]
+    fn a_token_signed_with_another_secret_is_rejected() {
+        let token = sign("a-nonce", &Secret::new("another-secret".to_string()));
+        assert!(!verify("a-nonce", &token, &secret()));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        assert!(!verify("a-nonce", "not hex", &secret()));
+        assert!(!verify("a-nonce", "", &secret()));
+    }
+}
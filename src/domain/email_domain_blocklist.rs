@@ -0,0 +1,62 @@
+//! src/domain/email_domain_blocklist.rs
+
+use std::collections::HashSet;
+
+/// Email domains that subscriptions are refused from, typically the ones of
+/// disposable email providers. Their subdomains are blocked as well.
+#[derive(Debug, Clone, Default)]
+pub struct EmailDomainBlocklist(HashSet<String>);
+
+impl EmailDomainBlocklist {
+    pub fn new(domains: impl IntoIterator<Item = String>) -> Self {
+        Self(
+            domains
+                .into_iter()
+                .map(|domain| domain.trim().trim_end_matches('.').to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+        )
+    }
+
+    pub fn contains(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let mut candidate = domain.as_str();
+        loop {
+            if self.0.contains(candidate) {
+                return true;
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmailDomainBlocklist;
+
+    fn blocklist() -> EmailDomainBlocklist {
+        EmailDomainBlocklist::new(["mailinator.com".to_string(), " YopMail.com ".to_string()])
+    }
+
+    #[test]
+    fn listed_domains_are_blocked_whatever_their_case() {
+        assert!(blocklist().contains("mailinator.com"));
+        assert!(blocklist().contains("MAILINATOR.com"));
+        assert!(blocklist().contains("yopmail.com"));
+    }
+
+    #[test]
+    fn subdomains_of_listed_domains_are_blocked() {
+        assert!(blocklist().contains("spam.mailinator.com"));
+    }
+
+    #[test]
+    fn other_domains_are_allowed() {
+        assert!(!blocklist().contains("gmail.com"));
+        assert!(!blocklist().contains("notmailinator.com"));
+        assert!(!blocklist().contains("com"));
+    }
+}
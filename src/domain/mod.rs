@@ -1,7 +1,15 @@
+mod email_domain_blocklist;
+mod new_password;
 mod new_subscriber;
+mod sender_identity;
 mod subscriber_email;
 mod subscriber_name;
+mod username;
 
+pub use email_domain_blocklist::EmailDomainBlocklist;
+pub use new_password::NewPassword;
 pub use new_subscriber::NewSubscriber;
+pub use sender_identity::SenderIdentity;
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
+pub use username::Username;
@@ -0,0 +1,64 @@
+//! src/domain/new_password.rs
+
+use secrecy::{ExposeSecret, Secret};
+use unicode_segmentation::UnicodeSegmentation;
+
+const MIN_LENGTH: usize = 12;
+/// Argon2 takes passwords of any length, this only keeps hashing cheap.
+const MAX_LENGTH: usize = 128;
+
+/// A password that follows the password policy, before it is hashed.
+#[derive(Debug)]
+pub struct NewPassword(Secret<String>);
+
+impl NewPassword {
+    pub fn parse(password: Secret<String>) -> Result<NewPassword, String> {
+        let length = password.expose_secret().graphemes(true).count();
+        if length < MIN_LENGTH {
+            Err(format!(
+                "Passwords must be at least {} characters long.",
+                MIN_LENGTH
+            ))
+        } else if length > MAX_LENGTH {
+            Err(format!(
+                "Passwords must be at most {} characters long.",
+                MAX_LENGTH
+            ))
+        } else if password.expose_secret().trim().is_empty() {
+            Err("Passwords cannot be made of whitespace only.".into())
+        } else {
+            Ok(Self(password))
+        }
+    }
+
+    pub fn into_secret(self) -> Secret<String> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::NewPassword;
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    #[test]
+    fn a_12_character_long_password_is_valid() {
+        assert_ok!(NewPassword::parse(Secret::new("a".repeat(12))));
+    }
+
+    #[test]
+    fn a_password_shorter_than_12_characters_is_rejected() {
+        assert_err!(NewPassword::parse(Secret::new("a".repeat(11))));
+    }
+
+    #[test]
+    fn a_password_longer_than_128_characters_is_rejected() {
+        assert_err!(NewPassword::parse(Secret::new("a".repeat(129))));
+    }
+
+    #[test]
+    fn whitespace_only_passwords_are_rejected() {
+        assert_err!(NewPassword::parse(Secret::new(" ".repeat(12))));
+    }
+}
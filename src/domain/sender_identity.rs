@@ -0,0 +1,182 @@
+//! src/domain/sender_identity.rs
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::SubscriberEmail;
+
+/// Who an email is sent from, formatted as an RFC 5322 mailbox:
+/// `Team Blog <news@example.com>`, or the bare address without a display name.
+#[derive(Debug, Clone)]
+pub struct SenderIdentity(String);
+
+impl SenderIdentity {
+    pub fn parse(
+        display_name: Option<String>,
+        email: SubscriberEmail,
+    ) -> Result<SenderIdentity, String> {
+        let display_name = match display_name {
+            Some(display_name) => display_name,
+            None => return Ok(email.into()),
+        };
+
+        let is_empty_or_white_space = display_name.trim().is_empty();
+        let is_too_long = display_name.graphemes(true).count() > 256;
+        // Line breaks would let the name spill into other headers.
+        let contains_control_characters = display_name.chars().any(char::is_control);
+
+        if is_empty_or_white_space || is_too_long || contains_control_characters {
+            return Err(format!("{} is not a valid sender name.", display_name));
+        }
+        Ok(Self(format!(
+            "{} <{}>",
+            encode_display_name(display_name.trim()),
+            email.as_ref()
+        )))
+    }
+}
+
+/// Mail headers are ASCII: other names are sent as RFC 2047 encoded words.
+fn encode_display_name(display_name: &str) -> String {
+    if display_name.is_ascii() {
+        return quote_display_name(display_name);
+    }
+
+    // An encoded word takes at most 75 characters: 45 bytes of the name fit
+    // in the 60 characters of base64 left after `=?UTF-8?B?` and `?=`.
+    const MAX_BYTES_PER_WORD: usize = 45;
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in display_name.chars() {
+        if chunk.len() + c.len_utf8() > MAX_BYTES_PER_WORD {
+            words.push(std::mem::take(&mut chunk));
+        }
+        chunk.push(c);
+    }
+    words.push(chunk);
+    words
+        .iter()
+        .map(|word| format!("=?UTF-8?B?{}?=", STANDARD.encode(word)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Names made of atoms are written as they are, any other one as a quoted string.
+fn quote_display_name(display_name: &str) -> String {
+    let is_atext = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c);
+    if display_name.chars().all(|c| is_atext(c) || c == ' ') {
+        return display_name.to_owned();
+    }
+
+    let mut quoted = String::with_capacity(display_name.len() + 2);
+    quoted.push('"');
+    for c in display_name.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl From<SubscriberEmail> for SenderIdentity {
+    fn from(email: SubscriberEmail) -> Self {
+        Self(email.as_ref().to_owned())
+    }
+}
+
+impl std::fmt::Display for SenderIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl AsRef<str> for SenderIdentity {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{SenderIdentity, SubscriberEmail};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use claims::assert_err;
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse("news@example.com".into()).unwrap()
+    }
+
+    fn parse(display_name: &str) -> Result<SenderIdentity, String> {
+        SenderIdentity::parse(Some(display_name.into()), email())
+    }
+
+    #[test]
+    fn an_identity_without_display_name_is_the_bare_address() {
+        let identity = SenderIdentity::parse(None, email()).unwrap();
+        assert_eq!(identity.as_ref(), "news@example.com");
+    }
+
+    #[test]
+    fn plain_display_names_are_not_quoted() {
+        assert_eq!(
+            parse("Team Blog").unwrap().as_ref(),
+            "Team Blog <news@example.com>"
+        );
+    }
+
+    #[test]
+    fn display_names_with_special_characters_are_quoted() {
+        assert_eq!(
+            parse("Blog, Inc.").unwrap().as_ref(),
+            r#""Blog, Inc." <news@example.com>"#
+        );
+        assert_eq!(
+            parse(r#"The "Blog" \ News"#).unwrap().as_ref(),
+            r#""The \"Blog\" \\ News" <news@example.com>"#
+        );
+    }
+
+    #[test]
+    fn non_ascii_display_names_are_encoded() {
+        assert_eq!(
+            parse("Café Blog").unwrap().as_ref(),
+            "=?UTF-8?B?Q2Fmw6kgQmxvZw==?= <news@example.com>"
+        );
+    }
+
+    #[test]
+    fn long_non_ascii_display_names_are_split_into_several_encoded_words() {
+        let display_name = "Блог ".repeat(20);
+        let identity = parse(&display_name).unwrap();
+
+        let (words, address) = identity.as_ref().rsplit_once(' ').unwrap();
+        assert_eq!(address, "<news@example.com>");
+        let mut decoded = Vec::new();
+        for word in words.split(' ') {
+            assert!(word.len() <= 75);
+            let encoded = word
+                .strip_prefix("=?UTF-8?B?")
+                .and_then(|w| w.strip_suffix("?="))
+                .unwrap();
+            decoded.extend(STANDARD.decode(encoded).unwrap());
+        }
+        assert_eq!(String::from_utf8(decoded).unwrap(), display_name.trim());
+    }
+
+    #[test]
+    fn whitespace_only_display_names_are_rejected() {
+        assert_err!(parse("  "));
+    }
+
+    #[test]
+    fn display_names_with_line_breaks_are_rejected() {
+        assert_err!(parse("Team Blog\r\nBcc: everyone@example.com"));
+    }
+
+    #[test]
+    fn a_display_name_longer_than_256_graphemes_is_rejected() {
+        assert_err!(parse(&"a".repeat(257)));
+    }
+}
@@ -2,6 +2,8 @@
 
 use validator::validate_email;
 
+use super::EmailDomainBlocklist;
+
 #[derive(Debug)]
 pub struct SubscriberEmail(String);
 
@@ -13,6 +15,25 @@ impl SubscriberEmail {
             Err(format!("{} is not a valid subscriber email.", s))
         }
     }
+
+    /// Parses the address of someone subscribing, who cannot use a blocked domain.
+    pub fn parse_allowed(
+        s: String,
+        blocked_domains: &EmailDomainBlocklist,
+    ) -> Result<SubscriberEmail, String> {
+        let email = Self::parse(s)?;
+        if blocked_domains.contains(email.domain()) {
+            return Err(format!(
+                "Addresses from {} cannot be used to subscribe.",
+                email.domain()
+            ));
+        }
+        Ok(email)
+    }
+
+    pub fn domain(&self) -> &str {
+        self.0.rsplit_once('@').map_or("", |(_, domain)| domain)
+    }
 }
 
 impl std::fmt::Display for SubscriberEmail {
@@ -30,7 +51,8 @@ impl AsRef<str> for SubscriberEmail {
 #[cfg(test)]
 mod tests {
     use super::SubscriberEmail;
-    use claims::assert_err;
+    use crate::domain::EmailDomainBlocklist;
+    use claims::{assert_err, assert_ok};
     use fake::faker::internet::en::SafeEmail;
     use fake::Fake;
     use rand::rngs::StdRng;
@@ -54,6 +76,14 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn email_from_a_blocked_domain_is_rejected() {
+        let blocklist = EmailDomainBlocklist::new(["mailinator.com".to_string()]);
+        let email = "ursula@mailinator.com".to_string();
+        assert_ok!(SubscriberEmail::parse(email.clone()));
+        assert_err!(SubscriberEmail::parse_allowed(email, &blocklist));
+    }
+
     #[derive(Debug, Clone)]
     struct ValidEmailFixture(pub String);
 
@@ -0,0 +1,61 @@
+//! src/domain/username.rs
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The name an admin logs in with.
+#[derive(Debug)]
+pub struct Username(String);
+
+impl Username {
+    pub fn parse(s: String) -> Result<Username, String> {
+        let is_empty = s.is_empty();
+        let is_too_long = s.graphemes(true).count() > 64;
+        // Basic auth credentials are split on the first colon.
+        let contains_forbidden_characters = s
+            .chars()
+            .any(|c| c == ':' || c.is_whitespace() || c.is_control());
+
+        if is_empty || is_too_long || contains_forbidden_characters {
+            Err(format!(
+                "{} is not a valid username: usernames are 1 to 64 characters \
+                without spaces or colons.",
+                s
+            ))
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+
+impl AsRef<str> for Username {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::Username;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn a_64_grapheme_long_username_is_valid() {
+        assert_ok!(Username::parse("ü".repeat(64)));
+    }
+
+    #[test]
+    fn a_username_longer_than_64_graphemes_is_rejected() {
+        assert_err!(Username::parse("a".repeat(65)));
+    }
+
+    #[test]
+    fn empty_usernames_are_rejected() {
+        assert_err!(Username::parse("".to_string()));
+    }
+
+    #[test]
+    fn usernames_with_colons_or_spaces_are_rejected() {
+        assert_err!(Username::parse("ursula:le".to_string()));
+        assert_err!(Username::parse("ursula le".to_string()));
+    }
+}
@@ -1,19 +1,47 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
 use reqwest::Client;
 use secrecy::{ExposeSecret, Secret};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+use crate::domain::{SenderIdentity, SubscriberEmail};
+use crate::metrics::Metrics;
+
+/// Postmark-compatible providers accept at most 500 messages per batch call.
+const MAX_BATCH_SIZE: usize = 500;
 
-use crate::domain::SubscriberEmail;
+/// Postmark-compatible providers reject batch calls with a larger payload.
+const MAX_BATCH_PAYLOAD_SIZE: usize = 50 * 1024 * 1024;
+
+/// The largest total size of the attachments of a single message, once
+/// base64-encoded, accepted by the provider.
+pub const MAX_ATTACHMENTS_SIZE: usize = 10 * 1024 * 1024;
+
+/// Provider error codes that can go away by sending the same message again
+/// later: the API is down for maintenance. Any other code is permanent, so
+/// that we don't keep sending messages the provider will never accept.
+const TRANSIENT_ERROR_CODES: &[i64] = &[100];
 
 pub struct EmailClient {
     http_client: Client,
     base_url: String,
-    sender: SubscriberEmail,
+    sender: SenderIdentity,
+    sender_identities: HashMap<String, SenderIdentity>,
     authorization_token: Secret<String>,
+    throttle: Throttle,
+    transactional_stream: String,
+    broadcast_stream: String,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl EmailClient {
     pub fn new(
         base_url: String,
-        sender: SubscriberEmail,
+        sender: SenderIdentity,
         authorization_token: Secret<String>,
         timeout: std::time::Duration,
     ) -> Self {
@@ -21,39 +49,568 @@ impl EmailClient {
             http_client: Client::builder().timeout(timeout).build().unwrap(),
             base_url,
             sender,
+            sender_identities: HashMap::new(),
             authorization_token,
+            throttle: Throttle::new(Semaphore::MAX_PERMITS, None),
+            transactional_stream: "outbound".into(),
+            broadcast_stream: "broadcast".into(),
+            metrics: None,
         }
     }
 
+    /// Make the named sender identities available to [`EmailClient::sender_identity`].
+    pub fn with_sender_identities(
+        mut self,
+        sender_identities: HashMap<String, SenderIdentity>,
+    ) -> Self {
+        self.sender_identities = sender_identities;
+        self
+    }
+
+    /// Look up a sender identity by name.
+    /// Messages are sent from the default sender unless they pick one.
+    pub fn sender_identity(&self, name: &str) -> Option<&SenderIdentity> {
+        self.sender_identities.get(name)
+    }
+
+    /// Route messages through the named provider message streams, keeping
+    /// the reputation of transactional emails apart from bulk ones.
+    pub fn with_message_streams(mut self, transactional: String, broadcast: String) -> Self {
+        self.transactional_stream = transactional;
+        self.broadcast_stream = broadcast;
+        self
+    }
+
+    /// The message stream for emails triggered by a user action, e.g. a
+    /// subscription confirmation.
+    pub fn transactional_stream(&self) -> &str {
+        &self.transactional_stream
+    }
+
+    /// The message stream for emails sent to a whole list, e.g. newsletter issues.
+    pub fn broadcast_stream(&self) -> &str {
+        &self.broadcast_stream
+    }
+
+    /// Cap the number of requests to the email API that can be in flight at
+    /// the same time and how many of them can be started every second.
+    ///
+    /// The limits are shared by every caller of this client.
+    pub fn with_rate_limit(
+        mut self,
+        max_concurrent_requests: usize,
+        max_requests_per_second: u32,
+    ) -> Self {
+        self.throttle = Throttle::new(max_concurrent_requests, Some(max_requests_per_second));
+        self
+    }
+
+    /// Record the outcome and latency of the calls to the email API.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn record_request(
+        &self,
+        endpoint: &str,
+        messages: usize,
+        rejected: usize,
+        started_at: Instant,
+    ) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_email_api_request(endpoint, messages, rejected, started_at.elapsed());
+        }
+    }
+
+    /// Send an email through the provider API.
+    ///
+    /// Returns the message id assigned by the provider, if it reported one.
     pub async fn send_email(
         &self,
         recipient: &SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), reqwest::Error> {
-        let url = format!("{}/email", self.base_url);
-        let request_body = SendEmailRequest {
-            from: self.sender.as_ref(),
-            to: recipient.as_ref(),
+    ) -> Result<Option<String>, reqwest::Error> {
+        self.send(&EmailMessage::new(
+            recipient,
             subject,
-            html_body: html_content,
-            text_body: text_content,
-        };
+            html_content,
+            text_content,
+        ))
+        .await
+    }
 
+    /// Checks that the provider API answers and accepts our token, without
+    /// sending anything.
+    pub async fn check_health(&self) -> Result<(), reqwest::Error> {
+        let url = format!("{}/server", self.base_url);
         self.http_client
-            .post(&url)
+            .get(&url)
             .header(
                 "X-Postmark-Server-Token",
                 self.authorization_token.expose_secret(),
             )
-            .json(&request_body)
+            .header(reqwest::header::ACCEPT, "application/json")
             .send()
             .await?
             .error_for_status()?;
-
         Ok(())
     }
+
+    /// Send a single message through the provider API.
+    ///
+    /// Returns the message id assigned by the provider, if it reported one.
+    pub async fn send(&self, message: &EmailMessage<'_>) -> Result<Option<String>, reqwest::Error> {
+        let url = format!("{}/email", self.base_url);
+        let request_body = SendEmailRequest::new(&self.sender, message);
+
+        let _permit = self.throttle.acquire().await;
+        let started_at = Instant::now();
+        let response_body = async {
+            self.http_client
+                .post(&url)
+                .header(
+                    "X-Postmark-Server-Token",
+                    self.authorization_token.expose_secret(),
+                )
+                .json(&request_body)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await
+        }
+        .await;
+        self.record_request("email", 1, usize::from(response_body.is_err()), started_at);
+        let response_body = response_body?;
+
+        Ok(serde_json::from_slice::<SendEmailResponse>(&response_body)
+            .ok()
+            .map(|r| r.message_id))
+    }
+
+    /// Send many emails through the provider batch API, in chunks of at most
+    /// `MAX_BATCH_SIZE` messages and `MAX_BATCH_PAYLOAD_SIZE` bytes.
+    ///
+    /// Returns one outcome per message, in the same order as `messages`.
+    pub async fn send_batch(&self, messages: &[EmailMessage<'_>]) -> Vec<SendOutcome> {
+        let chunks = batch_chunks(messages).map(|chunk| self.send_batch_chunk(chunk));
+
+        futures::future::join_all(chunks)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    async fn send_batch_chunk(&self, chunk: &[EmailMessage<'_>]) -> Vec<SendOutcome> {
+        let results = match self.post_batch(chunk).await {
+            Ok(results) if results.len() == chunk.len() => results,
+            Ok(results) => {
+                let error = format!(
+                    "{} results for a batch of {} messages",
+                    results.len(),
+                    chunk.len()
+                );
+                return chunk
+                    .iter()
+                    .map(|_| Err(SendError::UnexpectedResponse(error.clone())))
+                    .collect();
+            }
+            Err(e) => return chunk.iter().map(|_| Err(e.clone())).collect(),
+        };
+
+        results
+            .into_iter()
+            .map(|r| match r.error_code {
+                0 => Ok(r.message_id),
+                error_code => Err(SendError::Rejected {
+                    error_code,
+                    message: r.message,
+                }),
+            })
+            .collect()
+    }
+
+    async fn post_batch(&self, chunk: &[EmailMessage<'_>]) -> Result<Vec<BatchResult>, SendError> {
+        let url = format!("{}/email/batch", self.base_url);
+        let request_body: Vec<_> = chunk
+            .iter()
+            .map(|message| SendEmailRequest::new(&self.sender, message))
+            .collect();
+
+        let _permit = self.throttle.acquire().await;
+        let started_at = Instant::now();
+        let results = async {
+            let response_body = self
+                .http_client
+                .post(&url)
+                .header(
+                    "X-Postmark-Server-Token",
+                    self.authorization_token.expose_secret(),
+                )
+                .json(&request_body)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| SendError::RequestFailed(Arc::new(e)))?
+                .bytes()
+                .await
+                .map_err(|e| SendError::RequestFailed(Arc::new(e)))?;
+
+            serde_json::from_slice::<Vec<BatchResult>>(&response_body)
+                .map_err(|e| SendError::UnexpectedResponse(e.to_string()))
+        }
+        .await;
+
+        let rejected = match &results {
+            Ok(results) => results.iter().filter(|r| r.error_code != 0).count(),
+            Err(_) => chunk.len(),
+        };
+        self.record_request(
+            "email_batch",
+            chunk.len(),
+            rejected.min(chunk.len()),
+            started_at,
+        );
+        results
+    }
+}
+
+/// Split `messages` into consecutive chunks that fit in a single batch call.
+fn batch_chunks<'a, 'm>(
+    messages: &'a [EmailMessage<'m>],
+) -> impl Iterator<Item = &'a [EmailMessage<'m>]> {
+    let mut rest = messages;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut len = 0;
+        let mut payload_size = 0;
+        for message in rest.iter().take(MAX_BATCH_SIZE) {
+            payload_size += message.payload_size();
+            // A chunk holds at least one message, however large it is.
+            if len > 0 && payload_size > MAX_BATCH_PAYLOAD_SIZE {
+                break;
+            }
+            len += 1;
+        }
+        let (chunk, tail) = rest.split_at(len);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+/// An email to a single recipient; optional fields are set by chaining
+/// the builder methods on top of [`EmailMessage::new`].
+#[derive(Clone)]
+pub struct EmailMessage<'a> {
+    recipient: &'a SubscriberEmail,
+    subject: &'a str,
+    html_content: &'a str,
+    text_content: &'a str,
+    sender: Option<&'a SenderIdentity>,
+    reply_to: Option<&'a SubscriberEmail>,
+    cc: Vec<&'a SubscriberEmail>,
+    bcc: Vec<&'a SubscriberEmail>,
+    message_stream: Option<&'a str>,
+    tag: Option<&'a str>,
+    headers: Vec<(String, String)>,
+    metadata: BTreeMap<String, String>,
+    attachments: Option<&'a Attachments>,
+}
+
+impl<'a> EmailMessage<'a> {
+    pub fn new(
+        recipient: &'a SubscriberEmail,
+        subject: &'a str,
+        html_content: &'a str,
+        text_content: &'a str,
+    ) -> Self {
+        Self {
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            sender: None,
+            reply_to: None,
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            message_stream: None,
+            tag: None,
+            headers: Vec::new(),
+            metadata: BTreeMap::new(),
+            attachments: None,
+        }
+    }
+
+    /// Send from another identity than the default sender of the client.
+    pub fn sender(mut self, sender: &'a SenderIdentity) -> Self {
+        self.sender = Some(sender);
+        self
+    }
+
+    pub fn reply_to(mut self, address: &'a SubscriberEmail) -> Self {
+        self.reply_to = Some(address);
+        self
+    }
+
+    pub fn cc(mut self, address: &'a SubscriberEmail) -> Self {
+        self.cc.push(address);
+        self
+    }
+
+    pub fn bcc(mut self, address: &'a SubscriberEmail) -> Self {
+        self.bcc.push(address);
+        self
+    }
+
+    /// The provider message stream to send through, its default one if unset.
+    pub fn message_stream(mut self, message_stream: &'a str) -> Self {
+        self.message_stream = Some(message_stream);
+        self
+    }
+
+    /// A label used by the provider to group statistics and webhook events.
+    pub fn tag(mut self, tag: &'a str) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Data attached to the message by the provider, and sent back with
+    /// its webhook events.
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn attachments(mut self, attachments: &'a Attachments) -> Self {
+        self.attachments = Some(attachments);
+        self
+    }
+
+    /// A rough estimate of the size of the message in the request body.
+    fn payload_size(&self) -> usize {
+        let sender = self.sender.map_or(0, |s| s.as_ref().len());
+        let addresses = std::iter::once(self.recipient)
+            .chain(self.reply_to)
+            .chain(self.cc.iter().copied())
+            .chain(self.bcc.iter().copied());
+        let fields = self
+            .headers
+            .iter()
+            .map(|(k, v)| (k, v))
+            .chain(&self.metadata);
+
+        sender
+            + addresses.map(|a| a.as_ref().len()).sum::<usize>()
+            + fields.map(|(k, v)| k.len() + v.len()).sum::<usize>()
+            + self.subject.len()
+            + self.html_content.len()
+            + self.text_content.len()
+            + self.attachments.map_or(0, Attachments::size)
+    }
+}
+
+/// A file attached to an email.
+///
+/// Inline attachments are not offered as downloads: the HTML body embeds
+/// them with `<img src="cid:{content_id}">`.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    name: String,
+    content_type: String,
+    content_id: Option<String>,
+    /// Base64-encoded once, rather than for every recipient.
+    content: String,
+}
+
+impl Attachment {
+    pub fn new(name: String, content_type: String, content: &[u8]) -> Self {
+        Self {
+            name,
+            content_type,
+            content_id: None,
+            content: STANDARD.encode(content),
+        }
+    }
+
+    /// An image embedded in the HTML body, referenced as `cid:{name}`.
+    pub fn inline(name: String, content_type: String, content: &[u8]) -> Self {
+        Self {
+            content_id: Some(name.clone()),
+            ..Self::new(name, content_type, content)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn content_id(&self) -> Option<&str> {
+        self.content_id.as_deref()
+    }
+
+    /// The base64-encoded content.
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+}
+
+/// The attachments of a single message, within the provider limits.
+#[derive(Debug, Clone, Default)]
+pub struct Attachments(Vec<Attachment>);
+
+impl Attachments {
+    pub fn parse(attachments: Vec<Attachment>) -> Result<Attachments, AttachmentsError> {
+        let mut names = std::collections::HashSet::new();
+        for attachment in &attachments {
+            if attachment.name.trim().is_empty() {
+                return Err(AttachmentsError::Invalid(
+                    "Attachments must have a name.".into(),
+                ));
+            }
+            if !names.insert(attachment.name.as_str()) {
+                return Err(AttachmentsError::Invalid(format!(
+                    "There is more than one attachment named {}.",
+                    attachment.name
+                )));
+            }
+            if attachment.content_id.is_some() && !attachment.content_type.starts_with("image/") {
+                return Err(AttachmentsError::Invalid(format!(
+                    "{} is not an image: only images can be inlined.",
+                    attachment.name
+                )));
+            }
+        }
+
+        let attachments = Self(attachments);
+        if attachments.size() > MAX_ATTACHMENTS_SIZE {
+            return Err(AttachmentsError::TooLarge(attachments.size()));
+        }
+        Ok(attachments)
+    }
+
+    /// The total size of the encoded attachments, in bytes.
+    pub fn size(&self) -> usize {
+        self.0.iter().map(|a| a.content.len()).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Attachment> {
+        self.0.iter()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AttachmentsError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error(
+        "Attachments take {0} bytes once encoded, the limit is {max} bytes.",
+        max = MAX_ATTACHMENTS_SIZE
+    )]
+    TooLarge(usize),
+}
+
+/// The provider message id on success.
+pub type SendOutcome = Result<Option<String>, SendError>;
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum SendError {
+    #[error("Failed to call the email API")]
+    RequestFailed(#[source] Arc<reqwest::Error>),
+    #[error("The email API rejected the message with error code {error_code}: {message}")]
+    Rejected { error_code: i64, message: String },
+    #[error("The email API returned an unexpected response: {0}")]
+    UnexpectedResponse(String),
+}
+
+impl SendError {
+    /// Whether sending the same message again later could succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SendError::RequestFailed(e) => match e.status() {
+                Some(status) => {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                // Timeouts and connection errors
+                None => true,
+            },
+            SendError::Rejected { error_code, .. } => TRANSIENT_ERROR_CODES.contains(error_code),
+            // We can't tell which messages went out: retrying could send duplicates.
+            SendError::UnexpectedResponse(_) => false,
+        }
+    }
+}
+
+/// Spaces out requests to the email API so that we stay within the provider
+/// rate limit, and bounds how many of them are in flight at the same time.
+struct Throttle {
+    permits: Semaphore,
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn new(max_concurrent_requests: usize, max_requests_per_second: Option<u32>) -> Self {
+        let max_concurrent_requests = max_concurrent_requests.clamp(1, Semaphore::MAX_PERMITS);
+        let interval = match max_requests_per_second {
+            Some(rate) => Duration::from_secs(1) / rate.max(1),
+            None => Duration::ZERO,
+        };
+        Self {
+            permits: Semaphore::new(max_concurrent_requests),
+            interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for a free slot; the request can be sent while the permit is held.
+    async fn acquire(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("The throttle semaphore is never closed");
+        tokio::time::sleep_until(self.reserve_slot()).await;
+        permit
+    }
+
+    /// The earliest time the next request can start at, at least `interval`
+    /// after the previous one.
+    fn reserve_slot(&self) -> Instant {
+        let mut next_slot = self.next_slot.lock().unwrap();
+        let slot = (*next_slot).max(Instant::now());
+        *next_slot = slot + self.interval;
+        slot
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    error_code: i64,
+    message: String,
+    #[serde(rename = "MessageID", default)]
+    message_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: String,
 }
 
 #[derive(serde::Serialize)]
@@ -61,20 +618,112 @@ impl EmailClient {
 struct SendEmailRequest<'a> {
     from: &'a str,
     to: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bcc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
     subject: &'a str,
     html_body: &'a str,
     text_body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_stream: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<HeaderRequest<'a>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentRequest<'a>>,
+}
+
+impl<'a> SendEmailRequest<'a> {
+    fn new(default_sender: &'a SenderIdentity, message: &'a EmailMessage<'_>) -> Self {
+        // The provider takes a comma-separated list of addresses.
+        let join = |addresses: &[&SubscriberEmail]| {
+            (!addresses.is_empty()).then(|| {
+                addresses
+                    .iter()
+                    .map(|a| a.as_ref())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+        };
+
+        Self {
+            from: message.sender.unwrap_or(default_sender).as_ref(),
+            to: message.recipient.as_ref(),
+            cc: join(&message.cc),
+            bcc: join(&message.bcc),
+            reply_to: message.reply_to.map(AsRef::as_ref),
+            subject: message.subject,
+            html_body: message.html_content,
+            text_body: message.text_content,
+            message_stream: message.message_stream,
+            tag: message.tag,
+            headers: message
+                .headers
+                .iter()
+                .map(|(name, value)| HeaderRequest { name, value })
+                .collect(),
+            metadata: &message.metadata,
+            attachments: message
+                .attachments
+                .iter()
+                .flat_map(|a| a.iter())
+                .map(AttachmentRequest::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HeaderRequest<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AttachmentRequest<'a> {
+    name: &'a str,
+    content: &'a str,
+    content_type: &'a str,
+    #[serde(rename = "ContentID", skip_serializing_if = "Option::is_none")]
+    content_id: Option<String>,
+}
+
+impl<'a> From<&'a Attachment> for AttachmentRequest<'a> {
+    fn from(attachment: &'a Attachment) -> Self {
+        Self {
+            name: &attachment.name,
+            content: &attachment.content,
+            content_type: &attachment.content_type,
+            content_id: attachment
+                .content_id
+                .as_ref()
+                .map(|id| format!("cid:{}", id)),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
-    use claims::{assert_err, assert_ok};
+    use crate::domain::{SenderIdentity, SubscriberEmail};
+    use crate::email_client::{
+        batch_chunks, Attachment, Attachments, EmailClient, EmailMessage, SendError, Throttle,
+        MAX_ATTACHMENTS_SIZE,
+    };
+    use claims::{assert_err, assert_ok, assert_ok_eq};
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::{Fake, Faker};
+    use futures::FutureExt;
     use secrecy::Secret;
+    use std::time::Duration;
     use wiremock::matchers::{any, header, header_exists, method, path};
     use wiremock::{Mock, MockServer, Request, ResponseTemplate};
 
@@ -95,6 +744,21 @@ mod tests {
         }
     }
 
+    struct BatchSizeMatcher(usize);
+
+    impl wiremock::Match for BatchSizeMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<Vec<serde_json::Value>, _> = serde_json::from_slice(&request.body);
+            matches!(result, Ok(messages) if messages.len() == self.0)
+        }
+    }
+
+    fn batch_response(n_messages: usize) -> serde_json::Value {
+        (0..n_messages)
+            .map(|i| serde_json::json!({ "ErrorCode": 0, "Message": "OK", "MessageID": i.to_string() }))
+            .collect()
+    }
+
     /// Generate a random email subject
     fn subject() -> String {
         Sentence(1..2).fake()
@@ -114,9 +778,9 @@ mod tests {
     fn email_client(base_url: String) -> EmailClient {
         EmailClient::new(
             base_url,
-            email(),
+            email().into(),
             Secret::new(Faker.fake()),
-            std::time::Duration::from_millis(200),
+            Duration::from_millis(200),
         )
     }
 
@@ -165,6 +829,35 @@ mod tests {
         assert_ok!(outcome);
     }
 
+    #[tokio::test]
+    async fn send_email_returns_the_provider_message_id() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "To": "ursula_le_guin@gmail.com",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+                "ErrorCode": 0,
+                "Message": "OK"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok_eq!(
+            outcome,
+            Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817".to_string())
+        );
+    }
+
     #[tokio::test]
     async fn send_email_fails_if_the_server_returns_500() {
         // Arrange
@@ -187,13 +880,324 @@ mod tests {
         assert_err!(outcome);
     }
 
+    #[tokio::test]
+    async fn send_includes_the_optional_fields_of_the_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (recipient, subject, content) = (email(), subject(), content());
+        let (reply_to, first_cc, second_cc, bcc) = (email(), email(), email(), email());
+        let message = EmailMessage::new(&recipient, &subject, &content, &content)
+            .reply_to(&reply_to)
+            .cc(&first_cc)
+            .cc(&second_cc)
+            .bcc(&bcc)
+            .message_stream("broadcast")
+            .tag("newsletter")
+            .header("X-Campaign", "autumn")
+            .metadata("newsletter_issue_id", "42");
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send(&message).await;
+
+        // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["ReplyTo"], reply_to.as_ref());
+        assert_eq!(
+            body["Cc"],
+            format!("{}, {}", first_cc.as_ref(), second_cc.as_ref())
+        );
+        assert_eq!(body["Bcc"], bcc.as_ref());
+        assert_eq!(body["MessageStream"], "broadcast");
+        assert_eq!(body["Tag"], "newsletter");
+        assert_eq!(
+            body["Headers"],
+            serde_json::json!([{ "Name": "X-Campaign", "Value": "autumn" }])
+        );
+        assert_eq!(
+            body["Metadata"],
+            serde_json::json!({ "newsletter_issue_id": "42" })
+        );
+    }
+
+    #[tokio::test]
+    async fn send_uses_the_sender_identity_picked_by_the_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (recipient, subject, content) = (email(), subject(), content());
+        let sender = SenderIdentity::parse(
+            Some("Team Blog".into()),
+            SubscriberEmail::parse("news@example.com".into()).unwrap(),
+        )
+        .unwrap();
+        let message = EmailMessage::new(&recipient, &subject, &content, &content).sender(&sender);
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send(&message).await;
+
+        // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["From"], "Team Blog <news@example.com>");
+    }
+
+    #[tokio::test]
+    async fn send_email_leaves_out_the_optional_fields() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&request.body).unwrap();
+        let mut fields: Vec<_> = body.keys().map(String::as_str).collect();
+        fields.sort();
+        assert_eq!(fields, ["From", "HtmlBody", "Subject", "TextBody", "To"]);
+    }
+
+    #[tokio::test]
+    async fn send_batch_sends_one_request_per_chunk_of_500_messages() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (recipient, subject, content) = (email(), subject(), content());
+        let messages: Vec<_> = (0..501)
+            .map(|_| EmailMessage::new(&recipient, &subject, &content, &content))
+            .collect();
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .and(header_exists("X-Postmark-Server-Token"))
+            .and(BatchSizeMatcher(500))
+            .respond_with(ResponseTemplate::new(200).set_body_json(batch_response(500)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .and(BatchSizeMatcher(1))
+            .respond_with(ResponseTemplate::new(200).set_body_json(batch_response(1)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&messages).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 501);
+        assert!(outcomes.iter().all(|o| o.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_failures_for_each_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (recipient, subject, content) = (email(), subject(), content());
+        let message = || EmailMessage::new(&recipient, &subject, &content, &content);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                { "ErrorCode": 0, "Message": "OK", "MessageID": "first" },
+                { "ErrorCode": 406, "Message": "Inactive recipient" },
+                { "ErrorCode": 100, "Message": "Maintenance" },
+                { "ErrorCode": 1234, "Message": "Unknown" },
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client
+            .send_batch(&[message(), message(), message(), message()])
+            .await;
+
+        // Assert
+        assert!(matches!(&outcomes[0], Ok(Some(id)) if id == "first"));
+        assert!(matches!(
+            &outcomes[1],
+            Err(e @ SendError::Rejected { error_code: 406, .. }) if !e.is_retryable()
+        ));
+        assert!(matches!(
+            &outcomes[2],
+            Err(e @ SendError::Rejected { error_code: 100, .. }) if e.is_retryable()
+        ));
+        assert!(matches!(
+            &outcomes[3],
+            Err(e @ SendError::Rejected { error_code: 1234, .. }) if !e.is_retryable()
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_every_message_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (recipient, subject, content) = (email(), subject(), content());
+        let message = || EmailMessage::new(&recipient, &subject, &content, &content);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&[message(), message()]).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 2);
+        for outcome in outcomes {
+            assert!(matches!(outcome, Err(e @ SendError::RequestFailed(_)) if e.is_retryable()));
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_sends_attachments_base64_encoded() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (recipient, subject, content) = (email(), subject(), content());
+        let attachments = Attachments::parse(vec![
+            Attachment::new("notes.txt".into(), "text/plain".into(), b"Hello"),
+            Attachment::inline("logo.png".into(), "image/png".into(), &[137, 80, 78, 71]),
+        ])
+        .unwrap();
+        let message =
+            EmailMessage::new(&recipient, &subject, &content, &content).attachments(&attachments);
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(batch_response(1)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&[message]).await;
+
+        // Assert
+        assert!(outcomes[0].is_ok());
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body[0]["Attachments"],
+            serde_json::json!([
+                { "Name": "notes.txt", "Content": "SGVsbG8=", "ContentType": "text/plain" },
+                {
+                    "Name": "logo.png",
+                    "Content": "iVBORw==",
+                    "ContentType": "image/png",
+                    "ContentID": "cid:logo.png"
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn batches_are_split_to_stay_under_the_payload_limit() {
+        let (recipient, subject, content) = (email(), subject(), content());
+        // 9MB once base64-encoded
+        let attachments = Attachments::parse(vec![Attachment::new(
+            "report.pdf".into(),
+            "application/pdf".into(),
+            &vec![0; 6_750_000],
+        )])
+        .unwrap();
+        let messages: Vec<_> = (0..6)
+            .map(|_| {
+                EmailMessage::new(&recipient, &subject, &content, &content)
+                    .attachments(&attachments)
+            })
+            .collect();
+
+        let chunk_sizes: Vec<_> = batch_chunks(&messages).map(|c| c.len()).collect();
+
+        assert_eq!(chunk_sizes, vec![5, 1]);
+    }
+
+    #[test]
+    fn attachments_over_the_size_limit_are_rejected() {
+        let content = vec![0; MAX_ATTACHMENTS_SIZE];
+        assert_err!(Attachments::parse(vec![Attachment::new(
+            "report.pdf".into(),
+            "application/pdf".into(),
+            &content,
+        )]));
+    }
+
+    #[test]
+    fn only_images_can_be_inlined() {
+        assert_err!(Attachments::parse(vec![Attachment::inline(
+            "notes.txt".into(),
+            "text/plain".into(),
+            b"Hello",
+        )]));
+        assert_ok!(Attachments::parse(vec![Attachment::inline(
+            "logo.png".into(),
+            "image/png".into(),
+            &[137, 80, 78, 71],
+        )]));
+    }
+
+    #[test]
+    fn requests_are_spaced_out_by_the_rate_limit() {
+        let throttle = Throttle::new(10, Some(1));
+
+        let slots: Vec<_> = (0..3).map(|_| throttle.reserve_slot()).collect();
+
+        assert_eq!(slots[1] - slots[0], Duration::from_secs(1));
+        assert_eq!(slots[2] - slots[1], Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_concurrency_limit_wait_for_a_permit() {
+        let throttle = Throttle::new(2, None);
+
+        let first = throttle.acquire().await;
+        let _second = throttle.acquire().await;
+        assert!(throttle.acquire().now_or_never().is_none());
+
+        drop(first);
+        assert_eq!(throttle.permits.available_permits(), 1);
+    }
+
     #[tokio::test]
     async fn send_email_times_out_if_the_server_takes_too_long() {
         // Arrange
         let mock_server = MockServer::start().await;
         let email_client = email_client(mock_server.uri());
 
-        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+        let response = ResponseTemplate::new(200).set_delay(Duration::from_secs(180));
         Mock::given(any())
             .respond_with(response)
             .expect(1)
@@ -0,0 +1,389 @@
+//! src/invitations.rs
+
+use anyhow::Context;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::compute_password_hash;
+use crate::authorization::Role;
+use crate::configuration::PasswordHashingSettings;
+use crate::domain::{NewPassword, SubscriberEmail, Username};
+use crate::email_client::{EmailClient, EmailMessage};
+use crate::signed_token::{self, Purpose};
+use crate::telemetry::spawn_blocking_with_tracing;
+
+/// How long an invitation can be accepted for.
+const INVITATION_LIFETIME_DAYS: i64 = 7;
+
+#[derive(thiserror::Error, Debug)]
+pub enum InvitationError {
+    #[error("The invitation is invalid or has expired.")]
+    InvalidInvitation,
+    #[error("A user already has this email address.")]
+    EmailTaken,
+    #[error("This username is already taken.")]
+    UsernameTaken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// The payload of invitation links, signed with the application HMAC secret so
+/// that invitation ids cannot be guessed or links forged.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvitationToken {
+    pub invitation_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl InvitationToken {
+    pub fn encode(&self, secret: &Secret<String>) -> String {
+        let payload = format!("{}:{}", self.invitation_id, self.expires_at.timestamp());
+        signed_token::sign(Purpose::Invitation, payload.as_bytes(), secret)
+    }
+
+    /// Decodes a token, as long as it is signed and has not expired.
+    pub fn decode(token: &str, secret: &Secret<String>) -> Result<Self, anyhow::Error> {
+        let payload = signed_token::verify(Purpose::Invitation, token, secret)
+            .context("Invalid invitation token.")?;
+
+        let payload =
+            String::from_utf8(payload).context("The invitation token is not valid UTF8.")?;
+        let (invitation_id, expires_at) = payload
+            .split_once(':')
+            .context("The invitation token is missing its expiry.")?;
+        let invitation_id = invitation_id.parse().context("Invalid invitation id.")?;
+        let expires_at = Utc
+            .timestamp_opt(expires_at.parse().context("Invalid expiry.")?, 0)
+            .single()
+            .context("Invalid expiry.")?;
+        if expires_at <= Utc::now() {
+            anyhow::bail!("The invitation has expired.");
+        }
+
+        Ok(Self {
+            invitation_id,
+            expires_at,
+        })
+    }
+}
+
+/// An invitation that has been neither accepted nor revoked, and has not expired.
+#[derive(serde::Serialize)]
+pub struct PendingInvitation {
+    pub invitation_id: Uuid,
+    pub email: String,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Who sends invitation emails, and what links in them point to.
+pub struct InvitationMailer<'a> {
+    pub email_client: &'a EmailClient,
+    pub base_url: &'a str,
+    pub hmac_secret: &'a Secret<String>,
+}
+
+/// Invites someone to become an admin with the given role. Their user is
+/// only created once they accept, with the username and password they pick.
+#[tracing::instrument(name = "Invite a user", skip(pool, mailer))]
+pub async fn invite(
+    pool: &PgPool,
+    mailer: &InvitationMailer<'_>,
+    email: &SubscriberEmail,
+    role: Role,
+    invited_by: Uuid,
+) -> Result<InvitationToken, InvitationError> {
+    let email_taken = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM users WHERE lower(email) = lower($1)) AS "taken!""#,
+        email.as_ref()
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to look for a user with the email address.")?;
+    if email_taken {
+        return Err(InvitationError::EmailTaken);
+    }
+
+    let token = InvitationToken {
+        invitation_id: Uuid::new_v4(),
+        // Whole seconds, as in the signed token.
+        expires_at: Utc
+            .timestamp_opt(
+                (Utc::now() + Duration::days(INVITATION_LIFETIME_DAYS)).timestamp(),
+                0,
+            )
+            .unwrap(),
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO invitations (invitation_id, email, role, invited_by, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, now(), $5)
+        "#,
+        token.invitation_id,
+        email.as_ref(),
+        role.as_str(),
+        invited_by,
+        token.expires_at
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the invitation.")?;
+
+    send_invitation_email(mailer, email, role, &token)
+        .await
+        .context("Failed to send the invitation email.")?;
+    tracing::info!(
+        target: "audit",
+        invitation_id = %token.invitation_id,
+        %invited_by,
+        %role,
+        "Invited a user"
+    );
+    Ok(token)
+}
+
+async fn send_invitation_email(
+    mailer: &InvitationMailer<'_>,
+    email: &SubscriberEmail,
+    role: Role,
+    token: &InvitationToken,
+) -> Result<(), reqwest::Error> {
+    let invitation_link = format!(
+        "{}/invitations/accept?token={}",
+        mailer.base_url,
+        token.encode(mailer.hmac_secret)
+    );
+    let plain_body = format!(
+        "You have been invited to join the blog as {} {}.\n\
+        Visit {} within {} days to pick your username and password.",
+        if role == Role::Owner { "an" } else { "a" },
+        role,
+        invitation_link,
+        INVITATION_LIFETIME_DAYS
+    );
+    let html_body = format!(
+        "You have been invited to join the blog as {} {}.<br />\
+        Click <a href=\"{}\">here</a> within {} days to pick your username and password.",
+        if role == Role::Owner { "an" } else { "a" },
+        role,
+        invitation_link,
+        INVITATION_LIFETIME_DAYS
+    );
+
+    let message = EmailMessage::new(email, "You are invited", &html_body, &plain_body)
+        .message_stream(mailer.email_client.transactional_stream())
+        .tag("invitation");
+    mailer.email_client.send(&message).await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get a pending invitation", skip(pool))]
+pub async fn get_pending_invitation(
+    pool: &PgPool,
+    invitation_id: Uuid,
+) -> Result<Option<PendingInvitation>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT invitation_id, email, role, created_at, expires_at
+        FROM invitations
+        WHERE invitation_id = $1
+            AND accepted_at IS NULL AND revoked_at IS NULL AND expires_at > now()
+        "#,
+        invitation_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the invitation.")?;
+
+    row.map(|row| {
+        Ok(PendingInvitation {
+            invitation_id: row.invitation_id,
+            email: row.email,
+            role: row.role.parse().map_err(anyhow::Error::msg)?,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+        })
+    })
+    .transpose()
+}
+
+#[tracing::instrument(name = "List pending invitations", skip(pool))]
+pub async fn list_pending_invitations(
+    pool: &PgPool,
+) -> Result<Vec<PendingInvitation>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT invitation_id, email, role, created_at, expires_at
+        FROM invitations
+        WHERE accepted_at IS NULL AND revoked_at IS NULL AND expires_at > now()
+        ORDER BY created_at
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve the pending invitations.")?;
+
+    rows.into_iter()
+        .map(|row| {
+            Ok(PendingInvitation {
+                invitation_id: row.invitation_id,
+                email: row.email,
+                role: row.role.parse().map_err(anyhow::Error::msg)?,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+            })
+        })
+        .collect()
+}
+
+/// Revokes a pending invitation, returning whether there was one to revoke.
+#[tracing::instrument(name = "Revoke an invitation", skip(pool))]
+pub async fn revoke(pool: &PgPool, invitation_id: Uuid) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE invitations
+        SET revoked_at = now()
+        WHERE invitation_id = $1 AND accepted_at IS NULL AND revoked_at IS NULL
+        "#,
+        invitation_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to revoke the invitation.")?;
+
+    let revoked = result.rows_affected() > 0;
+    if revoked {
+        tracing::info!(target: "audit", %invitation_id, "Revoked an invitation");
+    }
+    Ok(revoked)
+}
+
+/// Creates the user of an invitation, which cannot be used again.
+#[tracing::instrument(name = "Accept an invitation", skip(pool, password, hashing))]
+pub async fn accept(
+    pool: &PgPool,
+    invitation_id: Uuid,
+    username: &Username,
+    password: NewPassword,
+    hashing: &PasswordHashingSettings,
+) -> Result<Uuid, InvitationError> {
+    // Checked before hashing, so that invalid invitations do not cost a hash.
+    if get_pending_invitation(pool, invitation_id).await?.is_none() {
+        return Err(InvitationError::InvalidInvitation);
+    }
+    let hashing = *hashing;
+    let password_hash = spawn_blocking_with_tracing(move || {
+        compute_password_hash(password.into_secret(), &hashing)
+    })
+    .await
+    .context("Failed to spawn blocking task.")??;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let invitation = sqlx::query!(
+        r#"
+        SELECT email, role
+        FROM invitations
+        WHERE invitation_id = $1
+            AND accepted_at IS NULL AND revoked_at IS NULL AND expires_at > now()
+        FOR UPDATE
+        "#,
+        invitation_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to retrieve the invitation.")?
+    .ok_or(InvitationError::InvalidInvitation)?;
+
+    let user_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash, email, role)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        user_id,
+        username.as_ref(),
+        password_hash.expose_secret(),
+        invitation.email,
+        invitation.role
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| match e.as_database_error() {
+        Some(db_error) if db_error.is_unique_violation() => {
+            if db_error.constraint() == Some("users_lower_email_key") {
+                InvitationError::EmailTaken
+            } else {
+                InvitationError::UsernameTaken
+            }
+        }
+        _ => InvitationError::UnexpectedError(
+            anyhow::Error::new(e).context("Failed to create the invited user."),
+        ),
+    })?;
+    sqlx::query!(
+        r#"
+        UPDATE invitations
+        SET accepted_at = now(), accepted_by = $2
+        WHERE invitation_id = $1
+        "#,
+        invitation_id,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to mark the invitation as accepted.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the accepted invitation.")?;
+
+    tracing::info!(target: "audit", %invitation_id, %user_id, "Accepted an invitation");
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InvitationToken;
+    use chrono::{Duration, TimeZone, Utc};
+    use claims::assert_err;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn secret() -> Secret<String> {
+        Secret::new("an-invitation-secret".to_string())
+    }
+
+    fn token(expires_in: Duration) -> InvitationToken {
+        InvitationToken {
+            invitation_id: Uuid::new_v4(),
+            expires_at: Utc
+                .timestamp_opt((Utc::now() + expires_in).timestamp(), 0)
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let token = token(Duration::days(1));
+        let decoded = InvitationToken::decode(&token.encode(&secret()), &secret()).unwrap();
+        assert_eq!(decoded, token);
+    }
+
+    #[test]
+    fn tokens_signed_with_another_secret_are_rejected() {
+        let encoded = token(Duration::days(1)).encode(&Secret::new("another".to_string()));
+        assert_err!(InvitationToken::decode(&encoded, &secret()));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let encoded = token(Duration::days(-1)).encode(&secret());
+        assert_err!(InvitationToken::decode(&encoded, &secret()));
+    }
+}
@@ -1,8 +1,26 @@
+pub mod api_tokens;
 pub mod application_state;
+pub mod auth_events;
 pub mod authentication;
+pub mod authorization;
+pub mod background_tasks;
+pub mod client_ip;
 pub mod configuration;
+pub mod csrf;
 pub mod domain;
 pub mod email_client;
+pub mod invitations;
+pub mod login_throttle;
+pub mod metrics;
+pub mod newsletter_delivery;
+pub mod oidc;
+pub mod password_reset;
+pub mod rate_limit;
 pub mod routes;
+pub mod security_headers;
+pub mod signed_token;
 pub mod startup;
+pub mod subscription_protection;
 pub mod telemetry;
+pub mod tracking;
+pub mod two_factor;
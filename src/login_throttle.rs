@@ -0,0 +1,233 @@
+//! src/login_throttle.rs
+
+use std::net::IpAddr;
+
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+
+use crate::client_ip::network_key;
+use crate::configuration::LoginThrottleSettings;
+
+/// What failed login attempts are counted against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttemptKey {
+    Username(String),
+    Ip(IpAddr),
+}
+
+impl AttemptKey {
+    fn scope(&self) -> &'static str {
+        match self {
+            Self::Username(_) => "username",
+            Self::Ip(_) => "ip",
+        }
+    }
+
+    fn key(&self) -> String {
+        match self {
+            Self::Username(username) => username.clone(),
+            Self::Ip(ip) => network_key(*ip),
+        }
+    }
+}
+
+impl std::fmt::Display for AttemptKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.scope(), self.key())
+    }
+}
+
+/// Slows down password guessing: after each failed login attempt the next one
+/// has to wait twice as long as before, and usernames or client IPs with too
+/// many failures are locked out for a while.
+///
+/// Attempts are tracked in the database, so that every entry point and every
+/// application instance share them.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    settings: LoginThrottleSettings,
+}
+
+impl LoginThrottle {
+    pub fn new(settings: LoginThrottleSettings) -> Self {
+        Self { settings }
+    }
+
+    fn max_failed_attempts(&self, key: &AttemptKey) -> i32 {
+        let max = match key {
+            AttemptKey::Username(_) => self.settings.max_failed_attempts_per_username,
+            AttemptKey::Ip(_) => self.settings.max_failed_attempts_per_ip,
+        };
+        max.try_into().unwrap_or(i32::MAX)
+    }
+
+    fn delay_after(&self, failed_attempts: i32) -> Duration {
+        if failed_attempts <= 0 {
+            return Duration::zero();
+        }
+        let exponent = (failed_attempts - 1).min(30) as u32;
+        (self.settings.base_delay() * 2i32.pow(exponent)).min(self.settings.max_delay())
+    }
+
+    /// How long the caller has to wait before trying to log in again, if they have to.
+    #[tracing::instrument(name = "Check login attempts", skip(self, pool))]
+    pub async fn check(
+        &self,
+        pool: &PgPool,
+        keys: &[AttemptKey],
+    ) -> Result<Option<std::time::Duration>, anyhow::Error> {
+        let now = Utc::now();
+        let mut allowed_at = now;
+        for key in keys {
+            let row = sqlx::query!(
+                r#"
+                SELECT failed_attempts, last_failed_at, locked_until
+                FROM login_attempts
+                WHERE scope = $1 AND key = $2
+                "#,
+                key.scope(),
+                key.key()
+            )
+            .fetch_optional(pool)
+            .await
+            .context("Failed to retrieve failed login attempts.")?;
+
+            if let Some(row) = row {
+                let delayed_until = row.last_failed_at + self.delay_after(row.failed_attempts);
+                allowed_at = allowed_at
+                    .max(delayed_until)
+                    .max(row.locked_until.unwrap_or(now));
+            }
+        }
+        Ok((allowed_at > now).then(|| (allowed_at - now).to_std().unwrap_or_default()))
+    }
+
+    /// Counts a failed attempt against every key, locking out the ones with too many.
+    /// Returns the keys that were just locked out.
+    #[tracing::instrument(name = "Record a failed login attempt", skip(self, pool))]
+    pub async fn record_failure(
+        &self,
+        pool: &PgPool,
+        keys: &[AttemptKey],
+    ) -> Result<Vec<AttemptKey>, anyhow::Error> {
+        let now = Utc::now();
+        let mut locked_out = Vec::new();
+        for key in keys {
+            let failed_attempts = sqlx::query_scalar!(
+                r#"
+                INSERT INTO login_attempts (scope, key, failed_attempts, last_failed_at)
+                VALUES ($1, $2, 1, $3)
+                ON CONFLICT (scope, key) DO UPDATE SET
+                    failed_attempts = CASE
+                        WHEN login_attempts.last_failed_at < $4 THEN 1
+                        ELSE login_attempts.failed_attempts + 1
+                    END,
+                    last_failed_at = $3
+                RETURNING failed_attempts
+                "#,
+                key.scope(),
+                key.key(),
+                now,
+                now - self.settings.lockout()
+            )
+            .fetch_one(pool)
+            .await
+            .context("Failed to record a failed login attempt.")?;
+
+            if failed_attempts >= self.max_failed_attempts(key) {
+                self.lock_out(pool, key, now + self.settings.lockout())
+                    .await?;
+                locked_out.push(key.clone());
+            }
+        }
+        Ok(locked_out)
+    }
+
+    async fn lock_out(
+        &self,
+        pool: &PgPool,
+        key: &AttemptKey,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        // The count starts over, so that the lockout is all there is to wait.
+        sqlx::query!(
+            r#"
+            UPDATE login_attempts
+            SET failed_attempts = 0, locked_until = $3
+            WHERE scope = $1 AND key = $2
+            "#,
+            key.scope(),
+            key.key(),
+            locked_until
+        )
+        .execute(pool)
+        .await
+        .context("Failed to lock out after failed login attempts.")?;
+
+        tracing::warn!(
+            target: "audit",
+            scope = key.scope(),
+            key = %key.key(),
+            %locked_until,
+            "Locked out after too many failed login attempts"
+        );
+        Ok(())
+    }
+
+    /// Forgets the failures of a username once its owner logged in.
+    #[tracing::instrument(name = "Record a successful login", skip(self, pool))]
+    pub async fn record_success(&self, pool: &PgPool, username: &str) -> Result<(), anyhow::Error> {
+        unlock(pool, &AttemptKey::Username(username.to_owned())).await?;
+        Ok(())
+    }
+}
+
+/// Lifts the lockout of a username or a client IP and forgets its failures.
+/// Returns whether there was anything to forget.
+pub async fn unlock(pool: &PgPool, key: &AttemptKey) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM login_attempts WHERE scope = $1 AND key = $2",
+        key.scope(),
+        key.key()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to forget failed login attempts.")?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoginThrottle;
+    use crate::configuration::LoginThrottleSettings;
+    use chrono::Duration;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(LoginThrottleSettings {
+            max_failed_attempts_per_username: 10,
+            max_failed_attempts_per_ip: 50,
+            base_delay_milliseconds: 250,
+            max_delay_seconds: 60,
+            lockout_minutes: 15,
+        })
+    }
+
+    #[test]
+    fn there_is_no_delay_without_failures() {
+        assert_eq!(throttle().delay_after(0), Duration::zero());
+    }
+
+    #[test]
+    fn the_delay_doubles_after_each_failure() {
+        assert_eq!(throttle().delay_after(1), Duration::milliseconds(250));
+        assert_eq!(throttle().delay_after(2), Duration::milliseconds(500));
+        assert_eq!(throttle().delay_after(4), Duration::seconds(2));
+    }
+
+    #[test]
+    fn the_delay_is_capped() {
+        assert_eq!(throttle().delay_after(9), Duration::seconds(60));
+        assert_eq!(throttle().delay_after(1000), Duration::seconds(60));
+    }
+}
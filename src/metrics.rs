@@ -0,0 +1,259 @@
+//! src/metrics.rs
+//!
+//! Prometheus metrics, exported on `/metrics`. Each application has its own
+//! registry.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use axum::body::Body;
+use axum::extract::{MatchedPath, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sqlx::PgPool;
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    email_messages: IntCounterVec,
+    email_api_request_duration: HistogramVec,
+    delivery_queue_depth: IntGauge,
+    subscribers: IntGaugeVec,
+    db_pool_connections: IntGaugeVec,
+    db_pool_max_connections: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served."),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "How long HTTP requests took to serve.",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let email_messages = IntCounterVec::new(
+            Opts::new(
+                "email_messages_total",
+                "Messages handed to the email API, by whether it accepted them.",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let email_api_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "email_api_request_duration_seconds",
+                "How long calls to the email API took.",
+            ),
+            &["endpoint", "outcome"],
+        )
+        .unwrap();
+        let delivery_queue_depth = IntGauge::new(
+            "newsletter_delivery_queue_depth",
+            "Deliveries of newsletter issues still pending.",
+        )
+        .unwrap();
+        let subscribers = IntGaugeVec::new(
+            Opts::new("subscribers", "Subscribers, by status."),
+            &["status"],
+        )
+        .unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Connections of the database pool, by state.",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let db_pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "The most connections the database pool opens.",
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_request_duration.clone()),
+            Box::new(email_messages.clone()),
+            Box::new(email_api_request_duration.clone()),
+            Box::new(delivery_queue_depth.clone()),
+            Box::new(subscribers.clone()),
+            Box::new(db_pool_connections.clone()),
+            Box::new(db_pool_max_connections.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("Metrics are only registered once");
+        }
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            email_messages,
+            email_api_request_duration,
+            delivery_queue_depth,
+            subscribers,
+            db_pool_connections,
+            db_pool_max_connections,
+        }
+    }
+
+    fn record_http_request(&self, method: &str, route: &str, status: &str, duration: Duration) {
+        let labels = [method, route, status];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration
+            .with_label_values(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records a call to the email API, made to send `messages` messages of
+    /// which `rejected` were not accepted.
+    pub fn record_email_api_request(
+        &self,
+        endpoint: &str,
+        messages: usize,
+        rejected: usize,
+        duration: Duration,
+    ) {
+        let outcome = if rejected == 0 { "sent" } else { "failed" };
+        self.email_api_request_duration
+            .with_label_values(&[endpoint, outcome])
+            .observe(duration.as_secs_f64());
+        self.email_messages
+            .with_label_values(&["sent"])
+            .inc_by((messages - rejected) as u64);
+        self.email_messages
+            .with_label_values(&["failed"])
+            .inc_by(rejected as u64);
+    }
+
+    /// Refreshes the metrics read from the database: the delivery queue, the
+    /// subscribers and the connection pool.
+    pub async fn refresh(&self, pool: &PgPool) -> Result<(), anyhow::Error> {
+        let idle = pool.num_idle() as i64;
+        self.db_pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        self.db_pool_connections
+            .with_label_values(&["in_use"])
+            .set(pool.size() as i64 - idle);
+        self.db_pool_max_connections
+            .set(pool.options().get_max_connections().into());
+
+        let pending_deliveries = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM deliveries WHERE status = 'pending'"#
+        )
+        .fetch_one(pool)
+        .await
+        .context("Failed to count the pending deliveries.")?;
+        self.delivery_queue_depth.set(pending_deliveries);
+
+        let subscribers = sqlx::query!(
+            r#"SELECT status, COUNT(*) AS "count!" FROM subscriptions GROUP BY status"#
+        )
+        .fetch_all(pool)
+        .await
+        .context("Failed to count the subscribers.")?;
+        self.subscribers.reset();
+        for row in subscribers {
+            self.subscribers
+                .with_label_values(&[&row.status])
+                .set(row.count);
+        }
+        Ok(())
+    }
+
+    /// The metrics, in the Prometheus text format.
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records the requests served by the routes it wraps, labelled with their
+/// route rather than their path to keep the number of series bounded.
+pub async fn record_http_metrics(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let started_at = Instant::now();
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+
+    let response = next.run(request).await;
+
+    metrics.record_http_request(
+        method.as_str(),
+        &route,
+        response.status().as_str(),
+        started_at.elapsed(),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn http_requests_are_counted_by_route_and_status() {
+        let metrics = Metrics::new();
+
+        metrics.record_http_request("GET", "/t/:token", "303", Duration::from_millis(5));
+        metrics.record_http_request("GET", "/t/:token", "303", Duration::from_millis(5));
+        metrics.record_http_request("GET", "/t/:token", "404", Duration::from_millis(5));
+
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded
+            .contains(r#"http_requests_total{method="GET",route="/t/:token",status="303"} 2"#));
+        assert!(encoded
+            .contains(r#"http_requests_total{method="GET",route="/t/:token",status="404"} 1"#));
+        assert!(encoded.contains(
+            r#"http_request_duration_seconds_count{method="GET",route="/t/:token",status="303"} 2"#
+        ));
+    }
+
+    #[test]
+    fn email_messages_are_counted_by_outcome() {
+        let metrics = Metrics::new();
+
+        metrics.record_email_api_request("email_batch", 3, 1, Duration::from_millis(50));
+        metrics.record_email_api_request("email", 1, 0, Duration::from_millis(50));
+
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains(r#"email_messages_total{outcome="sent"} 3"#));
+        assert!(encoded.contains(r#"email_messages_total{outcome="failed"} 1"#));
+        assert!(encoded.contains(
+            r#"email_api_request_duration_seconds_count{endpoint="email_batch",outcome="failed"} 1"#
+        ));
+        assert!(encoded.contains(
+            r#"email_api_request_duration_seconds_count{endpoint="email",outcome="sent"} 1"#
+        ));
+    }
+}
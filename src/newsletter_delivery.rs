@@ -0,0 +1,242 @@
+//! src/newsletter_delivery.rs
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Utc;
+use futures::{stream, StreamExt, TryStreamExt};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::application_state::ApplicationState;
+use crate::domain::{SenderIdentity, SubscriberEmail};
+use crate::email_client::{Attachments, EmailMessage, SendOutcome};
+use crate::tracking::Tracker;
+
+/// How many times we try to send an issue to the same subscriber.
+pub const MAX_DELIVERY_ATTEMPTS: i32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+pub struct Recipient {
+    pub subscriber_id: Uuid,
+    pub email: SubscriberEmail,
+}
+
+/// A newsletter issue, as it was published.
+pub struct Issue<'a> {
+    pub newsletter_issue_id: Uuid,
+    pub title: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub attachments: &'a Attachments,
+    /// The default sender of the email client if unset.
+    pub sender: Option<&'a SenderIdentity>,
+    pub tracking_enabled: bool,
+}
+
+/// The lifecycle of the delivery of a newsletter issue to a single subscriber.
+pub enum DeliveryStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Create a `pending` delivery for each recipient of a newsletter issue.
+#[tracing::instrument(
+    name = "Enqueue newsletter deliveries",
+    skip(transaction, subscriber_ids)
+)]
+pub async fn enqueue_deliveries(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    subscriber_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO deliveries (
+            newsletter_issue_id,
+            subscriber_id,
+            n_attempts,
+            status,
+            created_at,
+            updated_at
+        )
+        SELECT $1, subscriber_id, 0, $3, $4, $4
+        FROM UNNEST($2::uuid[]) AS subscriber_id
+        "#,
+        newsletter_issue_id,
+        subscriber_ids,
+        DeliveryStatus::Pending.as_str(),
+        Utc::now()
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// Deliver an issue to its recipients through the email provider batch API.
+///
+/// Only the messages that failed with a transient error are sent again,
+/// for up to `MAX_DELIVERY_ATTEMPTS` attempts overall.
+#[tracing::instrument(
+    name = "Deliver a newsletter issue",
+    skip(app_state, issue, recipients),
+    fields(newsletter_issue_id=%issue.newsletter_issue_id)
+)]
+pub async fn deliver_issue(
+    app_state: &ApplicationState,
+    issue: &Issue<'_>,
+    recipients: Vec<Recipient>,
+) -> Result<(), anyhow::Error> {
+    let mut pending = recipients;
+    for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+        let outcomes = send_issue(app_state, issue, &pending).await;
+        let outcomes: Vec<_> = pending.into_iter().zip(outcomes).collect();
+        record_delivery_attempts(&app_state.db_pool, issue.newsletter_issue_id, &outcomes)
+            .await
+            .context("Failed to record newsletter delivery attempts.")?;
+
+        pending = outcomes
+            .into_iter()
+            .filter_map(|(recipient, outcome)| match outcome {
+                Err(e) if e.is_retryable() && attempt < MAX_DELIVERY_ATTEMPTS => Some(recipient),
+                _ => None,
+            })
+            .collect();
+        if pending.is_empty() {
+            break;
+        }
+        tokio::time::sleep(RETRY_BACKOFF * attempt as u32).await;
+    }
+
+    Ok(())
+}
+
+async fn send_issue(
+    app_state: &ApplicationState,
+    issue: &Issue<'_>,
+    recipients: &[Recipient],
+) -> Vec<SendOutcome> {
+    let contents: Vec<(Cow<str>, Cow<str>)> = recipients
+        .iter()
+        .map(|recipient| render_issue(app_state, issue, recipient))
+        .collect();
+    let email_client = &app_state.email_client.0;
+    let messages: Vec<_> = recipients
+        .iter()
+        .zip(&contents)
+        .map(|(recipient, (html_content, text_content))| {
+            let message =
+                EmailMessage::new(&recipient.email, issue.title, html_content, text_content)
+                    .message_stream(email_client.broadcast_stream())
+                    .tag("newsletter")
+                    .metadata("newsletter_issue_id", issue.newsletter_issue_id.to_string())
+                    .metadata("subscriber_id", recipient.subscriber_id.to_string())
+                    .attachments(issue.attachments);
+            match issue.sender {
+                Some(sender) => message.sender(sender),
+                None => message,
+            }
+        })
+        .collect();
+
+    email_client.send_batch(&messages).await
+}
+
+/// The HTML and text content of the issue, as seen by a single recipient.
+fn render_issue<'a>(
+    app_state: &ApplicationState,
+    issue: &Issue<'a>,
+    recipient: &Recipient,
+) -> (Cow<'a, str>, Cow<'a, str>) {
+    if !issue.tracking_enabled {
+        return (issue.html_content.into(), issue.text_content.into());
+    }
+
+    let tracker = Tracker {
+        base_url: &app_state.base_url.0 .0,
+        hmac_secret: &app_state.hmac_secret.0,
+        newsletter_issue_id: issue.newsletter_issue_id,
+        subscriber_id: recipient.subscriber_id,
+    };
+    (
+        tracker.rewrite_html(issue.html_content).into(),
+        tracker.rewrite_text(issue.text_content).into(),
+    )
+}
+
+async fn record_delivery_attempts(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    outcomes: &[(Recipient, SendOutcome)],
+) -> Result<(), sqlx::Error> {
+    // Leave half of the connection pool to the rest of the application.
+    let max_concurrency = (pool.options().get_max_connections() as usize / 2).max(1);
+
+    let records: Vec<_> = outcomes
+        .iter()
+        .map(|(recipient, outcome)| {
+            if let Err(error) = outcome {
+                tracing::warn!(
+                    error.cause_chain = ?error,
+                    "Failed to send newsletter issue to {}", recipient.email
+                );
+            }
+            record_delivery_attempt(pool, newsletter_issue_id, recipient.subscriber_id, outcome)
+        })
+        .collect();
+
+    stream::iter(records)
+        .buffer_unordered(max_concurrency)
+        .try_collect()
+        .await
+}
+
+/// Record the outcome of an attempt to deliver an issue to a subscriber.
+#[tracing::instrument(name = "Record delivery attempt", skip(pool, outcome))]
+pub async fn record_delivery_attempt<E: std::fmt::Display>(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    outcome: &Result<Option<String>, E>,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let (status, provider_message_id, last_error, sent_at) = match outcome {
+        Ok(message_id) => (DeliveryStatus::Sent, message_id.clone(), None, Some(now)),
+        Err(e) => (DeliveryStatus::Failed, None, Some(e.to_string()), None),
+    };
+
+    sqlx::query!(
+        r#"
+        UPDATE deliveries
+        SET
+            n_attempts = n_attempts + 1,
+            status = $3,
+            provider_message_id = $4,
+            last_error = $5,
+            updated_at = $6,
+            sent_at = $7
+        WHERE newsletter_issue_id = $1 AND subscriber_id = $2
+        "#,
+        newsletter_issue_id,
+        subscriber_id,
+        status.as_str(),
+        provider_message_id,
+        last_error,
+        now,
+        sent_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
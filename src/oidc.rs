@@ -0,0 +1,485 @@
+//! src/oidc.rs
+//!
+//! Logging admins in through an OpenID Connect provider, with the
+//! authorization code flow and PKCE.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use axum_extra::extract::cookie::{Cookie, SameSite, SignedCookieJar};
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::configuration::OidcSettings;
+
+const PENDING_LOGIN_COOKIE: &str = "_oidc";
+/// How long users have to log in at the provider and come back.
+const PENDING_LOGIN_LIFETIME_MINUTES: i64 = 10;
+const SCOPES: &str = "openid email";
+/// How long the provider metadata is reused before it is fetched again.
+const METADATA_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(thiserror::Error, Debug)]
+pub enum OidcError {
+    #[error("The login with the identity provider is invalid or has expired.")]
+    InvalidState,
+    #[error("The identity provider refused the login.")]
+    ProviderError(String),
+    #[error("The identity provider sent an invalid ID token.")]
+    InvalidIdToken(#[source] anyhow::Error),
+    #[error("There is no account for this identity.")]
+    UnknownIdentity,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+#[derive(serde::Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+}
+
+pub struct OidcClient {
+    http_client: Client,
+    issuer_url: String,
+    client_id: String,
+    client_secret: Secret<String>,
+    display_name: String,
+    redirect_url: String,
+    metadata: Mutex<Option<(Arc<ProviderMetadata>, Instant)>>,
+}
+
+impl OidcClient {
+    /// Providers send users back to `/login/oidc/callback` under the base URL.
+    pub fn new(settings: OidcSettings, base_url: &str) -> Self {
+        Self {
+            http_client: Client::builder()
+                .timeout(settings.timeout())
+                .build()
+                .unwrap(),
+            issuer_url: settings.issuer_url,
+            client_id: settings.client_id,
+            client_secret: settings.client_secret,
+            display_name: settings.display_name,
+            redirect_url: format!("{}/login/oidc/callback", base_url),
+            metadata: Mutex::new(None),
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    /// The provider metadata, fetched again once it is older than an hour.
+    async fn metadata(&self) -> Result<Arc<ProviderMetadata>, anyhow::Error> {
+        if let Some((metadata, fetched_at)) = &*self.metadata.lock().unwrap() {
+            if fetched_at.elapsed() < METADATA_LIFETIME {
+                return Ok(metadata.clone());
+            }
+        }
+        let metadata = Arc::new(self.discover().await?);
+        *self.metadata.lock().unwrap() = Some((metadata.clone(), Instant::now()));
+        Ok(metadata)
+    }
+
+    #[tracing::instrument(name = "Discover the OpenID Connect provider", skip(self))]
+    async fn discover(&self) -> Result<ProviderMetadata, anyhow::Error> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer_url.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = self
+            .http_client
+            .get(&url)
+            .send()
+            .await
+            .context("Failed to fetch the provider metadata.")?
+            .error_for_status()
+            .context("The provider metadata could not be fetched.")?
+            .json()
+            .await
+            .context("Failed to parse the provider metadata.")?;
+        if metadata.issuer != self.issuer_url {
+            anyhow::bail!(
+                "The provider metadata is for the {} issuer instead of {}.",
+                metadata.issuer,
+                self.issuer_url
+            );
+        }
+        Ok(metadata)
+    }
+
+    /// Starts a login: returns where to send the user, and what to remember
+    /// until they come back.
+    pub async fn start_login(&self) -> Result<(String, PendingLogin), anyhow::Error> {
+        let metadata = self.metadata().await?;
+        let pending = PendingLogin::generate();
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", SCOPES),
+                ("state", &pending.state),
+                ("nonce", &pending.nonce),
+                ("code_challenge", &pending.code_challenge()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .context("Invalid authorization endpoint.")?;
+        Ok((url.into(), pending))
+    }
+
+    /// Exchanges the code users come back with for their identity.
+    #[tracing::instrument(name = "Finish an OpenID Connect login", skip_all)]
+    pub async fn finish_login(
+        &self,
+        code: &str,
+        pending: &PendingLogin,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let metadata = self.metadata().await?;
+        let response = self
+            .http_client
+            .post(&metadata.token_endpoint)
+            .basic_auth(&self.client_id, Some(self.client_secret.expose_secret()))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+                ("code_verifier", &pending.code_verifier),
+            ])
+            .send()
+            .await
+            .context("Failed to call the token endpoint.")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error = match response.json::<TokenErrorResponse>().await {
+                Ok(body) => body.error,
+                Err(_) => status.to_string(),
+            };
+            return Err(OidcError::ProviderError(error));
+        }
+        let TokenResponse { id_token } = response
+            .json()
+            .await
+            .context("Failed to parse the token response.")?;
+
+        let claims = IdTokenClaims::parse(&id_token).map_err(OidcError::InvalidIdToken)?;
+        claims
+            .validate(
+                &metadata.issuer,
+                &self.client_id,
+                &pending.nonce,
+                Utc::now(),
+            )
+            .map_err(OidcError::InvalidIdToken)?;
+        Ok(claims)
+    }
+}
+
+/// A login waiting for users to come back from the provider, kept in a signed cookie.
+pub struct PendingLogin {
+    pub state: String,
+    nonce: String,
+    code_verifier: String,
+    started_at: DateTime<Utc>,
+}
+
+impl PendingLogin {
+    fn generate() -> Self {
+        let random = |length| {
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .map(char::from)
+                .take(length)
+                .collect::<String>()
+        };
+        Self {
+            state: random(32),
+            nonce: random(32),
+            code_verifier: random(64),
+            started_at: Utc::now(),
+        }
+    }
+
+    fn code_challenge(&self) -> String {
+        code_challenge(&self.code_verifier)
+    }
+
+    pub fn cookie(&self) -> Cookie<'static> {
+        let value = format!(
+            "{}:{}:{}:{}",
+            self.started_at.timestamp(),
+            self.state,
+            self.nonce,
+            self.code_verifier
+        );
+        // Users come back from the provider's site: a strict cookie would not be sent.
+        Cookie::build((PENDING_LOGIN_COOKIE, value))
+            .path("/login/oidc")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .build()
+    }
+
+    pub fn removal_cookie() -> Cookie<'static> {
+        Cookie::build((PENDING_LOGIN_COOKIE, ""))
+            .path("/login/oidc")
+            .build()
+    }
+
+    pub fn from_jar(jar: &SignedCookieJar) -> Option<Self> {
+        let cookie = jar.get(PENDING_LOGIN_COOKIE)?;
+        let mut segments = cookie.value().splitn(4, ':');
+        let started_at = Utc
+            .timestamp_opt(segments.next()?.parse().ok()?, 0)
+            .single()?;
+        let state = segments.next()?.to_owned();
+        let nonce = segments.next()?.to_owned();
+        let code_verifier = segments.next()?.to_owned();
+
+        let expires_at = started_at + chrono::Duration::minutes(PENDING_LOGIN_LIFETIME_MINUTES);
+        (expires_at > Utc::now()).then_some(Self {
+            state,
+            nonce,
+            code_verifier,
+            started_at,
+        })
+    }
+}
+
+/// The PKCE challenge of a verifier, with the S256 method.
+fn code_challenge(code_verifier: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier))
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Self::One(audience) => audience == client_id,
+            Self::Many(audiences) => audiences.iter().any(|a| a == client_id),
+        }
+    }
+}
+
+/// What ID tokens tell about users.
+#[derive(Debug, serde::Deserialize)]
+pub struct IdTokenClaims {
+    iss: String,
+    pub sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+impl IdTokenClaims {
+    /// Reads the claims of an ID token.
+    ///
+    /// Its signature is not checked: the token comes straight from the token
+    /// endpoint, over TLS, in exchange for a code only this application can
+    /// redeem, which OpenID Connect Core (3.1.3.7) deems enough.
+    fn parse(id_token: &str) -> Result<Self, anyhow::Error> {
+        let payload = id_token
+            .split('.')
+            .nth(1)
+            .context("The ID token is not a JWT.")?;
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .context("Failed to base64-decode the ID token claims.")?;
+        serde_json::from_slice(&payload).context("Failed to parse the ID token claims.")
+    }
+
+    fn validate(
+        &self,
+        issuer: &str,
+        client_id: &str,
+        nonce: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        if self.iss != issuer {
+            anyhow::bail!(
+                "The ID token was issued by {} instead of {}.",
+                self.iss,
+                issuer
+            );
+        }
+        if !self.aud.contains(client_id) {
+            anyhow::bail!("The ID token is not meant for this application.");
+        }
+        if self.exp <= now.timestamp() {
+            anyhow::bail!("The ID token has expired.");
+        }
+        if self.nonce.as_deref() != Some(nonce) {
+            anyhow::bail!("The ID token nonce does not match the login.");
+        }
+        Ok(())
+    }
+}
+
+/// Finds the user an identity belongs to. Identities are linked to users the
+/// first time they log in, through their verified email address.
+#[tracing::instrument(
+    name = "Find the user of an OpenID Connect identity",
+    skip(pool, claims)
+)]
+pub async fn find_user(pool: &PgPool, claims: &IdTokenClaims) -> Result<Uuid, OidcError> {
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM users WHERE oidc_subject = $1",
+        claims.sub
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the user of an OpenID Connect identity.")?;
+    if let Some(user_id) = user_id {
+        return Ok(user_id);
+    }
+
+    let Some(email) = claims.email.as_deref().filter(|_| claims.email_verified) else {
+        return Err(OidcError::UnknownIdentity);
+    };
+    let user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE users
+        SET oidc_subject = $1
+        WHERE lower(email) = lower($2) AND oidc_subject IS NULL
+        RETURNING user_id
+        "#,
+        claims.sub,
+        email
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to link an OpenID Connect identity.")?
+    .ok_or(OidcError::UnknownIdentity)?;
+
+    tracing::info!(
+        target: "audit",
+        %user_id,
+        subject = claims.sub,
+        "Linked an OpenID Connect identity"
+    );
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{code_challenge, Audience, IdTokenClaims};
+    use base64::Engine;
+    use chrono::{Duration, Utc};
+    use claims::{assert_err, assert_ok};
+
+    fn claims() -> IdTokenClaims {
+        IdTokenClaims {
+            iss: "https://idp.example.com".into(),
+            sub: "248289761001".into(),
+            aud: Audience::One("blog".into()),
+            exp: (Utc::now() + Duration::minutes(5)).timestamp(),
+            nonce: Some("a-nonce".into()),
+            email: Some("ursula@example.com".into()),
+            email_verified: true,
+        }
+    }
+
+    fn validate(claims: &IdTokenClaims) -> Result<(), anyhow::Error> {
+        claims.validate("https://idp.example.com", "blog", "a-nonce", Utc::now())
+    }
+
+    #[test]
+    fn the_code_challenge_matches_the_rfc_example() {
+        // RFC 7636, appendix B.
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn claims_are_read_from_the_jwt_payload() {
+        let payload = serde_json::json!({
+            "iss": "https://idp.example.com",
+            "sub": "248289761001",
+            "aud": ["blog", "another-client"],
+            "exp": 1700000000,
+            "nonce": "a-nonce",
+        });
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(&payload).unwrap());
+        let id_token = format!("eyJhbGciOiJSUzI1NiJ9.{}.c2lnbmF0dXJl", payload);
+
+        let claims = assert_ok!(IdTokenClaims::parse(&id_token));
+
+        assert_eq!(claims.sub, "248289761001");
+        assert!(claims.aud.contains("blog"));
+        assert_eq!(claims.email, None);
+        assert!(!claims.email_verified);
+    }
+
+    #[test]
+    fn tokens_that_are_not_jwts_are_rejected() {
+        assert_err!(IdTokenClaims::parse("not-a-jwt"));
+    }
+
+    #[test]
+    fn valid_claims_are_accepted() {
+        assert_ok!(validate(&claims()));
+    }
+
+    #[test]
+    fn claims_from_another_issuer_are_rejected() {
+        let mut claims = claims();
+        claims.iss = "https://evil.example.com".into();
+        assert_err!(validate(&claims));
+    }
+
+    #[test]
+    fn claims_for_another_client_are_rejected() {
+        let mut claims = claims();
+        claims.aud = Audience::Many(vec!["another-client".into()]);
+        assert_err!(validate(&claims));
+    }
+
+    #[test]
+    fn expired_claims_are_rejected() {
+        let mut claims = claims();
+        claims.exp = (Utc::now() - Duration::seconds(1)).timestamp();
+        assert_err!(validate(&claims));
+    }
+
+    #[test]
+    fn claims_without_the_nonce_of_the_login_are_rejected() {
+        let mut claims = claims();
+        claims.nonce = Some("another-nonce".into());
+        assert_err!(validate(&claims));
+        claims.nonce = None;
+        assert_err!(validate(&claims));
+    }
+}
@@ -0,0 +1,265 @@
+//! src/password_reset.rs
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::api_tokens::hash_token;
+use crate::authentication::compute_password_hash;
+use crate::background_tasks::BackgroundTasks;
+use crate::configuration::PasswordHashingSettings;
+use crate::domain::{NewPassword, SubscriberEmail};
+use crate::email_client::{EmailClient, EmailMessage};
+use crate::login_throttle::{unlock, AttemptKey};
+use crate::telemetry::spawn_blocking_with_tracing;
+
+/// How long a password reset link can be used for.
+const RESET_LINK_LIFETIME_MINUTES: i64 = 60;
+const TOKEN_LENGTH: usize = 32;
+/// How many reset links an address can be sent within the lifetime of a link.
+const MAX_RESET_LINKS: i64 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PasswordResetError {
+    #[error("The password reset link is invalid or has expired.")]
+    InvalidToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// Whether a string could be a reset token at all, before it is looked up
+/// or written back into a page.
+pub fn is_well_formed_token(token: &str) -> bool {
+    token.len() == TOKEN_LENGTH && token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Emails a password reset link to the user with this address, if there is one
+/// and they were not sent too many links lately. Which of these is the case is
+/// not revealed to the caller: the email is sent in the background.
+#[tracing::instrument(
+    name = "Request a password reset",
+    skip(pool, email_client, background_tasks, base_url)
+)]
+pub async fn request_reset(
+    pool: &PgPool,
+    email_client: Arc<EmailClient>,
+    background_tasks: &BackgroundTasks,
+    base_url: &str,
+    email: SubscriberEmail,
+) -> Result<(), anyhow::Error> {
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    // Locked so that concurrent requests for the same user are counted one
+    // after the other.
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM users WHERE lower(email) = lower($1) FOR UPDATE",
+        email.as_ref()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to retrieve the user with the email address.")?;
+    let Some(user_id) = user_id else {
+        tracing::info!("No user has this email address");
+        return Ok(());
+    };
+    let now = Utc::now();
+    let recent_links = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!" FROM password_reset_tokens
+        WHERE user_id = $1 AND created_at > $2
+        "#,
+        user_id,
+        now - Duration::minutes(RESET_LINK_LIFETIME_MINUTES)
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context("Failed to count the recent password reset tokens.")?;
+    if recent_links >= MAX_RESET_LINKS {
+        tracing::info!("The user was sent too many links lately");
+        return Ok(());
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        hash_token(&token),
+        user_id,
+        now,
+        now + Duration::minutes(RESET_LINK_LIFETIME_MINUTES)
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store the password reset token.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the password reset token.")?;
+
+    let reset_link = format!("{}/login/reset-password?token={}", base_url, token);
+    background_tasks.spawn(
+        async move {
+            match send_reset_email(&email_client, &email, &reset_link).await {
+                Ok(()) => {
+                    tracing::info!(target: "audit", %user_id, "Sent a password reset link")
+                }
+                Err(e) => tracing::error!(
+                    error.cause_chain = ?e,
+                    "Failed to send a password reset link"
+                ),
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+    Ok(())
+}
+
+async fn send_reset_email(
+    email_client: &EmailClient,
+    email: &SubscriberEmail,
+    reset_link: &str,
+) -> Result<(), reqwest::Error> {
+    let plain_body = format!(
+        "Someone asked to reset the password of your account.\n\
+        Visit {} within {} minutes to choose a new one.\n\
+        If it was not you, you can ignore this email.",
+        reset_link, RESET_LINK_LIFETIME_MINUTES
+    );
+    let html_body = format!(
+        "Someone asked to reset the password of your account.<br />\
+        Click <a href=\"{}\">here</a> within {} minutes to choose a new one.<br />\
+        If it was not you, you can ignore this email.",
+        reset_link, RESET_LINK_LIFETIME_MINUTES
+    );
+
+    let message = EmailMessage::new(email, "Reset your password", &html_body, &plain_body)
+        .message_stream(email_client.transactional_stream())
+        .tag("password-reset");
+    email_client.send(&message).await?;
+    Ok(())
+}
+
+/// Sets a new password with a reset token, which cannot be used again.
+/// Logins in progress are invalidated, and the lockout of the user is lifted.
+#[tracing::instrument(name = "Reset a password", skip(pool, token, password, hashing))]
+pub async fn reset_password(
+    pool: &PgPool,
+    token: &str,
+    password: NewPassword,
+    hashing: &PasswordHashingSettings,
+) -> Result<Uuid, PasswordResetError> {
+    let token_hash = hash_token(token);
+    // Checked before hashing, so that invalid tokens do not cost a hash.
+    let valid = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM password_reset_tokens
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        ) AS "valid!"
+        "#,
+        token_hash
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to retrieve the password reset token.")?;
+    if !valid {
+        return Err(PasswordResetError::InvalidToken);
+    }
+
+    let hashing = *hashing;
+    let password_hash = spawn_blocking_with_tracing(move || {
+        compute_password_hash(password.into_secret(), &hashing)
+    })
+    .await
+    .context("Failed to spawn blocking task.")??;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE password_reset_tokens
+        SET used_at = now()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        RETURNING user_id
+        "#,
+        token_hash
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to use the password reset token.")?
+    .ok_or(PasswordResetError::InvalidToken)?;
+    let username = sqlx::query_scalar!(
+        r#"
+        UPDATE users
+        SET password_hash = $1, password_changed_at = now()
+        WHERE user_id = $2
+        RETURNING username
+        "#,
+        password_hash.expose_secret(),
+        user_id
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context("Failed to update the password.")?;
+    // Other links sent before are of no use anymore.
+    sqlx::query!(
+        "UPDATE password_reset_tokens SET used_at = now() WHERE user_id = $1 AND used_at IS NULL",
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to invalidate the other password reset tokens.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the password reset.")?;
+
+    unlock(pool, &AttemptKey::Username(username)).await?;
+    tracing::info!(target: "audit", %user_id, "Reset a password");
+    Ok(user_id)
+}
+
+/// Whether the password of a user changed after a point in time, which makes
+/// logins started before it invalid.
+#[tracing::instrument(name = "Check for a password change", skip(pool))]
+pub async fn password_changed_since(
+    pool: &PgPool,
+    user_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<bool, anyhow::Error> {
+    let changed_at = sqlx::query_scalar!(
+        "SELECT password_changed_at FROM users WHERE user_id = $1",
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to retrieve when the password changed.")?;
+    Ok(changed_at.is_some_and(|changed_at| changed_at > since))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_well_formed_token;
+
+    #[test]
+    fn tokens_are_32_alphanumeric_characters() {
+        assert!(is_well_formed_token(&"a1".repeat(16)));
+        assert!(!is_well_formed_token(&"a".repeat(31)));
+        assert!(!is_well_formed_token(&format!("{}\"<", "a".repeat(30))));
+    }
+}
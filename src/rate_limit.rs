@@ -0,0 +1,78 @@
+//! src/rate_limit.rs
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counts hits per key over fixed windows of time, in memory.
+///
+/// Limits are therefore enforced per application instance, and start over
+/// when the application restarts.
+pub struct RateLimiter {
+    max_hits: u32,
+    window: Duration,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+struct Window {
+    started_at: Instant,
+    hits: u32,
+}
+
+impl RateLimiter {
+    pub fn new(max_hits: u32, window: Duration) -> Self {
+        Self {
+            max_hits,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a hit for `key`, returning `false` if it goes over the limit.
+    pub fn check(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // Forget the windows that are over, so that the map does not grow forever.
+        windows.retain(|_, w| now.duration_since(w.started_at) < self.window);
+
+        let window = windows.entry(key.to_owned()).or_insert(Window {
+            started_at: now,
+            hits: 0,
+        });
+        if window.hits >= self.max_hits {
+            return false;
+        }
+        window.hits += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::Duration;
+
+    #[test]
+    fn hits_over_the_limit_are_rejected() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.check("127.0.0.1"));
+        assert!(limiter.check("127.0.0.1"));
+        assert!(!limiter.check("127.0.0.1"));
+    }
+
+    #[test]
+    fn keys_are_limited_independently() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        assert!(limiter.check("127.0.0.1"));
+        assert!(limiter.check("10.0.0.1"));
+        assert!(!limiter.check("127.0.0.1"));
+    }
+
+    #[test]
+    fn the_limit_is_reset_once_the_window_is_over() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(10));
+        assert!(limiter.check("127.0.0.1"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.check("127.0.0.1"));
+    }
+}
@@ -0,0 +1,114 @@
+//! src/routes/admin/api_tokens.rs
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::AdminError;
+use crate::api_tokens::{self, ApiTokenScope, ApiTokenSummary};
+use crate::auth_events::{self, AuthEvent, AuthEventKind, RequestContext};
+use crate::authorization::{require, Authorized};
+
+#[derive(serde::Deserialize)]
+pub struct NewApiToken {
+    /// What the token is for, e.g. the name of the CI job using it.
+    name: String,
+    scopes: Vec<ApiTokenScope>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CreatedApiToken {
+    token_id: Uuid,
+    token: String,
+    scopes: Vec<ApiTokenScope>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ApiTokens {
+    tokens: Vec<ApiTokenSummary>,
+}
+
+#[tracing::instrument(name = "List API tokens", skip(pool, caller))]
+pub async fn list_api_tokens(
+    caller: Authorized<require::ManageOwnAccount>,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    let user_id = caller.user_id;
+
+    let tokens = api_tokens::list_tokens(&pool, user_id).await?;
+
+    Ok(Json(ApiTokens { tokens }).into_response())
+}
+
+/// Creates an API token for the caller. The token is only ever shown in this
+/// response. Since tokens act without a second factor, creating one takes
+/// a two-factor code.
+#[tracing::instrument(name = "Create an API token", skip(pool, caller, request, body))]
+pub async fn create_api_token(
+    caller: Authorized<require::CreateApiTokens>,
+    request: RequestContext,
+    State(pool): State<PgPool>,
+    Json(body): Json<NewApiToken>,
+) -> Result<Response, AdminError> {
+    let user_id = caller.user_id;
+
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(AdminError::InvalidInput("API tokens need a name.".into()));
+    }
+    if body.scopes.is_empty() {
+        return Err(AdminError::InvalidInput(
+            "API tokens need at least one scope.".into(),
+        ));
+    }
+
+    let (token_id, token) = api_tokens::create_token(&pool, user_id, name, &body.scopes).await?;
+    let event = AuthEvent {
+        kind: AuthEventKind::ApiTokenCreated,
+        user_id: Some(user_id),
+        username: None,
+        request: Some(&request),
+        detail: Some(format!("{} ({})", token_id, name)),
+    };
+    auth_events::record(&pool, event).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedApiToken {
+            token_id,
+            token: token.expose_secret().clone(),
+            scopes: body.scopes,
+        }),
+    )
+        .into_response())
+}
+
+#[tracing::instrument(name = "Revoke an API token", skip(pool, caller, request))]
+pub async fn revoke_api_token(
+    Path(token_id): Path<Uuid>,
+    caller: Authorized<require::ManageOwnAccount>,
+    request: RequestContext,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    let user_id = caller.user_id;
+
+    if !api_tokens::revoke_token(&pool, user_id, token_id).await? {
+        return Err(AdminError::NotFound("There is no such API token.".into()));
+    }
+    let event = AuthEvent {
+        kind: AuthEventKind::ApiTokenRevoked,
+        user_id: Some(user_id),
+        username: None,
+        request: Some(&request),
+        detail: Some(token_id.to_string()),
+    };
+    auth_events::record(&pool, event).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
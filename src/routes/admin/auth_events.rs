@@ -0,0 +1,32 @@
+//! src/routes/admin/auth_events.rs
+
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::PgPool;
+
+use super::AdminError;
+use crate::auth_events::{self, AuthEventFilter, RecordedAuthEvent};
+use crate::authorization::{require, Authorized};
+
+#[derive(serde::Serialize)]
+pub struct AuthEvents {
+    events: Vec<RecordedAuthEvent>,
+}
+
+/// Lists logins, lockouts, password changes and API token changes, most
+/// recent first. They can be filtered with the `kind`, `user_id`, `username`,
+/// `ip`, `since` and `until` query parameters, and `limit` caps how many are
+/// listed.
+#[tracing::instrument(name = "List authentication events", skip(pool))]
+pub async fn list_auth_events(
+    _: Authorized<require::ViewAuthEvents>,
+    Query(filter): Query<AuthEventFilter>,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    let events = auth_events::list_events(&pool, &filter).await?;
+
+    Ok(Json(AuthEvents { events }).into_response())
+}
@@ -0,0 +1,104 @@
+//! src/routes/admin/invitations.rs
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use super::AdminError;
+use crate::application_state::ApplicationState;
+use crate::authorization::{require, Authorized, Role};
+use crate::domain::SubscriberEmail;
+use crate::invitations::{self, InvitationError, InvitationMailer, PendingInvitation};
+
+#[derive(serde::Deserialize)]
+pub struct NewInvitation {
+    email: String,
+    role: Role,
+}
+
+#[derive(serde::Serialize)]
+pub struct CreatedInvitation {
+    invitation_id: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Invitations {
+    invitations: Vec<PendingInvitation>,
+}
+
+impl From<InvitationError> for AdminError {
+    fn from(e: InvitationError) -> Self {
+        match e {
+            InvitationError::EmailTaken | InvitationError::UsernameTaken => {
+                AdminError::Conflict(e.to_string())
+            }
+            InvitationError::InvalidInvitation => AdminError::NotFound(e.to_string()),
+            InvitationError::UnexpectedError(e) => AdminError::UnexpectedError(e),
+        }
+    }
+}
+
+/// Emails an invitation to become an admin. The user is only created once
+/// the invitation is accepted.
+#[tracing::instrument(name = "Invite a user", skip(caller, app_state, body))]
+pub async fn invite_user(
+    caller: Authorized<require::ManageUsers>,
+    State(app_state): State<ApplicationState>,
+    Json(body): Json<NewInvitation>,
+) -> Result<Response, AdminError> {
+    let email = SubscriberEmail::parse(body.email).map_err(AdminError::InvalidInput)?;
+    let mailer = InvitationMailer {
+        email_client: &app_state.email_client.0,
+        base_url: &app_state.base_url.0 .0,
+        hmac_secret: &app_state.hmac_secret.0,
+    };
+
+    let token = invitations::invite(
+        &app_state.db_pool,
+        &mailer,
+        &email,
+        body.role,
+        caller.user_id,
+    )
+    .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedInvitation {
+            invitation_id: token.invitation_id,
+            expires_at: token.expires_at,
+        }),
+    )
+        .into_response())
+}
+
+#[tracing::instrument(name = "List pending invitations", skip(app_state))]
+pub async fn list_invitations(
+    _: Authorized<require::ManageUsers>,
+    State(app_state): State<ApplicationState>,
+) -> Result<Response, AdminError> {
+    let invitations = invitations::list_pending_invitations(&app_state.db_pool).await?;
+
+    Ok(Json(Invitations { invitations }).into_response())
+}
+
+#[tracing::instrument(name = "Revoke an invitation", skip(app_state))]
+pub async fn revoke_invitation(
+    Path(invitation_id): Path<Uuid>,
+    _: Authorized<require::ManageUsers>,
+    State(app_state): State<ApplicationState>,
+) -> Result<Response, AdminError> {
+    if !invitations::revoke(&app_state.db_pool, invitation_id).await? {
+        return Err(AdminError::NotFound(
+            "There is no such pending invitation.".into(),
+        ));
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
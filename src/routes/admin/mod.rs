@@ -0,0 +1,57 @@
+//! src/routes/admin/mod.rs
+
+mod api_tokens;
+mod auth_events;
+mod invitations;
+mod newsletter_deliveries;
+mod newsletter_stats;
+mod subscribers;
+mod two_factor;
+mod users;
+
+use axum::{http::StatusCode, response::IntoResponse};
+
+use crate::routes::error_chain_fmt;
+
+pub use api_tokens::{create_api_token, list_api_tokens, revoke_api_token};
+pub use auth_events::list_auth_events;
+pub use invitations::{invite_user, list_invitations, revoke_invitation};
+pub use newsletter_deliveries::{failed_newsletter_issue_deliveries, newsletter_issue_deliveries};
+pub use newsletter_stats::newsletter_issue_stats;
+pub use subscribers::list_subscribers;
+pub use two_factor::{confirm_two_factor_enrollment, start_two_factor_enrollment};
+pub use users::set_user_role;
+
+#[derive(thiserror::Error)]
+pub enum AdminError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    InvalidInput(String),
+}
+
+impl std::fmt::Debug for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            AdminError::UnexpectedError(error) => {
+                tracing::error!("Unexpected error caused by {}", error);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            AdminError::NotFound(message) => (StatusCode::NOT_FOUND, message).into_response(),
+            AdminError::Conflict(message) => (StatusCode::CONFLICT, message).into_response(),
+            AdminError::InvalidInput(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! src/routes/admin/newsletter_deliveries.rs
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::AdminError;
+use crate::authorization::{require, Authorized};
+
+#[derive(serde::Serialize)]
+pub struct DeliveryProgress {
+    newsletter_issue_id: Uuid,
+    title: String,
+    total: i64,
+    pending: i64,
+    sent: i64,
+    failed: i64,
+}
+
+#[derive(serde::Serialize)]
+pub struct FailedDelivery {
+    subscriber_id: Uuid,
+    email: String,
+    n_attempts: i32,
+    last_error: Option<String>,
+    updated_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get newsletter issue delivery progress", skip(pool))]
+pub async fn newsletter_issue_deliveries(
+    Path(newsletter_issue_id): Path<Uuid>,
+    _: Authorized<require::ViewNewsletterStats>,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    let title = get_issue_title(&pool, newsletter_issue_id).await?;
+    let counts = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) AS "total!",
+            COUNT(*) FILTER (WHERE status = 'pending') AS "pending!",
+            COUNT(*) FILTER (WHERE status = 'sent') AS "sent!",
+            COUNT(*) FILTER (WHERE status = 'failed') AS "failed!"
+        FROM deliveries
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_one(&pool)
+    .await
+    .context("Failed to count newsletter deliveries.")?;
+
+    Ok(Json(DeliveryProgress {
+        newsletter_issue_id,
+        title,
+        total: counts.total,
+        pending: counts.pending,
+        sent: counts.sent,
+        failed: counts.failed,
+    })
+    .into_response())
+}
+
+#[tracing::instrument(name = "Get failed newsletter issue deliveries", skip(pool))]
+pub async fn failed_newsletter_issue_deliveries(
+    Path(newsletter_issue_id): Path<Uuid>,
+    _: Authorized<require::ViewNewsletterStats>,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    get_issue_title(&pool, newsletter_issue_id).await?;
+    let failures: Vec<_> = sqlx::query!(
+        r#"
+        SELECT d.subscriber_id, s.email, d.n_attempts, d.last_error, d.updated_at
+        FROM deliveries d
+        JOIN subscriptions s ON s.id = d.subscriber_id
+        WHERE d.newsletter_issue_id = $1 AND d.status = 'failed'
+        ORDER BY d.updated_at
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_all(&pool)
+    .await
+    .context("Failed to retrieve failed newsletter deliveries.")?
+    .into_iter()
+    .map(|r| FailedDelivery {
+        subscriber_id: r.subscriber_id,
+        email: r.email,
+        n_attempts: r.n_attempts,
+        last_error: r.last_error,
+        updated_at: r.updated_at,
+    })
+    .collect();
+
+    Ok(Json(failures).into_response())
+}
+
+async fn get_issue_title(pool: &PgPool, newsletter_issue_id: Uuid) -> Result<String, AdminError> {
+    let issue = sqlx::query!(
+        r#"
+        SELECT title
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the newsletter issue.")?
+    .ok_or_else(|| AdminError::NotFound("There is no such newsletter issue.".into()))?;
+
+    Ok(issue.title)
+}
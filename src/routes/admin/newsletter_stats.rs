@@ -0,0 +1,104 @@
+//! src/routes/admin/newsletter_stats.rs
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::AdminError;
+use crate::authorization::{require, Authorized};
+
+#[derive(serde::Serialize)]
+pub struct IssueStats {
+    newsletter_issue_id: Uuid,
+    title: String,
+    tracking_enabled: bool,
+    opens: i64,
+    unique_opens: i64,
+    clicks: i64,
+    unique_clicks: i64,
+    links: Vec<LinkStats>,
+}
+
+#[derive(serde::Serialize)]
+pub struct LinkStats {
+    url: String,
+    clicks: i64,
+    unique_clicks: i64,
+}
+
+#[tracing::instrument(name = "Get newsletter issue stats", skip(pool))]
+pub async fn newsletter_issue_stats(
+    Path(newsletter_issue_id): Path<Uuid>,
+    _: Authorized<require::ViewNewsletterStats>,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    let issue = sqlx::query!(
+        r#"
+        SELECT title, tracking_enabled
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .context("Failed to retrieve the newsletter issue.")?
+    .ok_or_else(|| AdminError::NotFound("There is no such newsletter issue.".into()))?;
+
+    let totals = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE kind = 'open') AS "opens!",
+            COUNT(DISTINCT subscriber_id) FILTER (WHERE kind = 'open') AS "unique_opens!",
+            COUNT(*) FILTER (WHERE kind = 'click') AS "clicks!",
+            COUNT(DISTINCT subscriber_id) FILTER (WHERE kind = 'click') AS "unique_clicks!"
+        FROM newsletter_tracking_events
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_one(&pool)
+    .await
+    .context("Failed to aggregate tracking events.")?;
+
+    let links = sqlx::query!(
+        r#"
+        SELECT
+            url AS "url!",
+            COUNT(*) AS "clicks!",
+            COUNT(DISTINCT subscriber_id) AS "unique_clicks!"
+        FROM newsletter_tracking_events
+        WHERE newsletter_issue_id = $1 AND kind = 'click'
+        GROUP BY url
+        ORDER BY 2 DESC, url
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_all(&pool)
+    .await
+    .context("Failed to aggregate link clicks.")?
+    .into_iter()
+    .map(|r| LinkStats {
+        url: r.url,
+        clicks: r.clicks,
+        unique_clicks: r.unique_clicks,
+    })
+    .collect();
+
+    Ok(Json(IssueStats {
+        newsletter_issue_id,
+        title: issue.title,
+        tracking_enabled: issue.tracking_enabled,
+        opens: totals.opens,
+        unique_opens: totals.unique_opens,
+        clicks: totals.clicks,
+        unique_clicks: totals.unique_clicks,
+        links,
+    })
+    .into_response())
+}
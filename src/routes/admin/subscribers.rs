@@ -0,0 +1,48 @@
+//! src/routes/admin/subscribers.rs
+
+use anyhow::Context;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use super::AdminError;
+use crate::authorization::{require, Authorized};
+
+#[derive(serde::Serialize)]
+pub struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Subscriber {
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+}
+
+/// Lists every subscriber, whatever their status. Callers authenticate with
+/// their password, or with an API token with the `read_subscribers` scope.
+#[tracing::instrument(name = "List subscribers", skip(pool))]
+pub async fn list_subscribers(
+    _: Authorized<require::ReadSubscribers>,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    let subscribers = sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT email, name, status, subscribed_at
+        FROM subscriptions
+        ORDER BY subscribed_at
+        "#
+    )
+    .fetch_all(&pool)
+    .await
+    .context("Failed to retrieve the subscribers.")?;
+
+    Ok(Json(Subscribers { subscribers }).into_response())
+}
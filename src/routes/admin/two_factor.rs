@@ -0,0 +1,82 @@
+//! src/routes/admin/two_factor.rs
+
+use anyhow::Context;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::PgPool;
+
+use super::AdminError;
+use crate::authorization::{require, Authorized};
+use crate::two_factor::{self, TwoFactorError};
+
+#[derive(serde::Serialize)]
+pub struct TwoFactorEnrollment {
+    /// For authenticator apps that cannot scan the provisioning URI.
+    secret: String,
+    provisioning_uri: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EnrollmentConfirmation {
+    code: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+impl From<TwoFactorError> for AdminError {
+    fn from(e: TwoFactorError) -> Self {
+        match e {
+            TwoFactorError::AlreadyEnabled | TwoFactorError::NotEnrolled => {
+                AdminError::Conflict(e.to_string())
+            }
+            TwoFactorError::InvalidCode => AdminError::InvalidInput(e.to_string()),
+            TwoFactorError::UnexpectedError(e) => AdminError::UnexpectedError(e),
+        }
+    }
+}
+
+/// Starts enrolling the caller in two-factor authentication: their authenticator
+/// app is set up from the returned provisioning URI, usually shown as a QR code.
+#[tracing::instrument(name = "Start two-factor enrollment", skip(pool, caller))]
+pub async fn start_two_factor_enrollment(
+    caller: Authorized<require::ManageOwnAccount>,
+    State(pool): State<PgPool>,
+) -> Result<Response, AdminError> {
+    let user_id = caller.user_id;
+
+    let username = sqlx::query_scalar!("SELECT username FROM users WHERE user_id = $1", user_id)
+        .fetch_one(&pool)
+        .await
+        .context("Failed to retrieve the username.")?;
+    let secret = two_factor::start_enrollment(&pool, user_id).await?;
+
+    Ok(Json(TwoFactorEnrollment {
+        secret: secret.to_base32(),
+        provisioning_uri: secret.provisioning_uri(&username),
+    })
+    .into_response())
+}
+
+/// Enables two-factor authentication with a first code from the authenticator
+/// app. The recovery codes are only ever shown in this response.
+#[tracing::instrument(
+    name = "Confirm two-factor enrollment",
+    skip(pool, caller, confirmation)
+)]
+pub async fn confirm_two_factor_enrollment(
+    caller: Authorized<require::ManageOwnAccount>,
+    State(pool): State<PgPool>,
+    Json(confirmation): Json<EnrollmentConfirmation>,
+) -> Result<Response, AdminError> {
+    let user_id = caller.user_id;
+
+    let recovery_codes = two_factor::confirm_enrollment(&pool, user_id, &confirmation.code).await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }).into_response())
+}
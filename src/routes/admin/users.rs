@@ -0,0 +1,46 @@
+//! src/routes/admin/users.rs
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::PgPool;
+
+use super::AdminError;
+use crate::authorization::{require, set_role, Authorized, Role, SetRoleError};
+
+#[derive(serde::Deserialize)]
+pub struct RoleChange {
+    role: Role,
+}
+
+impl From<SetRoleError> for AdminError {
+    fn from(e: SetRoleError) -> Self {
+        match e {
+            SetRoleError::UnknownUser => AdminError::NotFound(e.to_string()),
+            SetRoleError::LastOwner => AdminError::Conflict(e.to_string()),
+            SetRoleError::UnexpectedError(e) => AdminError::UnexpectedError(e),
+        }
+    }
+}
+
+#[tracing::instrument(name = "Change the role of a user", skip(pool, caller, change))]
+pub async fn set_user_role(
+    Path(username): Path<String>,
+    caller: Authorized<require::ManageUsers>,
+    State(pool): State<PgPool>,
+    Json(change): Json<RoleChange>,
+) -> Result<Response, AdminError> {
+    tracing::info!(
+        target: "audit",
+        changed_by = %caller.user_id,
+        username,
+        role = %change.role,
+        "Role change requested"
+    );
+    set_role(&pool, &username, change.role).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
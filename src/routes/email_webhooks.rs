@@ -0,0 +1,124 @@
+//! src/routes/email_webhooks.rs
+
+use anyhow::Context;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use hyper::StatusCode;
+use secrecy::ExposeSecret;
+use sha2::Sha256;
+use sqlx::PgPool;
+
+use crate::{routes::error_chain_fmt, startup::WebhookSecret};
+
+/// The subset of a provider (Postmark-compatible) webhook event we care about.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailEvent {
+    record_type: String,
+    #[serde(rename = "Type", default)]
+    bounce_type: Option<String>,
+    email: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum WebhookError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+    #[error("The webhook signature is invalid.")]
+    InvalidSignature(#[source] anyhow::Error),
+    #[error("The webhook payload is invalid.")]
+    InvalidPayload(#[source] anyhow::Error),
+}
+
+impl std::fmt::Debug for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Self::InvalidSignature(_) => StatusCode::UNAUTHORIZED.into_response(),
+            Self::InvalidPayload(_) => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Handle an email provider webhook",
+    skip(webhook_secret, pool, headers, body),
+    fields(record_type=tracing::field::Empty)
+)]
+pub async fn email_webhook(
+    State(webhook_secret): State<WebhookSecret>,
+    State(pool): State<PgPool>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, WebhookError> {
+    verify_signature(&headers, &body, &webhook_secret).map_err(WebhookError::InvalidSignature)?;
+
+    let event: EmailEvent = serde_json::from_slice(&body)
+        .context("Failed to deserialize the webhook payload.")
+        .map_err(WebhookError::InvalidPayload)?;
+    tracing::Span::current().record("record_type", tracing::field::display(&event.record_type));
+
+    let status = match (event.record_type.as_str(), event.bounce_type.as_deref()) {
+        ("Bounce", Some("HardBounce")) => "bounced",
+        ("SpamComplaint", _) => "complained",
+        // Soft bounces and other events are acknowledged, but don't affect delivery.
+        _ => return Ok(StatusCode::OK.into_response()),
+    };
+
+    mark_subscriber(&pool, &event.email, status)
+        .await
+        .context("Failed to update the subscriber status.")?;
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Check the hex-encoded HMAC-SHA256 of the raw body sent in `X-Webhook-Signature`.
+fn verify_signature(
+    headers: &HeaderMap,
+    body: &[u8],
+    webhook_secret: &WebhookSecret,
+) -> Result<(), anyhow::Error> {
+    let signature = headers
+        .get("X-Webhook-Signature")
+        .context("The 'X-Webhook-Signature' header was missing.")?
+        .to_str()
+        .context("The 'X-Webhook-Signature' header was not a valid UTF8 string.")?;
+    let signature = hex::decode(signature).context("Failed to hex-decode the signature.")?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(webhook_secret.0.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .context("The signature does not match the payload.")
+}
+
+#[tracing::instrument(name = "Mark subscriber as undeliverable", skip(pool, email))]
+async fn mark_subscriber(pool: &PgPool, email: &str, status: &str) -> Result<(), sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = $1
+        WHERE email = $2
+        "#,
+        status,
+        email
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        tracing::warn!("Received a webhook event for an unknown subscriber.");
+    }
+    Ok(())
+}
@@ -1,7 +1,149 @@
 //! src/routes/health_check.rs
 
-use axum::{http::StatusCode, response::IntoResponse};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::{http::StatusCode, Json};
+use sqlx::migrate::Migrator;
+use sqlx::PgPool;
+
+use crate::application_state::EmailClientState;
+use crate::configuration::HealthCheckSettings;
+
+/// The migrations the application was built with.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
 
 pub async fn health_check() -> impl IntoResponse {
     StatusCode::OK
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ok,
+    Unavailable,
+}
+
+#[derive(serde::Serialize)]
+struct CheckReport {
+    status: Status,
+    duration_milliseconds: u128,
+    /// Why the dependency is unavailable. The details are only logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+#[derive(serde::Serialize)]
+struct HealthReport {
+    status: Status,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, CheckReport>,
+}
+
+/// Whether the process is up and serving requests, whatever the state of its
+/// dependencies: orchestrators restart the application when it is not.
+pub async fn liveness() -> impl IntoResponse {
+    Json(HealthReport {
+        status: Status::Ok,
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Whether the application can do its job: the database answers and is fully
+/// migrated, and the email API answers. Orchestrators stop sending traffic
+/// while it responds with `503 Service Unavailable`.
+#[tracing::instrument(skip_all)]
+pub async fn readiness(
+    State(pool): State<PgPool>,
+    State(email_client): State<EmailClientState>,
+    State(settings): State<HealthCheckSettings>,
+) -> Response {
+    let timeout = settings.timeout();
+    let (database, migrations, email_api) = tokio::join!(
+        run_check("database", timeout, check_database(&pool)),
+        run_check("migrations", timeout, check_migrations(&pool)),
+        run_check("email_api", timeout, async {
+            email_client
+                .0
+                .check_health()
+                .await
+                .context("The email API did not answer successfully.")
+        }),
+    );
+
+    let checks = BTreeMap::from([database, migrations, email_api]);
+    let status = if checks.values().all(|check| check.status == Status::Ok) {
+        Status::Ok
+    } else {
+        Status::Unavailable
+    };
+    let status_code = match status {
+        Status::Ok => StatusCode::OK,
+        Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status_code, Json(HealthReport { status, checks })).into_response()
+}
+
+async fn run_check(
+    name: &'static str,
+    timeout: Duration,
+    check: impl Future<Output = Result<(), anyhow::Error>>,
+) -> (&'static str, CheckReport) {
+    let started_at = Instant::now();
+    let outcome = tokio::time::timeout(timeout, check).await;
+    let duration_milliseconds = started_at.elapsed().as_millis();
+
+    let error = match outcome {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => {
+            tracing::warn!(error.cause_chain = ?e, check = name, "A dependency is unavailable");
+            Some("failed")
+        }
+        Err(_) => {
+            tracing::warn!(check = name, "A dependency did not answer in time");
+            Some("timed out")
+        }
+    };
+    let report = CheckReport {
+        status: match error {
+            None => Status::Ok,
+            Some(_) => Status::Unavailable,
+        },
+        duration_milliseconds,
+        error,
+    };
+    (name, report)
+}
+
+async fn check_database(pool: &PgPool) -> Result<(), anyhow::Error> {
+    sqlx::query!("SELECT 1 AS one")
+        .fetch_one(pool)
+        .await
+        .context("Failed to query the database.")?;
+    Ok(())
+}
+
+/// Fails if a migration the application was built with was not applied.
+async fn check_migrations(pool: &PgPool) -> Result<(), anyhow::Error> {
+    // The table belongs to sqlx rather than to our schema: the query is not
+    // checked against it at compile time.
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await
+            .context("Failed to retrieve the applied migrations.")?;
+    let pending: Vec<_> = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| migration.version)
+        .collect();
+    if !pending.is_empty() {
+        anyhow::bail!("Some migrations are pending: {:?}.", pending);
+    }
+    Ok(())
+}
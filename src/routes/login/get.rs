@@ -1,37 +1,37 @@
 //! src/routes/login/get.rs
 
 use axum::{
-    http::header,
+    extract::State,
     response::{IntoResponse, Response},
 };
 use axum_extra::extract::CookieJar;
-use hyper::StatusCode;
 
-pub async fn login_form(cookie_jar: CookieJar) -> Response {
-    let error_html = match cookie_jar.get("_flash") {
+use crate::application_state::OidcClientState;
+use crate::csrf::CsrfToken;
+
+use super::page::{flash_html, html_page};
+
+pub async fn login_form(
+    csrf: CsrfToken,
+    State(oidc): State<OidcClientState>,
+    cookie_jar: CookieJar,
+) -> Response {
+    let error_html = flash_html(&cookie_jar);
+    let csrf_field = csrf.form_field();
+    let oidc_html = match oidc.0 {
         None => "".into(),
-        Some(cookie) => {
-            format!("<p><i>{}</i></p>", cookie.value())
-        }
+        Some(client) => format!(
+            r#"<p><a href="/login/oidc">Log in with {}</a></p>"#,
+            htmlescape::encode_minimal(client.display_name())
+        ),
     };
 
-    (
-        StatusCode::OK,
-        [
-            (header::CONTENT_TYPE, "text/html"),
-            (header::SET_COOKIE, "_flash=; Max-Age=0"),
-        ],
+    let page = html_page(
+        "Login",
         format!(
-            r#"
-            <!DOCTYPE html>
-            <html lang="en">
-            <head>
-                <meta http-equiv="content-type" content="text/html"; charset="utf-8">
-                <title>Login</title>
-            </head>
-            <body>
-                {error_html}
+            r#"{error_html}
                 <form action="/login" method="post">
+                    {csrf_field}
                     <label>Username
                         <input
                             type="text"
@@ -48,10 +48,9 @@ pub async fn login_form(cookie_jar: CookieJar) -> Response {
                     </label>
                     <button type="submit">Login</button>
                 </form>
-            </body>
-            </html>
-            "#
+                {oidc_html}
+                <p><a href="/login/forgot-password">Forgot your password?</a></p>"#
         ),
-    )
-        .into_response()
+    );
+    (csrf, page).into_response()
 }
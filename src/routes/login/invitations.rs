@@ -0,0 +1,146 @@
+//! src/routes/login/invitations.rs
+
+use axum::extract::{Form, Query, State};
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::CookieJar;
+use hyper::StatusCode;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+
+use crate::configuration::PasswordHashingSettings;
+use crate::csrf::CsrfToken;
+use crate::domain::{NewPassword, Username};
+use crate::invitations::{self, InvitationError, InvitationToken};
+use crate::startup::HmacSecret;
+
+use super::page::{flash_html, html_page, redirect_with_flash};
+
+fn invalid_invitation() -> Response {
+    redirect_with_flash("/login", &InvitationError::InvalidInvitation.to_string())
+}
+
+#[derive(serde::Deserialize)]
+pub struct InvitationLinkParameters {
+    token: String,
+}
+
+pub async fn invitation_form(
+    csrf: CsrfToken,
+    cookie_jar: CookieJar,
+    State(pool): State<PgPool>,
+    State(hmac_secret): State<HmacSecret>,
+    Query(parameters): Query<InvitationLinkParameters>,
+) -> Response {
+    let Ok(token) = InvitationToken::decode(&parameters.token, &hmac_secret.0) else {
+        return invalid_invitation();
+    };
+    let invitation = match invitations::get_pending_invitation(&pool, token.invitation_id).await {
+        Ok(Some(invitation)) => invitation,
+        Ok(None) => return invalid_invitation(),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to retrieve an invitation");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let error_html = flash_html(&cookie_jar);
+    // Signed tokens only hold URL-safe base64 and hex characters.
+    let token = parameters.token;
+    let role = invitation.role;
+    let csrf_field = csrf.form_field();
+    let page = html_page(
+        "Join the blog",
+        format!(
+            r#"{error_html}
+                <p>You have been invited to join as {role}.</p>
+                <form action="/invitations/accept" method="post">
+                    {csrf_field}
+                    <input type="hidden" name="token" value="{token}">
+                    <label>Username
+                        <input
+                            type="text"
+                            placeholder="Enter Username"
+                            name="username"
+                        >
+                    </label>
+                    <label>Password
+                        <input
+                            type="password"
+                            placeholder="Enter Password"
+                            name="password"
+                        >
+                    </label>
+                    <label>Confirm password
+                        <input
+                            type="password"
+                            placeholder="Type the password again"
+                            name="password_check"
+                        >
+                    </label>
+                    <button type="submit">Create account</button>
+                </form>"#
+        ),
+    );
+    (csrf, page).into_response()
+}
+
+#[derive(serde::Deserialize)]
+pub struct AcceptanceData {
+    token: String,
+    username: String,
+    password: Secret<String>,
+    password_check: Secret<String>,
+}
+
+#[tracing::instrument(
+    skip(form, pool, hmac_secret, hashing),
+    fields(invitation_id=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn accept_invitation(
+    State(pool): State<PgPool>,
+    State(hmac_secret): State<HmacSecret>,
+    State(hashing): State<PasswordHashingSettings>,
+    Form(form): Form<AcceptanceData>,
+) -> Response {
+    let Ok(token) = InvitationToken::decode(&form.token, &hmac_secret.0) else {
+        return invalid_invitation();
+    };
+    tracing::Span::current().record(
+        "invitation_id",
+        tracing::field::display(&token.invitation_id),
+    );
+    let form_location = format!("/invitations/accept?token={}", form.token);
+
+    let username = match Username::parse(form.username) {
+        Ok(username) => username,
+        // The error quotes the username, which may not fit in a cookie.
+        Err(_) => {
+            return redirect_with_flash(
+                &form_location,
+                "Usernames are 1 to 64 characters without spaces or colons",
+            )
+        }
+    };
+    if form.password.expose_secret() != form.password_check.expose_secret() {
+        return redirect_with_flash(&form_location, "The two passwords do not match");
+    }
+    let password = match NewPassword::parse(form.password) {
+        Ok(password) => password,
+        Err(e) => return redirect_with_flash(&form_location, e.trim_end_matches('.')),
+    };
+
+    match invitations::accept(&pool, token.invitation_id, &username, password, &hashing).await {
+        Ok(user_id) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            redirect_with_flash("/login", "Your account has been created. Please log in")
+        }
+        Err(InvitationError::InvalidInvitation) => invalid_invitation(),
+        Err(e @ (InvitationError::UsernameTaken | InvitationError::EmailTaken)) => {
+            redirect_with_flash(&form_location, e.to_string().trim_end_matches('.'))
+        }
+        Err(InvitationError::UnexpectedError(e)) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to accept an invitation");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
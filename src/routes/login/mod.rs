@@ -1,7 +1,18 @@
 //! src/routes/login/mod.rs
 
 mod get;
+mod invitations;
+mod oidc;
+mod page;
+mod password_reset;
 mod post;
+mod two_factor;
 
 pub use get::login_form;
+pub use invitations::{accept_invitation, invitation_form};
+pub use oidc::{oidc_callback, oidc_login};
+pub use password_reset::{
+    forgot_password_form, password_reset_form, request_password_reset, reset_password,
+};
 pub use post::login;
+pub use two_factor::{two_factor_form, verify_two_factor};
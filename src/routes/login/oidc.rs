@@ -0,0 +1,118 @@
+//! src/routes/login/oidc.rs
+
+use axum::extract::{Query, State};
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::SignedCookieJar;
+use hyper::{header, StatusCode};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::application_state::OidcClientState;
+use crate::auth_events::{self, AuthEvent, AuthEventKind, RequestContext};
+use crate::oidc::{find_user, OidcClient, OidcError, PendingLogin};
+
+use super::page::redirect_with_flash;
+
+/// Sends users to the provider to log in.
+#[tracing::instrument(skip_all)]
+pub async fn oidc_login(State(oidc): State<OidcClientState>, jar: SignedCookieJar) -> Response {
+    let Some(client) = oidc.0 else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match client.start_login().await {
+        Ok((url, pending)) => (
+            StatusCode::SEE_OTHER,
+            [(header::LOCATION, url)],
+            jar.add(pending.cookie()),
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to start an OpenID Connect login");
+            redirect_with_flash("/login", "The identity provider cannot be reached.")
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct CallbackParameters {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set by providers instead of a code when users did not log in.
+    error: Option<String>,
+}
+
+/// Where providers send users back to, once they logged in.
+#[tracing::instrument(
+    skip_all,
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn oidc_callback(
+    request: RequestContext,
+    State(pool): State<PgPool>,
+    State(oidc): State<OidcClientState>,
+    jar: SignedCookieJar,
+    Query(parameters): Query<CallbackParameters>,
+) -> Response {
+    let Some(client) = oidc.0 else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let pending = PendingLogin::from_jar(&jar);
+    let jar = jar.remove(PendingLogin::removal_cookie());
+
+    let outcome = match finish_login(&client, &pool, pending, parameters).await {
+        Ok(user_id) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            let event = AuthEvent {
+                kind: AuthEventKind::LoginSucceeded,
+                user_id: Some(user_id),
+                username: None,
+                request: Some(&request),
+                detail: Some("with OpenID Connect".into()),
+            };
+            auth_events::record(&pool, event)
+                .await
+                .map_err(OidcError::UnexpectedError)
+        }
+        Err(e) => Err(e),
+    };
+    match outcome {
+        Ok(()) => (StatusCode::SEE_OTHER, [(header::LOCATION, "/")], jar).into_response(),
+        Err(OidcError::UnexpectedError(e)) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to finish an OpenID Connect login");
+            (jar, StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        }
+        Err(e) => {
+            tracing::warn!(error.cause_chain = ?e, "Rejected an OpenID Connect login");
+            let event = AuthEvent {
+                kind: AuthEventKind::LoginFailed,
+                user_id: None,
+                username: None,
+                request: Some(&request),
+                detail: Some(format!("with OpenID Connect: {}", e)),
+            };
+            if let Err(e) = auth_events::record(&pool, event).await {
+                tracing::error!(error.cause_chain = ?e, "Failed to record a failed login");
+            }
+            (jar, redirect_with_flash("/login", &e.to_string())).into_response()
+        }
+    }
+}
+
+async fn finish_login(
+    client: &OidcClient,
+    pool: &PgPool,
+    pending: Option<PendingLogin>,
+    parameters: CallbackParameters,
+) -> Result<Uuid, OidcError> {
+    let pending = pending.ok_or(OidcError::InvalidState)?;
+    if parameters.state.as_deref() != Some(&pending.state) {
+        return Err(OidcError::InvalidState);
+    }
+    if let Some(error) = parameters.error {
+        return Err(OidcError::ProviderError(error));
+    }
+    let code = parameters.code.ok_or(OidcError::InvalidState)?;
+
+    let claims = client.finish_login(&code, &pending).await?;
+    find_user(pool, &claims).await
+}
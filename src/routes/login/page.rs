@@ -0,0 +1,66 @@
+//! src/routes/login/page.rs
+//!
+//! What the account pages have in common: they are forms that redirect back
+//! to themselves with a flash message when something is wrong.
+
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::CookieJar;
+use hyper::{header, StatusCode};
+
+const FLASH_COOKIE: &str = "_flash";
+
+/// Redirects to `location` with a message for it to show. The cookie is set
+/// for every path, as the page redirected to is usually not under the one
+/// redirected from.
+pub(super) fn redirect_with_flash(location: &str, message: &str) -> Response {
+    (
+        StatusCode::SEE_OTHER,
+        [
+            (header::LOCATION, location.to_string()),
+            (
+                header::SET_COOKIE,
+                format!("{}={}; Path=/", FLASH_COOKIE, message),
+            ),
+        ],
+    )
+        .into_response()
+}
+
+/// The flash message for the page to show, if any. Anyone can set the cookie
+/// on their own browser, so it is escaped like any other input.
+pub(super) fn flash_html(cookie_jar: &CookieJar) -> String {
+    match cookie_jar.get(FLASH_COOKIE) {
+        None => "".into(),
+        Some(cookie) => {
+            format!(
+                "<p><i>{}</i></p>",
+                htmlescape::encode_minimal(cookie.value())
+            )
+        }
+    }
+}
+
+pub(super) fn html_page(title: &str, body: String) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "text/html"),
+            (header::SET_COOKIE, "_flash=; Path=/; Max-Age=0"),
+        ],
+        format!(
+            r#"
+            <!DOCTYPE html>
+            <html lang="en">
+            <head>
+                <meta http-equiv="content-type" content="text/html"; charset="utf-8">
+                <title>{title}</title>
+            </head>
+            <body>
+                {body}
+            </body>
+            </html>
+            "#
+        ),
+    )
+        .into_response()
+}
@@ -0,0 +1,180 @@
+//! src/routes/login/password_reset.rs
+
+use axum::extract::{Form, Query, State};
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::CookieJar;
+use hyper::StatusCode;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+
+use crate::application_state::ApplicationState;
+use crate::auth_events::{self, AuthEvent, AuthEventKind, RequestContext};
+use crate::configuration::PasswordHashingSettings;
+use crate::csrf::CsrfToken;
+use crate::domain::{NewPassword, SubscriberEmail};
+use crate::password_reset::{self, is_well_formed_token, PasswordResetError};
+
+use super::page::{flash_html, html_page, redirect_with_flash};
+
+pub async fn forgot_password_form(csrf: CsrfToken, cookie_jar: CookieJar) -> Response {
+    let error_html = flash_html(&cookie_jar);
+    let csrf_field = csrf.form_field();
+    let page = html_page(
+        "Forgot your password?",
+        format!(
+            r#"{error_html}
+                <form action="/login/forgot-password" method="post">
+                    {csrf_field}
+                    <label>Email address of your account
+                        <input
+                            type="email"
+                            placeholder="Enter Email"
+                            name="email"
+                        >
+                    </label>
+                    <button type="submit">Send a reset link</button>
+                </form>"#
+        ),
+    );
+    (csrf, page).into_response()
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetRequestData {
+    email: String,
+}
+
+/// Whether or not the address belongs to an account, the answer is the same.
+#[tracing::instrument(skip(form, app_state))]
+pub async fn request_password_reset(
+    State(app_state): State<ApplicationState>,
+    Form(form): Form<ResetRequestData>,
+) -> Response {
+    match SubscriberEmail::parse(form.email) {
+        Ok(email) => {
+            let outcome = password_reset::request_reset(
+                &app_state.db_pool,
+                app_state.email_client.0.clone(),
+                &app_state.background_tasks,
+                &app_state.base_url.0 .0,
+                email,
+            )
+            .await;
+            if let Err(e) = outcome {
+                tracing::error!(error.cause_chain = ?e, "Failed to request a password reset");
+            }
+        }
+        Err(e) => tracing::info!(error = %e, "Password reset requested for an invalid address"),
+    }
+
+    redirect_with_flash(
+        "/login",
+        "If this address belongs to an account a password reset link has been sent to it",
+    )
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetLinkParameters {
+    token: String,
+}
+
+pub async fn password_reset_form(
+    csrf: CsrfToken,
+    cookie_jar: CookieJar,
+    Query(parameters): Query<ResetLinkParameters>,
+) -> Response {
+    // The token ends up in the page: only the ones that can be valid make it there.
+    if !is_well_formed_token(&parameters.token) {
+        return redirect_with_flash(
+            "/login/forgot-password",
+            &PasswordResetError::InvalidToken.to_string(),
+        );
+    }
+    let error_html = flash_html(&cookie_jar);
+    let token = parameters.token;
+    let csrf_field = csrf.form_field();
+    let page = html_page(
+        "Reset your password",
+        format!(
+            r#"{error_html}
+                <form action="/login/reset-password" method="post">
+                    {csrf_field}
+                    <input type="hidden" name="token" value="{token}">
+                    <label>New password
+                        <input
+                            type="password"
+                            placeholder="Enter new password"
+                            name="new_password"
+                        >
+                    </label>
+                    <label>Confirm new password
+                        <input
+                            type="password"
+                            placeholder="Type the new password again"
+                            name="new_password_check"
+                        >
+                    </label>
+                    <button type="submit">Reset password</button>
+                </form>"#
+        ),
+    );
+    (csrf, page).into_response()
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetData {
+    token: String,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(
+    skip(form, request, pool, hashing),
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn reset_password(
+    request: RequestContext,
+    State(pool): State<PgPool>,
+    State(hashing): State<PasswordHashingSettings>,
+    Form(form): Form<ResetData>,
+) -> Response {
+    if !is_well_formed_token(&form.token) {
+        return redirect_with_flash(
+            "/login/forgot-password",
+            &PasswordResetError::InvalidToken.to_string(),
+        );
+    }
+    let form_location = format!("/login/reset-password?token={}", form.token);
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        return redirect_with_flash(&form_location, "The two passwords do not match");
+    }
+    let password = match NewPassword::parse(form.new_password) {
+        Ok(password) => password,
+        Err(e) => return redirect_with_flash(&form_location, e.trim_end_matches('.')),
+    };
+
+    match password_reset::reset_password(&pool, &form.token, password, &hashing).await {
+        Ok(user_id) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            let event = AuthEvent {
+                kind: AuthEventKind::PasswordChanged,
+                user_id: Some(user_id),
+                username: None,
+                request: Some(&request),
+                detail: Some("reset with an emailed link".into()),
+            };
+            if let Err(e) = auth_events::record(&pool, event).await {
+                // The password has been changed already: there is no taking it back.
+                tracing::error!(error.cause_chain = ?e, "Failed to record a password change");
+            }
+            redirect_with_flash("/login", "Your password has been reset. Please log in")
+        }
+        Err(e @ PasswordResetError::InvalidToken) => {
+            redirect_with_flash("/login/forgot-password", &e.to_string())
+        }
+        Err(PasswordResetError::UnexpectedError(e)) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to reset a password");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -2,12 +2,20 @@
 
 use axum::extract::{Form, State};
 use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::SignedCookieJar;
+use chrono::Utc;
 use hyper::{header, StatusCode};
 use secrecy::Secret;
 use sqlx::PgPool;
 
-use crate::authentication::{validate_credentials, AuthError, Credientials};
+use crate::auth_events::{self, AuthEvent, AuthEventKind, RequestContext};
+use crate::authentication::{authenticate, AuthError, Credientials, PasswordHashing};
+use crate::login_throttle::LoginThrottle;
 use crate::routes::error_chain_fmt;
+use crate::two_factor;
+
+use super::page::redirect_with_flash;
+use super::two_factor::PendingLogin;
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
@@ -16,36 +24,73 @@ pub struct FormData {
 }
 
 #[tracing::instrument(
-    skip(form, pool),
+    skip(form, request, pool, throttle, hashing, jar),
     fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
 )]
-pub async fn login(State(pool): State<PgPool>, Form(form): Form<FormData>) -> Response {
+pub async fn login(
+    request: RequestContext,
+    State(pool): State<PgPool>,
+    State(throttle): State<LoginThrottle>,
+    State(hashing): State<PasswordHashing>,
+    jar: SignedCookieJar,
+    Form(form): Form<FormData>,
+) -> Response {
+    let username = form.username.clone();
     let credentials = Credientials {
         username: form.username,
         password: form.password,
     };
 
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
 
-    match validate_credentials(credentials, &pool).await {
-        Ok(user_id) => {
-            tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
+    let outcome = match authenticate(credentials, &request, &pool, &throttle, &hashing).await {
+        Ok(user_id) => two_factor::is_enabled(&pool, user_id)
+            .await
+            .map(|enabled| (user_id, enabled))
+            .map_err(AuthError::UnexpectedError),
+        Err(e) => Err(e),
+    };
+    // Logins with two-factor authentication only succeed after the second step.
+    let outcome = match outcome {
+        Ok((user_id, false)) => {
+            let event = AuthEvent {
+                kind: AuthEventKind::LoginSucceeded,
+                user_id: Some(user_id),
+                username: Some(&username),
+                request: Some(&request),
+                detail: None,
+            };
+            auth_events::record(&pool, event)
+                .await
+                .map(|()| (user_id, false))
+                .map_err(AuthError::UnexpectedError)
+        }
+        outcome => outcome,
+    };
+
+    match outcome {
+        Ok((user_id, false)) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
             (StatusCode::SEE_OTHER, [(header::LOCATION, "/")]).into_response()
         }
-        Err(e) => {
-            let e = match e {
-                AuthError::InvalidCredentials(_) => LoginError::AuthError(e.into()),
-                AuthError::UnexpectedError(_) => LoginError::UnexpectedError(e.into()),
+        Ok((user_id, true)) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            let pending = PendingLogin {
+                user_id,
+                username,
+                started_at: Utc::now(),
             };
             (
                 StatusCode::SEE_OTHER,
-                [
-                    (header::LOCATION, &"/login".to_string()),
-                    (header::SET_COOKIE, &format!("_flash={}", e)),
-                ],
+                [(header::LOCATION, "/login/two-factor")],
+                jar.add(pending.cookie()),
             )
                 .into_response()
         }
+        Err(e) => {
+            let e = LoginError::from_auth_error(e);
+            redirect_with_flash("/login", &e.to_string())
+        }
     }
 }
 
@@ -53,10 +98,26 @@ pub async fn login(State(pool): State<PgPool>, Form(form): Form<FormData>) -> Re
 pub enum LoginError {
     #[error("Authentication failed")]
     AuthError(#[source] anyhow::Error),
+    #[error("Too many failed attempts. Try again later")]
+    TooManyAttempts(#[source] anyhow::Error),
+    #[error("Your login expired. Please log in again")]
+    Expired,
     #[error("Something went wrong")]
     UnexpectedError(#[from] anyhow::Error),
 }
 
+impl LoginError {
+    pub fn from_auth_error(e: AuthError) -> Self {
+        match e {
+            AuthError::InvalidCredentials(_)
+            | AuthError::SecondFactorNotEnabled
+            | AuthError::MissingScope(_) => LoginError::AuthError(e.into()),
+            AuthError::TooManyAttempts { .. } => LoginError::TooManyAttempts(e.into()),
+            AuthError::UnexpectedError(_) => LoginError::UnexpectedError(e.into()),
+        }
+    }
+}
+
 impl std::fmt::Debug for LoginError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         error_chain_fmt(self, f)
@@ -0,0 +1,172 @@
+//! src/routes/login/two_factor.rs
+
+use axum::extract::{Form, State};
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::{Cookie, SameSite, SignedCookieJar};
+use axum_extra::extract::CookieJar;
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{header, StatusCode};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::page::{flash_html, html_page, redirect_with_flash};
+use super::post::LoginError;
+use crate::auth_events::{self, AuthEvent, AuthEventKind, RequestContext};
+use crate::authentication::{authenticate_second_factor, AuthError};
+use crate::csrf::CsrfToken;
+use crate::login_throttle::LoginThrottle;
+use crate::password_reset::password_changed_since;
+use crate::two_factor::CodeReuse;
+
+const PENDING_LOGIN_COOKIE: &str = "_two_factor";
+/// How long users have to type in their code once their password was checked.
+const PENDING_LOGIN_LIFETIME_MINUTES: i64 = 5;
+
+/// A login waiting for its second step, kept in a signed cookie.
+pub struct PendingLogin {
+    pub user_id: Uuid,
+    pub username: String,
+    pub started_at: DateTime<Utc>,
+}
+
+impl PendingLogin {
+    pub fn cookie(&self) -> Cookie<'static> {
+        let value = format!(
+            "{}:{}:{}",
+            self.started_at.timestamp(),
+            self.user_id,
+            self.username
+        );
+        Cookie::build((PENDING_LOGIN_COOKIE, value))
+            .path("/login")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .build()
+    }
+
+    fn from_jar(jar: &SignedCookieJar) -> Option<Self> {
+        let cookie = jar.get(PENDING_LOGIN_COOKIE)?;
+        let mut segments = cookie.value().splitn(3, ':');
+        let started_at = Utc
+            .timestamp_opt(segments.next()?.parse().ok()?, 0)
+            .single()?;
+        let user_id = segments.next()?.parse().ok()?;
+        let username = segments.next()?.to_owned();
+
+        let expires_at = started_at + chrono::Duration::minutes(PENDING_LOGIN_LIFETIME_MINUTES);
+        (expires_at > Utc::now()).then_some(Self {
+            user_id,
+            username,
+            started_at,
+        })
+    }
+}
+
+fn removal_cookie() -> Cookie<'static> {
+    Cookie::build((PENDING_LOGIN_COOKIE, ""))
+        .path("/login")
+        .build()
+}
+
+fn expired_login(jar: SignedCookieJar) -> Response {
+    (
+        jar.remove(removal_cookie()),
+        redirect_with_flash("/login", &LoginError::Expired.to_string()),
+    )
+        .into_response()
+}
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    code: String,
+}
+
+pub async fn two_factor_form(csrf: CsrfToken, cookie_jar: CookieJar) -> Response {
+    let error_html = flash_html(&cookie_jar);
+    let csrf_field = csrf.form_field();
+
+    let page = html_page(
+        "Two-factor authentication",
+        format!(
+            r#"{error_html}
+                <form action="/login/two-factor" method="post">
+                    {csrf_field}
+                    <label>Code from your authenticator app, or a recovery code
+                        <input
+                            type="text"
+                            autocomplete="one-time-code"
+                            placeholder="Enter code"
+                            name="code"
+                        >
+                    </label>
+                    <button type="submit">Verify</button>
+                </form>"#
+        ),
+    );
+    (csrf, page).into_response()
+}
+
+#[tracing::instrument(
+    skip(form, request, pool, throttle, jar),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn verify_two_factor(
+    request: RequestContext,
+    State(pool): State<PgPool>,
+    State(throttle): State<LoginThrottle>,
+    jar: SignedCookieJar,
+    Form(form): Form<FormData>,
+) -> Response {
+    let Some(pending) = PendingLogin::from_jar(&jar) else {
+        return expired_login(jar);
+    };
+    // A password reset ends the logins started before it.
+    match password_changed_since(&pool, pending.user_id, pending.started_at).await {
+        Ok(false) => {}
+        Ok(true) => return expired_login(jar),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to check for a password change");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    tracing::Span::current().record("username", tracing::field::display(&pending.username));
+    tracing::Span::current().record("user_id", tracing::field::display(&pending.user_id));
+
+    let outcome = authenticate_second_factor(
+        pending.user_id,
+        &pending.username,
+        &form.code,
+        CodeReuse::Forbidden,
+        &request,
+        &pool,
+        &throttle,
+    )
+    .await;
+    let outcome = match outcome {
+        Ok(()) => {
+            let event = AuthEvent {
+                kind: AuthEventKind::LoginSucceeded,
+                user_id: Some(pending.user_id),
+                username: Some(&pending.username),
+                request: Some(&request),
+                detail: Some("with a second factor".into()),
+            };
+            auth_events::record(&pool, event)
+                .await
+                .map_err(AuthError::UnexpectedError)
+        }
+        Err(e) => Err(e),
+    };
+    match outcome {
+        Ok(()) => (
+            StatusCode::SEE_OTHER,
+            [(header::LOCATION, "/")],
+            jar.remove(removal_cookie()),
+        )
+            .into_response(),
+        Err(e) => {
+            let e = LoginError::from_auth_error(e);
+            redirect_with_flash("/login/two-factor", &e.to_string())
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! src/routes/metrics.rs
+
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::metrics::Metrics;
+use crate::startup::MetricsToken;
+
+/// The metrics for Prometheus to scrape, with the configured bearer token:
+/// they are not for the public, and refreshing them queries the database.
+/// Those read from the database are left as they were when it cannot be
+/// queried, rather than failing the scrape.
+#[tracing::instrument(skip_all)]
+pub async fn export_metrics(
+    State(metrics): State<Arc<Metrics>>,
+    State(pool): State<PgPool>,
+    State(token): State<MetricsToken>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_authorized(&headers, &token) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, r#"Bearer realm="metrics""#)],
+        )
+            .into_response();
+    }
+    if let Err(e) = metrics.refresh(&pool).await {
+        tracing::warn!(error.cause_chain = ?e, "Failed to refresh the metrics read from the database");
+    }
+    match metrics.encode() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to encode the metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Whether the request carries the metrics token. The hashes are compared
+/// rather than the tokens, so that the time it takes tells nothing of the token.
+fn is_authorized(headers: &HeaderMap, MetricsToken(token): &MetricsToken) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|candidate| {
+            Sha256::digest(candidate.trim().as_bytes())
+                == Sha256::digest(token.expose_secret().as_bytes())
+        })
+}
@@ -1,13 +1,22 @@
+mod admin;
+mod email_webhooks;
 mod health_check;
 mod home;
 mod login;
+mod metrics;
 mod newsletters;
+mod pages;
 mod subscriptions;
 mod subscriptions_confirm;
+mod tracking;
 
+pub use admin::*;
+pub use email_webhooks::*;
 pub use health_check::*;
 pub use home::*;
 pub use login::*;
+pub use metrics::*;
 pub use newsletters::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use tracking::*;
@@ -1,26 +1,51 @@
 //! src/routes/newletters.rs
 
 use anyhow::Context;
-use axum::extract::{Json, State};
-use axum::http::{self, HeaderMap, StatusCode};
+use axum::body::Body;
+use axum::extract::multipart::{Field, MultipartError};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{FromRequest, Json, Multipart, State};
+use axum::http::{Request, StatusCode};
 use axum::response::{IntoResponse, Response};
-use base64::Engine;
+use chrono::Utc;
 use hyper::header;
-use secrecy::Secret;
-use sqlx::PgPool;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
 
 use crate::application_state::ApplicationState;
-use crate::authentication::{validate_credentials, AuthError, Credientials};
-use crate::domain::SubscriberEmail;
+use crate::authorization::{require, Authorized};
+use crate::domain::{SenderIdentity, SubscriberEmail};
+use crate::email_client::{Attachment, Attachments, AttachmentsError, MAX_ATTACHMENTS_SIZE};
+use crate::newsletter_delivery::{deliver_issue, enqueue_deliveries, Issue, Recipient};
 
 use super::error_chain_fmt;
 
+/// The largest title and content of an issue, all together, once decoded.
+pub const MAX_CONTENT_SIZE: usize = 2 * 1024 * 1024;
+
+/// The largest request body a valid issue can be sent in: JSON escapes a
+/// character of content to up to 6 bytes (`\u001f`), and attachments take
+/// less than their encoded size once uploaded as files. The limits are
+/// enforced on the decoded issue, this one only bounds how much we read.
+pub const MAX_PUBLISH_REQUEST_SIZE: usize = MAX_ATTACHMENTS_SIZE + 6 * MAX_CONTENT_SIZE + 64 * 1024;
+
 #[derive(thiserror::Error)]
 pub enum PublishError {
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
-    #[error("Authentication failed")]
-    AuthError(#[source] anyhow::Error),
+    #[error(transparent)]
+    InvalidJson(#[from] JsonRejection),
+    #[error(transparent)]
+    InvalidMultipart(#[from] MultipartError),
+    #[error("{0}")]
+    InvalidIssue(String),
+    #[error(transparent)]
+    InvalidAttachments(#[from] AttachmentsError),
+    #[error(
+        "The issue content takes {0} bytes, the limit is {max} bytes.",
+        max = MAX_CONTENT_SIZE
+    )]
+    ContentTooLarge(usize),
 }
 
 impl std::fmt::Debug for PublishError {
@@ -36,13 +61,20 @@ impl IntoResponse for PublishError {
                 tracing::error!("Unexpected error caused by {}", error);
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
-            PublishError::AuthError(_) => {
-                let mut response = StatusCode::UNAUTHORIZED.into_response();
-                response.headers_mut().insert(
-                    header::WWW_AUTHENTICATE,
-                    http::HeaderValue::from_str(r#"Basic realm="publish""#).unwrap(),
-                );
-                response
+            PublishError::InvalidJson(rejection) => rejection.into_response(),
+            PublishError::InvalidMultipart(rejection) => rejection.into_response(),
+            PublishError::InvalidIssue(message) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+            PublishError::InvalidAttachments(error) => {
+                let status = match error {
+                    AttachmentsError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                    AttachmentsError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                };
+                (status, error.to_string()).into_response()
+            }
+            PublishError::ContentTooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()).into_response()
             }
         }
     }
@@ -52,6 +84,14 @@ impl IntoResponse for PublishError {
 pub struct BodyData {
     title: String,
     content: Content,
+    /// Opt this issue out of open and click tracking.
+    /// It has no effect if tracking is disabled for the mailing list.
+    #[serde(default)]
+    tracking: Option<bool>,
+    /// The name of the sender identity to send this issue from,
+    /// instead of the one of the mailing list.
+    #[serde(default)]
+    sender: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
@@ -60,68 +100,314 @@ pub struct Content {
     text: String,
 }
 
+/// A newsletter issue submitted for publication.
+///
+/// It is sent as JSON, or as `multipart/form-data` when it comes with files:
+/// `title`, `html`, `text`, `tracking` and `sender` fields, plus an `attachment` part
+/// per attached file and an `inline` part per image embedded in the HTML
+/// content as `cid:<file name>`.
+pub struct NewIssue {
+    title: String,
+    content: Content,
+    tracking: Option<bool>,
+    sender: Option<String>,
+    attachments: Attachments,
+}
+
+impl From<BodyData> for NewIssue {
+    fn from(body: BodyData) -> Self {
+        Self {
+            title: body.title,
+            content: body.content,
+            tracking: body.tracking,
+            sender: body.sender,
+            attachments: Attachments::default(),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequest<S, Body> for NewIssue
+where
+    S: Send + Sync,
+{
+    type Rejection = PublishError;
+
+    async fn from_request(request: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let is_multipart = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+        let issue: NewIssue = if is_multipart {
+            let multipart = Multipart::from_request(request, state)
+                .await
+                .map_err(|rejection| PublishError::InvalidIssue(rejection.body_text()))?;
+            parse_multipart_issue(multipart).await?
+        } else {
+            let Json(body) = Json::<BodyData>::from_request(request, state).await?;
+            body.into()
+        };
+
+        let content_size = issue.title.len() + issue.content.html.len() + issue.content.text.len();
+        if content_size > MAX_CONTENT_SIZE {
+            return Err(PublishError::ContentTooLarge(content_size));
+        }
+        Ok(issue)
+    }
+}
+
+async fn parse_multipart_issue(mut multipart: Multipart) -> Result<NewIssue, PublishError> {
+    let (mut title, mut html, mut text, mut tracking, mut sender) = (None, None, None, None, None);
+    let mut attachments = Vec::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        match field.name().unwrap_or_default() {
+            "title" => title = Some(field.text().await?),
+            "html" => html = Some(field.text().await?),
+            "text" => text = Some(field.text().await?),
+            "tracking" => {
+                let value = field.text().await?;
+                tracking = Some(value.parse().map_err(|_| {
+                    PublishError::InvalidIssue(format!("{} is not a valid tracking flag.", value))
+                })?);
+            }
+            "sender" => sender = Some(field.text().await?),
+            "attachment" => {
+                let (name, content_type) = file_details(&field)?;
+                attachments.push(Attachment::new(name, content_type, &field.bytes().await?));
+            }
+            "inline" => {
+                let (name, content_type) = file_details(&field)?;
+                attachments.push(Attachment::inline(
+                    name,
+                    content_type,
+                    &field.bytes().await?,
+                ));
+            }
+            // Unknown parts are ignored, like unknown JSON fields.
+            _ => {}
+        }
+    }
+
+    let missing = |field: &str| PublishError::InvalidIssue(format!("missing field `{}`", field));
+    Ok(NewIssue {
+        title: title.ok_or_else(|| missing("title"))?,
+        content: Content {
+            html: html.ok_or_else(|| missing("html"))?,
+            text: text.ok_or_else(|| missing("text"))?,
+        },
+        tracking,
+        sender,
+        attachments: Attachments::parse(attachments)?,
+    })
+}
+
+/// The name and content type of an uploaded file.
+fn file_details(field: &Field<'_>) -> Result<(String, String), PublishError> {
+    let name = field
+        .file_name()
+        .ok_or_else(|| PublishError::InvalidIssue("Uploaded files must have a name.".into()))?;
+    let content_type = field.content_type().unwrap_or("application/octet-stream");
+    Ok((name.to_owned(), content_type.to_owned()))
+}
+
 #[tracing::instrument(
     name = "Publish a newsletter issue",
-    skip(app_state, header_map, body),
-    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+    skip(publisher, app_state, issue),
+    fields(user_id=tracing::field::Empty)
 )]
 pub async fn publish_newsletter(
+    publisher: Authorized<require::PublishNewsletters>,
     State(app_state): State<ApplicationState>,
-    header_map: HeaderMap,
-    Json(body): Json<BodyData>,
+    issue: NewIssue,
 ) -> Result<Response, PublishError> {
-    let credentials = basic_authentication(&header_map).map_err(PublishError::AuthError)?;
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
+    // Machines publish with an API token, people with their password and a second factor.
+    let user_id = publisher.user_id;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
 
-    let user_id = validate_credentials(credentials, &app_state.db_pool)
-        .await
-        .map_err(|e| match e {
-            AuthError::InvalidCredentials(_) => PublishError::AuthError(e.into()),
-            AuthError::UnexpectedError(_) => PublishError::UnexpectedError(e.into()),
-        })?;
-
-    tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
-
-    let susbcribers = get_confirmed_subscribers(&app_state.db_pool).await?;
-    for subscriber in susbcribers {
-        match subscriber {
-            Ok(subscriber) => app_state
-                .email_client
-                .0
-                .send_email(
-                    &subscriber.email,
-                    &body.title,
-                    &body.content.html,
-                    &body.content.text,
-                )
-                .await
-                .with_context(|| {
-                    format!("Failed to send newsletter issue to {}", subscriber.email)
-                })?,
+    let tracking_enabled = app_state.newsletter.tracking_enabled && issue.tracking.unwrap_or(true);
+    let sender = sender_identity(&app_state, &issue)?;
+
+    let subscribers: Vec<_> = get_confirmed_subscribers(&app_state.db_pool)
+        .await?
+        .into_iter()
+        .filter_map(|subscriber| match subscriber {
+            Ok(subscriber) => Some(subscriber),
             Err(error) => {
                 tracing::warn!(
                     error.cause_chain = ?error,
                     "Skipping a confirmed susbcriber. \
                     Their stored contact details are invalid"
-                )
+                );
+                None
             }
-        }
+        })
+        .collect();
+
+    let mut transaction = app_state
+        .db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let newsletter_issue_id = insert_newsletter_issue(&mut transaction, &issue, tracking_enabled)
+        .await
+        .context("Failed to store newsletter issue details.")?;
+    insert_newsletter_issue_attachments(&mut transaction, newsletter_issue_id, &issue.attachments)
+        .await
+        .context("Failed to store newsletter issue attachments.")?;
+    let subscriber_ids: Vec<_> = subscribers.iter().map(|s| s.subscriber_id).collect();
+    enqueue_deliveries(&mut transaction, newsletter_issue_id, &subscriber_ids)
+        .await
+        .context("Failed to enqueue newsletter deliveries.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a newsletter issue.")?;
+
+    let published_issue = Issue {
+        newsletter_issue_id,
+        title: &issue.title,
+        html_content: &issue.content.html,
+        text_content: &issue.content.text,
+        attachments: &issue.attachments,
+        sender,
+        tracking_enabled,
+    };
+    deliver_issue(&app_state, &published_issue, subscribers).await?;
+
+    Ok(Json(serde_json::json!({ "newsletter_issue_id": newsletter_issue_id })).into_response())
+}
+
+/// Checks an issue the way publishing it would, without storing or sending
+/// anything, and returns it as subscribers would get it, before tracking.
+#[tracing::instrument(
+    name = "Preview a newsletter issue",
+    skip(editor, app_state, issue),
+    fields(user_id=%editor.user_id)
+)]
+pub async fn preview_newsletter(
+    editor: Authorized<require::DraftNewsletters>,
+    State(app_state): State<ApplicationState>,
+    issue: NewIssue,
+) -> Result<Response, PublishError> {
+    sender_identity(&app_state, &issue)?;
+    let attachments: Vec<_> = issue
+        .attachments
+        .iter()
+        .map(|attachment| {
+            serde_json::json!({
+                "name": attachment.name(),
+                "content_type": attachment.content_type(),
+                "inline": attachment.content_id().is_some(),
+            })
+        })
+        .collect();
+
+    Ok(Json(serde_json::json!({
+        "title": issue.title,
+        "content": {
+            "html": issue.content.html,
+            "text": issue.content.text,
+        },
+        "attachments": attachments,
+    }))
+    .into_response())
+}
+
+/// The identity the issue is sent from, if not the default one of the email client.
+fn sender_identity<'a>(
+    app_state: &'a ApplicationState,
+    issue: &NewIssue,
+) -> Result<Option<&'a SenderIdentity>, PublishError> {
+    let name = issue
+        .sender
+        .as_ref()
+        .or(app_state.newsletter.sender.as_ref());
+    match name {
+        Some(name) => app_state
+            .email_client
+            .0
+            .sender_identity(name)
+            .map(Some)
+            .ok_or_else(|| {
+                PublishError::InvalidIssue(format!("{} is not a known sender identity.", name))
+            }),
+        None => Ok(None),
     }
+}
 
-    Ok(StatusCode::OK.into_response())
+#[tracing::instrument(name = "Save newsletter issue details", skip(transaction, issue))]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue: &NewIssue,
+    tracking_enabled: bool,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            tracking_enabled,
+            published_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        newsletter_issue_id,
+        issue.title,
+        issue.content.text,
+        issue.content.html,
+        tracking_enabled,
+        Utc::now()
+    );
+    transaction.execute(query).await?;
+
+    Ok(newsletter_issue_id)
 }
 
-struct ConfirmedSubscriber {
-    email: SubscriberEmail,
+#[tracing::instrument(
+    name = "Save newsletter issue attachments",
+    skip(transaction, attachments)
+)]
+async fn insert_newsletter_issue_attachments(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    attachments: &Attachments,
+) -> Result<(), sqlx::Error> {
+    for attachment in attachments.iter() {
+        let query = sqlx::query!(
+            r#"
+            INSERT INTO newsletter_issue_attachments (
+                newsletter_issue_id,
+                name,
+                content_type,
+                content_id,
+                content
+            )
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            newsletter_issue_id,
+            attachment.name(),
+            attachment.content_type(),
+            attachment.content_id(),
+            attachment.content()
+        );
+        transaction.execute(query).await?;
+    }
+    Ok(())
 }
 
 #[tracing::instrument(name = "Get confirmed subscribers", skip(pool))]
 async fn get_confirmed_subscribers(
     pool: &PgPool,
-) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
+) -> Result<Vec<Result<Recipient, anyhow::Error>>, anyhow::Error> {
     let confirmed_susbcribers = sqlx::query!(
         r#"
-        SELECT email
+        SELECT id, email
         FROM subscriptions
         WHERE status = 'confirmed'
         "#
@@ -130,41 +416,13 @@ async fn get_confirmed_subscribers(
     .await?
     .into_iter()
     .map(|r| match SubscriberEmail::parse(r.email) {
-        Ok(email) => Ok(ConfirmedSubscriber { email }),
+        Ok(email) => Ok(Recipient {
+            subscriber_id: r.id,
+            email,
+        }),
         Err(error) => Err(anyhow::anyhow!(error)),
     })
     .collect();
 
     Ok(confirmed_susbcribers)
 }
-
-fn basic_authentication(headers: &HeaderMap) -> Result<Credientials, anyhow::Error> {
-    let header_value = headers
-        .get("Authorization")
-        .context("The 'Authorization' header was missing")?
-        .to_str()
-        .context("The 'Authorization' header was not a valid UTF8 string.")?;
-    let base64encoded_segment = header_value
-        .strip_prefix("Basic ")
-        .context("The authorization scheme was not 'Basic'.")?;
-    let decoded_bytes = base64::engine::general_purpose::STANDARD
-        .decode(base64encoded_segment)
-        .context("Failed to base64-decode 'Basic' credentials.")?;
-    let decoded_credentials = String::from_utf8(decoded_bytes)
-        .context("The decoded credential string is not valid UTF8.")?;
-
-    let mut credientials = decoded_credentials.splitn(2, ':');
-    let username = credientials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
-        .to_string();
-    let password = credientials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
-        .to_string();
-
-    Ok(Credientials {
-        username,
-        password: Secret::new(password),
-    })
-}
@@ -0,0 +1,238 @@
+//! src/routes/pages/mod.rs
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use hyper::StatusCode;
+
+/// How the outcome of a request is rendered: HTML pages for browsers,
+/// JSON for API clients asking for it with the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Html,
+    Json,
+}
+
+impl ResponseFormat {
+    /// JSON is picked only if it is preferred over HTML: a missing header,
+    /// `*/*` or a tie all get HTML.
+    fn from_accept(accept: &str) -> Self {
+        let ranges: Vec<_> = accept.split(',').filter_map(MediaRange::parse).collect();
+        if quality(&ranges, "application", "json") > quality(&ranges, "text", "html") {
+            Self::Json
+        } else {
+            Self::Html
+        }
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for ResponseFormat
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Self::Html, Self::from_accept))
+    }
+}
+
+struct MediaRange<'a> {
+    type_: &'a str,
+    subtype: &'a str,
+    quality: f32,
+}
+
+impl<'a> MediaRange<'a> {
+    fn parse(s: &'a str) -> Option<Self> {
+        let mut parts = s.split(';');
+        let (type_, subtype) = parts.next()?.trim().split_once('/')?;
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        Some(Self {
+            type_,
+            subtype,
+            quality,
+        })
+    }
+}
+
+/// The quality of the most specific range matching `type_/subtype`, 0 if none does.
+fn quality(ranges: &[MediaRange<'_>], type_: &str, subtype: &str) -> f32 {
+    let specificity = |r: &MediaRange<'_>| match (r.type_, r.subtype) {
+        (t, s) if t.eq_ignore_ascii_case(type_) && s.eq_ignore_ascii_case(subtype) => Some(2),
+        (t, "*") if t.eq_ignore_ascii_case(type_) => Some(1),
+        ("*", "*") => Some(0),
+        _ => None,
+    };
+    ranges
+        .iter()
+        .filter_map(|r| specificity(r).map(|s| (s, r.quality)))
+        .max_by_key(|(s, _)| *s)
+        .map_or(0.0, |(_, quality)| quality)
+}
+
+/// A problem with one of the fields submitted by a user. Problems with the
+/// request as a whole, e.g. a body that is not valid JSON, are reported on
+/// the `body` field.
+#[derive(Debug, serde::Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// The pages shown to subscribers along the double opt-in flow.
+pub enum Page<'a> {
+    CheckYourInbox {
+        email: &'a str,
+    },
+    InvalidDetails {
+        errors: &'a [FieldError],
+    },
+    /// Some details are missing, or could not be read at all.
+    MalformedDetails {
+        errors: &'a [FieldError],
+    },
+    TooManyAttempts,
+    ChallengeFailed,
+    Confirmed,
+    LinkExpired,
+    UnknownLink,
+    Error,
+}
+
+impl Page<'_> {
+    pub fn render(&self, format: ResponseFormat) -> Response {
+        match format {
+            ResponseFormat::Html => (self.status(), Html(self.html())).into_response(),
+            ResponseFormat::Json => {
+                let body = match self {
+                    Self::InvalidDetails { errors } | Self::MalformedDetails { errors } => {
+                        serde_json::json!({ "status": self.outcome(), "errors": errors })
+                    }
+                    _ => serde_json::json!({ "status": self.outcome() }),
+                };
+                (self.status(), Json(body)).into_response()
+            }
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::CheckYourInbox { .. } | Self::Confirmed => StatusCode::OK,
+            Self::InvalidDetails { .. } => StatusCode::BAD_REQUEST,
+            Self::MalformedDetails { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            Self::ChallengeFailed => StatusCode::FORBIDDEN,
+            Self::LinkExpired => StatusCode::GONE,
+            Self::UnknownLink => StatusCode::UNAUTHORIZED,
+            Self::Error => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What happened, for API clients.
+    fn outcome(&self) -> &'static str {
+        match self {
+            Self::CheckYourInbox { .. } => "pending_confirmation",
+            Self::InvalidDetails { .. } | Self::MalformedDetails { .. } => "invalid_details",
+            Self::TooManyAttempts => "too_many_attempts",
+            Self::ChallengeFailed => "challenge_failed",
+            Self::Confirmed => "confirmed",
+            Self::LinkExpired => "link_expired",
+            Self::UnknownLink => "unknown_link",
+            Self::Error => "error",
+        }
+    }
+
+    fn html(&self) -> String {
+        let (title, content) = match self {
+            Self::CheckYourInbox { email } => (
+                "Check your inbox",
+                include_str!("check_inbox.html")
+                    .replace("{{email}}", &htmlescape::encode_minimal(email)),
+            ),
+            Self::InvalidDetails { errors } | Self::MalformedDetails { errors } => {
+                let errors: Vec<_> = errors
+                    .iter()
+                    .map(|e| {
+                        format!(
+                            "        <li>{}</li>",
+                            htmlescape::encode_minimal(&e.message)
+                        )
+                    })
+                    .collect();
+                (
+                    "Invalid details",
+                    include_str!("invalid_details.html").replace("{{errors}}", &errors.join("\n")),
+                )
+            }
+            Self::TooManyAttempts => (
+                "Too many attempts",
+                include_str!("too_many_attempts.html").into(),
+            ),
+            Self::ChallengeFailed => (
+                "Verification failed",
+                include_str!("challenge_failed.html").into(),
+            ),
+            Self::Confirmed => ("Confirmed", include_str!("confirmed.html").into()),
+            Self::LinkExpired => ("Link expired", include_str!("link_expired.html").into()),
+            Self::UnknownLink => ("Unknown link", include_str!("unknown_link.html").into()),
+            Self::Error => ("Error", include_str!("error.html").into()),
+        };
+        include_str!("layout.html")
+            .replace("{{title}}", title)
+            .replace("{{content}}", content.trim_end())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseFormat;
+
+    #[test]
+    fn browsers_get_html() {
+        let accept = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(ResponseFormat::from_accept(accept), ResponseFormat::Html);
+    }
+
+    #[test]
+    fn api_clients_asking_for_json_get_json() {
+        assert_eq!(
+            ResponseFormat::from_accept("application/json"),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("text/html;q=0.5, application/json"),
+            ResponseFormat::Json
+        );
+    }
+
+    #[test]
+    fn wildcards_get_html() {
+        assert_eq!(ResponseFormat::from_accept("*/*"), ResponseFormat::Html);
+        assert_eq!(
+            ResponseFormat::from_accept("application/*, text/*"),
+            ResponseFormat::Html
+        );
+    }
+
+    #[test]
+    fn specific_ranges_take_precedence_over_wildcards() {
+        assert_eq!(
+            ResponseFormat::from_accept("*/*, text/html;q=0"),
+            ResponseFormat::Json
+        );
+    }
+}
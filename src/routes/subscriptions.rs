@@ -1,12 +1,15 @@
 //! src/routes/subscriptions.rs
 
+use std::{net::IpAddr, sync::Arc};
+
 use anyhow::Context;
 use axum::{
-    extract::{Form, State},
+    body::Body,
+    extract::{Form, FromRequest, FromRequestParts, Json, State},
+    http::{header, Request, StatusCode},
     response::{IntoResponse, Response},
 };
 use chrono::Utc;
-use hyper::StatusCode;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Deserialize;
 use sqlx::{Executor, Postgres, Transaction};
@@ -14,31 +17,135 @@ use uuid::Uuid;
 
 use crate::{
     application_state::ApplicationState,
-    domain::{NewSubscriber, SubscriberEmail, SubscriberName},
-    email_client::EmailClient,
+    client_ip::ClientIp,
+    domain::{EmailDomainBlocklist, NewSubscriber, SubscriberEmail, SubscriberName},
+    email_client::{EmailClient, EmailMessage},
+    routes::pages::{FieldError, Page, ResponseFormat},
+    startup::HmacSecret,
+    subscription_protection::{issue_challenge, SubscriptionProtection},
 };
 
 #[derive(Deserialize)]
 pub struct FormData {
-    email: String,
-    name: String,
+    /// Missing details are reported as field errors, like invalid ones.
+    email: Option<String>,
+    name: Option<String>,
+    /// A honeypot: the field is hidden from people, only bots fill it in.
+    #[serde(default)]
+    website: String,
+    /// The proof-of-work challenge and its solution, when one is asked for.
+    challenge: Option<String>,
+    nonce: Option<String>,
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+impl FormData {
+    /// Both fields are validated, so that every problem is reported at once.
+    fn parse(
+        self,
+        blocked_domains: &EmailDomainBlocklist,
+    ) -> Result<NewSubscriber, SubscribeError> {
+        let (name, email) = match (self.name, self.email) {
+            (Some(name), Some(email)) => (name, email),
+            (name, email) => {
+                let errors = [("name", name), ("email", email)]
+                    .into_iter()
+                    .filter(|(_, value)| value.is_none())
+                    .map(|(field, _)| FieldError {
+                        field,
+                        message: format!("The {} is missing.", field),
+                    })
+                    .collect();
+                return Err(SubscribeError::MalformedDetails(errors));
+            }
+        };
+
+        let name = SubscriberName::parse(name);
+        let email = SubscriberEmail::parse_allowed(email, blocked_domains);
+        match (name, email) {
+            (Ok(name), Ok(email)) => Ok(NewSubscriber { name, email }),
+            (name, email) => {
+                let errors = [("name", name.err()), ("email", email.err())]
+                    .into_iter()
+                    .filter_map(|(field, message)| {
+                        Some(FieldError {
+                            field,
+                            message: message?,
+                        })
+                    })
+                    .collect();
+                Err(SubscribeError::ValidationError(errors))
+            }
+        }
+    }
+}
+
+/// The subscription details, sent either as an HTML form or as JSON.
+pub struct SubscriptionRequest {
+    form: FormData,
+    is_json: bool,
+}
+
+#[axum::async_trait]
+impl<S> FromRequest<S, Body> for SubscriptionRequest
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        let (mut parts, body) = request.into_parts();
+        let format = if is_json {
+            ResponseFormat::Json
+        } else {
+            ResponseFormat::from_request_parts(&mut parts, state)
+                .await
+                .unwrap_or_else(|never| match never {})
+        };
+        let request = Request::from_parts(parts, body);
 
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        Ok(Self {
-            name: SubscriberName::parse(value.name)?,
-            email: SubscriberEmail::parse(value.email)?,
-        })
+        // Bodies that cannot be read get the same error page as invalid
+        // details, with the status code of the rejection.
+        let rejected = |status: StatusCode, message: String| {
+            let errors = [FieldError {
+                field: "body",
+                message,
+            }];
+            (
+                status,
+                Page::MalformedDetails { errors: &errors }.render(format),
+            )
+                .into_response()
+        };
+        let form = if is_json {
+            Json::<FormData>::from_request(request, state)
+                .await
+                .map(|Json(form)| form)
+                .map_err(|rejection| rejected(rejection.status(), rejection.body_text()))?
+        } else {
+            Form::<FormData>::from_request(request, state)
+                .await
+                .map(|Form(form)| form)
+                .map_err(|rejection| rejected(rejection.status(), rejection.body_text()))?
+        };
+        Ok(Self { form, is_json })
     }
 }
 
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
-    #[error("{0}")]
-    ValidationError(String),
+    #[error("The subscription details are invalid.")]
+    ValidationError(Vec<FieldError>),
+    #[error("Some subscription details are missing.")]
+    MalformedDetails(Vec<FieldError>),
+    #[error("Too many subscription attempts.")]
+    TooManyAttempts,
+    #[error("The proof-of-work challenge was not solved.")]
+    ChallengeFailed(#[source] anyhow::Error),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -49,13 +156,14 @@ impl std::fmt::Debug for SubscribeError {
     }
 }
 
-impl IntoResponse for SubscribeError {
-    fn into_response(self) -> axum::response::Response {
+impl SubscribeError {
+    fn page(&self) -> Page<'_> {
         match self {
-            Self::ValidationError(err) => (StatusCode::BAD_REQUEST, err).into_response(),
-            Self::UnexpectedError(_) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, "Some thing went wrong").into_response()
-            }
+            Self::ValidationError(errors) => Page::InvalidDetails { errors },
+            Self::MalformedDetails(errors) => Page::MalformedDetails { errors },
+            Self::TooManyAttempts => Page::TooManyAttempts,
+            Self::ChallengeFailed(_) => Page::ChallengeFailed,
+            Self::UnexpectedError(_) => Page::Error,
         }
     }
 }
@@ -63,17 +171,78 @@ impl IntoResponse for SubscribeError {
 #[allow(clippy::async_yields_async)]
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(app_state, form),
+    skip(format, app_state, request),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_email = ?request.form.email,
+        subscriber_name = ?request.form.name
     )
 )]
 pub async fn subscribe(
+    format: ResponseFormat,
+    ClientIp(client_ip): ClientIp,
     State(app_state): State<ApplicationState>,
-    Form(form): Form<FormData>,
-) -> Result<Response, SubscribeError> {
-    let new_subscriber = form.try_into().map_err(SubscribeError::ValidationError)?;
+    request: SubscriptionRequest,
+) -> Response {
+    // API clients posting JSON get JSON back, whatever they accept.
+    let format = if request.is_json {
+        ResponseFormat::Json
+    } else {
+        format
+    };
+
+    // Bots are told that everything went fine, so that they do not adapt.
+    if !request.form.website.is_empty() {
+        tracing::warn!(client = %client_ip, "The subscription honeypot was filled in");
+        return Page::CheckYourInbox {
+            email: request.form.email.as_deref().unwrap_or_default(),
+        }
+        .render(format);
+    }
+
+    match add_subscriber(app_state, client_ip, request.form).await {
+        Ok(new_subscriber) => Page::CheckYourInbox {
+            email: new_subscriber.email.as_ref(),
+        }
+        .render(format),
+        Err(e) => {
+            match e {
+                SubscribeError::UnexpectedError(_) => {
+                    tracing::error!(error.cause_chain = ?e, "Failed to add a new subscriber")
+                }
+                SubscribeError::TooManyAttempts | SubscribeError::ChallengeFailed(_) => {
+                    tracing::warn!(error.cause_chain = ?e, client = %client_ip, "Rejected a subscription")
+                }
+                SubscribeError::ValidationError(_) | SubscribeError::MalformedDetails(_) => {}
+            }
+            e.page().render(format)
+        }
+    }
+}
+
+async fn add_subscriber(
+    app_state: ApplicationState,
+    client_ip: IpAddr,
+    form: FormData,
+) -> Result<NewSubscriber, SubscribeError> {
+    let protection = app_state.subscription_protection;
+    if !protection.check_ip(client_ip) {
+        return Err(SubscribeError::TooManyAttempts);
+    }
+    if protection.proof_of_work_difficulty().is_some() {
+        let (Some(challenge), Some(nonce)) = (&form.challenge, &form.nonce) else {
+            return Err(SubscribeError::ChallengeFailed(anyhow::anyhow!(
+                "No solution to the proof-of-work challenge was sent."
+            )));
+        };
+        protection
+            .redeem_challenge(challenge, nonce, &app_state.hmac_secret.0)
+            .map_err(SubscribeError::ChallengeFailed)?;
+    }
+
+    let new_subscriber = form.parse(protection.blocked_domains())?;
+    if !protection.check_domain(new_subscriber.email.domain()) {
+        return Err(SubscribeError::TooManyAttempts);
+    }
 
     let pool = app_state.db_pool;
 
@@ -82,10 +251,17 @@ pub async fn subscribe(
         .await
         .context("Failed to acquire a Postgres connection from the pool")?;
 
-    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
+    let (subscriber_id, status) = insert_subscriber(&mut transaction, &new_subscriber)
         .await
         .context("Failed to insert new susbcriber in the database")?;
+    // Whoever subscribes again gets the same answer, whether or not they
+    // confirmed: telling them apart would reveal who reads the newsletter.
+    if status != "pending_confirmation" {
+        tracing::info!("The subscriber is already {}", status);
+        return Ok(new_subscriber);
+    }
 
+    // Subscribers who lost their link, or let it expire, get a new one.
     let subscription_token = generate_subscription_token();
     store_token(&mut transaction, subscriber_id, &subscription_token)
         .await
@@ -108,7 +284,18 @@ pub async fn subscribe(
     .await
     .context("Failed to send a confirmation email.")?;
 
-    Ok(StatusCode::OK.into_response())
+    Ok(new_subscriber)
+}
+
+/// Hands out a proof-of-work challenge to solve before subscribing.
+pub async fn subscription_challenge(
+    State(hmac_secret): State<HmacSecret>,
+    State(protection): State<Arc<SubscriptionProtection>>,
+) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "challenge": issue_challenge(&hmac_secret.0),
+        "difficulty": protection.proof_of_work_difficulty().unwrap_or(0),
+    }))
 }
 
 fn generate_subscription_token() -> String {
@@ -119,6 +306,8 @@ fn generate_subscription_token() -> String {
         .collect()
 }
 
+/// Saves a new subscriber, unless someone already subscribed with the same
+/// email address. Returns the id and status of the subscription either way.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database"
     skip(transaction, new_subscriber)
@@ -126,25 +315,40 @@ fn generate_subscription_token() -> String {
 pub async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
+) -> Result<(Uuid, String), sqlx::Error> {
     let query = sqlx::query!(
         r#"
         INSERT INTO subscriptions (id, email, name, subscribed_at, status)
         VALUES ($1, $2, $3, $4, 'pending_confirmation')
+        ON CONFLICT (email) DO NOTHING
         "#,
-        subscriber_id,
+        Uuid::new_v4(),
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
         Utc::now()
     );
-
     transaction.execute(query).await.map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
     })?;
 
-    Ok(subscriber_id)
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT id, status
+        FROM subscriptions
+        WHERE email = $1
+        FOR UPDATE
+        "#,
+        new_subscriber.email.as_ref(),
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok((subscriber.id, subscriber.status))
 }
 
 #[tracing::instrument(
@@ -171,9 +375,11 @@ pub async fn send_confirm_email(
         confirmation_link
     );
 
-    email_client
-        .send_email(&new_subscriber.email, "Welcome", &html_body, &plain_body)
-        .await
+    let message = EmailMessage::new(&new_subscriber.email, "Welcome", &html_body, &plain_body)
+        .message_stream(email_client.transactional_stream())
+        .tag("confirmation");
+    email_client.send(&message).await?;
+    Ok(())
 }
 
 #[tracing::instrument(
@@ -187,11 +393,12 @@ pub async fn store_token(
 ) -> Result<(), sqlx::Error> {
     let query = sqlx::query!(
         r#"
-        INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-        VALUES ($1, $2)
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, created_at)
+        VALUES ($1, $2, $3)
         "#,
         subscription_token,
-        subscriber_id
+        subscriber_id,
+        Utc::now()
     );
 
     transaction.execute(query).await.map_err(|e| {
@@ -3,14 +3,16 @@
 use anyhow::Context;
 use axum::{
     extract::{Query, State},
-    response::{IntoResponse, Response},
+    response::Response,
 };
-use hyper::StatusCode;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use sqlx::PgPool;
 use uuid::Uuid;
 
+use crate::configuration::NewsletterSettings;
 use crate::routes::error_chain_fmt;
+use crate::routes::pages::{Page, ResponseFormat};
 
 #[derive(Deserialize)]
 pub struct Parameters {
@@ -23,6 +25,8 @@ pub enum ConfirmationError {
     UnexpectedError(#[from] anyhow::Error),
     #[error("There is no subscriber associated with the provided token.")]
     UnknownToken,
+    #[error("The provided token has expired.")]
+    ExpiredToken,
 }
 
 impl std::fmt::Debug for ConfirmationError {
@@ -31,31 +35,62 @@ impl std::fmt::Debug for ConfirmationError {
     }
 }
 
-impl IntoResponse for ConfirmationError {
-    fn into_response(self) -> axum::response::Response {
+impl ConfirmationError {
+    fn page(&self) -> Page<'static> {
         match self {
-            Self::UnexpectedError(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
-            Self::UnknownToken => (StatusCode::UNAUTHORIZED).into_response(),
+            Self::UnexpectedError(_) => Page::Error,
+            Self::UnknownToken => Page::UnknownLink,
+            Self::ExpiredToken => Page::LinkExpired,
         }
     }
 }
 
 #[allow(clippy::async_yields_async)]
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool))]
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(format, parameters, pool, newsletter)
+)]
 pub async fn confirm(
+    format: ResponseFormat,
     parameters: Query<Parameters>,
     State(pool): State<PgPool>,
-) -> Result<Response, ConfirmationError> {
-    let subscriber_id = get_subscriber_id_from_token(&pool, &parameters.subscription_token)
+    State(newsletter): State<NewsletterSettings>,
+) -> Response {
+    let outcome = confirm_subscription(
+        &pool,
+        &parameters.subscription_token,
+        newsletter.confirmation_link_lifetime(),
+    )
+    .await;
+
+    match outcome {
+        Ok(()) => Page::Confirmed.render(format),
+        Err(e) => {
+            if let ConfirmationError::UnexpectedError(_) = e {
+                tracing::error!(error.cause_chain = ?e, "Failed to confirm a subscriber");
+            }
+            e.page().render(format)
+        }
+    }
+}
+
+async fn confirm_subscription(
+    pool: &PgPool,
+    subscription_token: &str,
+    lifetime: chrono::Duration,
+) -> Result<(), ConfirmationError> {
+    let (subscriber_id, created_at) = get_subscriber_id_from_token(pool, subscription_token)
         .await
         .context("Failed to retrieve the subcriber id associated with the provided token.")?
         .ok_or(ConfirmationError::UnknownToken)?;
+    if Utc::now() - created_at > lifetime {
+        return Err(ConfirmationError::ExpiredToken);
+    }
 
-    confirm_subscriber(&pool, subscriber_id)
+    confirm_subscriber(pool, subscriber_id)
         .await
         .context("Failed to update the subscriber status to `confirmed`.")?;
-
-    Ok(StatusCode::OK.into_response())
+    Ok(())
 }
 
 #[allow(clippy::async_yields_async)]
@@ -65,7 +100,7 @@ pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<()
         r#"
         UPDATE subscriptions
         SET status = 'confirmed'
-        WHERE id = $1
+        WHERE id = $1 AND status = 'pending_confirmation'
         "#,
         subscriber_id,
     )
@@ -78,14 +113,15 @@ pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<()
     Ok(())
 }
 
+/// The subscriber a token was issued to, and when it was issued.
 #[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_token, pool))]
 pub async fn get_subscriber_id_from_token(
     pool: &PgPool,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
+) -> Result<Option<(Uuid, DateTime<Utc>)>, sqlx::Error> {
     let result = sqlx::query!(
         r#"
-        SELECT subscriber_id
+        SELECT subscriber_id, created_at
         FROM subscription_tokens
         WHERE subscription_token = $1
         "#,
@@ -97,5 +133,5 @@ pub async fn get_subscriber_id_from_token(
         tracing::error!("Failed to execute query: {:?}", e);
         e
     })?;
-    Ok(result.map(|r| r.subscriber_id))
+    Ok(result.map(|r| (r.subscriber_id, r.created_at)))
 }
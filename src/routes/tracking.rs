@@ -0,0 +1,133 @@
+//! src/routes/tracking.rs
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use hyper::StatusCode;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    routes::error_chain_fmt,
+    startup::HmacSecret,
+    tracking::{TrackedEvent, TrackingToken},
+};
+
+/// A transparent 1x1 GIF.
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[derive(thiserror::Error)]
+pub enum TrackingError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+    #[error("The tracking token is invalid.")]
+    InvalidToken(#[source] anyhow::Error),
+}
+
+impl std::fmt::Debug for TrackingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for TrackingError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Self::InvalidToken(_) => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+}
+
+#[tracing::instrument(name = "Track a newsletter open", skip(token, pool, hmac_secret))]
+pub async fn track_open(
+    Path(token): Path<String>,
+    State(pool): State<PgPool>,
+    State(hmac_secret): State<HmacSecret>,
+) -> Result<Response, TrackingError> {
+    let token =
+        TrackingToken::decode(&token, &hmac_secret.0).map_err(TrackingError::InvalidToken)?;
+    if token.event != TrackedEvent::Open {
+        return Err(TrackingError::InvalidToken(anyhow::anyhow!(
+            "Not an open-tracking token."
+        )));
+    }
+
+    record_event(&pool, &token)
+        .await
+        .context("Failed to record a newsletter open.")?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "image/gif"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        PIXEL,
+    )
+        .into_response())
+}
+
+#[tracing::instrument(name = "Track a newsletter link click", skip(token, pool, hmac_secret))]
+pub async fn track_click(
+    Path(token): Path<String>,
+    State(pool): State<PgPool>,
+    State(hmac_secret): State<HmacSecret>,
+) -> Result<Response, TrackingError> {
+    let token =
+        TrackingToken::decode(&token, &hmac_secret.0).map_err(TrackingError::InvalidToken)?;
+    let TrackedEvent::Click(url) = &token.event else {
+        return Err(TrackingError::InvalidToken(anyhow::anyhow!(
+            "Not a click-tracking token."
+        )));
+    };
+
+    record_event(&pool, &token)
+        .await
+        .context("Failed to record a newsletter link click.")?;
+
+    Ok((StatusCode::FOUND, [(header::LOCATION, url.as_str())]).into_response())
+}
+
+/// Store a tracking event, unless tracking has since been disabled for the issue.
+#[tracing::instrument(name = "Save tracking event", skip(pool, token))]
+async fn record_event(pool: &PgPool, token: &TrackingToken) -> Result<(), sqlx::Error> {
+    let (kind, url) = match &token.event {
+        TrackedEvent::Open => ("open", None),
+        TrackedEvent::Click(url) => ("click", Some(url.as_str())),
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_tracking_events (
+            event_id,
+            newsletter_issue_id,
+            subscriber_id,
+            kind,
+            url,
+            occurred_at
+        )
+        SELECT $1, newsletter_issue_id, $3, $4, $5, $6
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $2 AND tracking_enabled
+        "#,
+        Uuid::new_v4(),
+        token.newsletter_issue_id,
+        token.subscriber_id,
+        kind,
+        url,
+        Utc::now()
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! src/security_headers.rs
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use cookie::{Cookie, SameSite};
+
+use crate::configuration::SecurityHeadersSettings;
+
+/// The headers added to every response, checked once at startup.
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    secure_cookies: bool,
+}
+
+impl SecurityHeaders {
+    pub fn new(settings: &SecurityHeadersSettings) -> Result<Self, anyhow::Error> {
+        let mut headers = vec![
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_str(&settings.content_security_policy)
+                    .context("Invalid Content-Security-Policy.")?,
+            ),
+            (
+                header::X_FRAME_OPTIONS,
+                HeaderValue::from_str(&settings.frame_options)
+                    .context("Invalid X-Frame-Options.")?,
+            ),
+            (
+                header::REFERRER_POLICY,
+                HeaderValue::from_str(&settings.referrer_policy)
+                    .context("Invalid Referrer-Policy.")?,
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+        ];
+        if let Some(max_age) = settings.hsts_max_age_seconds {
+            headers.push((
+                header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_str(&format!("max-age={}; includeSubDomains", max_age))
+                    .expect("HSTS headers are valid header values"),
+            ));
+        }
+        Ok(Self {
+            headers,
+            secure_cookies: settings.secure_cookies,
+        })
+    }
+
+    /// Adds the headers a response does not set itself, and hardens its cookies.
+    fn apply(&self, response_headers: &mut HeaderMap) {
+        for (name, value) in &self.headers {
+            if !response_headers.contains_key(name) {
+                response_headers.insert(name.clone(), value.clone());
+            }
+        }
+
+        let cookies: Vec<_> = response_headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|value| self.harden_cookie(value))
+            .collect();
+        response_headers.remove(header::SET_COOKIE);
+        for cookie in cookies {
+            response_headers.append(header::SET_COOKIE, cookie);
+        }
+    }
+
+    /// Makes a cookie `HttpOnly`, `Secure` if configured, and `SameSite=Lax`
+    /// unless it picked its own policy. Cookies that fail to parse are left as is.
+    fn harden_cookie(&self, value: &HeaderValue) -> HeaderValue {
+        let Some(mut cookie) = value
+            .to_str()
+            .ok()
+            .and_then(|value| Cookie::parse(value).ok())
+        else {
+            return value.clone();
+        };
+        cookie.set_http_only(true);
+        if self.secure_cookies {
+            cookie.set_secure(true);
+        }
+        if cookie.same_site().is_none() {
+            cookie.set_same_site(SameSite::Lax);
+        }
+        HeaderValue::from_str(&cookie.to_string()).unwrap_or_else(|_| value.clone())
+    }
+}
+
+pub async fn set_security_headers(
+    State(security_headers): State<Arc<SecurityHeaders>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let mut response = next.run(request).await;
+    security_headers.apply(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecurityHeaders;
+    use crate::configuration::SecurityHeadersSettings;
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    fn settings() -> SecurityHeadersSettings {
+        SecurityHeadersSettings {
+            content_security_policy: "default-src 'none'".into(),
+            frame_options: "DENY".into(),
+            referrer_policy: "no-referrer".into(),
+            hsts_max_age_seconds: Some(3600),
+            secure_cookies: true,
+        }
+    }
+
+    fn set_cookies(headers: &HeaderMap) -> Vec<&str> {
+        headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn every_header_is_added() {
+        let mut headers = HeaderMap::new();
+
+        SecurityHeaders::new(&settings())
+            .unwrap()
+            .apply(&mut headers);
+
+        assert_eq!(
+            headers[header::CONTENT_SECURITY_POLICY],
+            "default-src 'none'"
+        );
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[header::REFERRER_POLICY], "no-referrer");
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(
+            headers[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=3600; includeSubDomains"
+        );
+    }
+
+    #[test]
+    fn there_is_no_hsts_without_a_max_age() {
+        let mut settings = settings();
+        settings.hsts_max_age_seconds = None;
+        let mut headers = HeaderMap::new();
+
+        SecurityHeaders::new(&settings).unwrap().apply(&mut headers);
+
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+    }
+
+    #[test]
+    fn headers_set_by_a_route_are_kept() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::X_FRAME_OPTIONS,
+            HeaderValue::from_static("SAMEORIGIN"),
+        );
+
+        SecurityHeaders::new(&settings())
+            .unwrap()
+            .apply(&mut headers);
+
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+    }
+
+    #[test]
+    fn cookies_are_hardened() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("_flash=Hello"));
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("_csrf=abc; SameSite=Strict; Path=/"),
+        );
+
+        SecurityHeaders::new(&settings())
+            .unwrap()
+            .apply(&mut headers);
+
+        let cookies = set_cookies(&headers);
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies[0].starts_with("_flash=Hello"));
+        assert!(cookies[0].contains("HttpOnly"));
+        assert!(cookies[0].contains("Secure"));
+        assert!(cookies[0].contains("SameSite=Lax"));
+        assert!(cookies[1].contains("SameSite=Strict"));
+        assert!(!cookies[1].contains("SameSite=Lax"));
+    }
+
+    #[test]
+    fn cookies_are_not_secure_unless_configured() {
+        let mut settings = settings();
+        settings.secure_cookies = false;
+        let mut headers = HeaderMap::new();
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("_flash=Hello"));
+
+        SecurityHeaders::new(&settings).unwrap().apply(&mut headers);
+
+        assert!(!set_cookies(&headers)[0].contains("Secure"));
+    }
+
+    #[test]
+    fn invalid_header_values_are_rejected() {
+        let mut settings = settings();
+        settings.referrer_policy = "no-referrer\n".into();
+
+        assert!(SecurityHeaders::new(&settings).is_err());
+    }
+}
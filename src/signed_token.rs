@@ -0,0 +1,120 @@
+//! src/signed_token.rs
+//!
+//! Tokens the application hands out and takes back without storing them: a
+//! payload signed with the HMAC secret, as `{base64 payload}.{hex signature}`.
+//! What a token is for is signed along with its payload, so that a token
+//! issued for one use is never accepted for another.
+
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    /// Links to follow opens and clicks in newsletter issues.
+    Tracking,
+    /// Links to accept an invitation.
+    Invitation,
+    /// Tokens in forms, checked against the CSRF cookie.
+    Csrf,
+    /// Proof-of-work challenges for the subscription form.
+    SubscriptionChallenge,
+}
+
+impl Purpose {
+    fn prefix(self) -> &'static [u8] {
+        match self {
+            Self::Tracking => b"tracking:",
+            Self::Invitation => b"invitation:",
+            Self::Csrf => b"csrf:",
+            Self::SubscriptionChallenge => b"subscription-challenge:",
+        }
+    }
+}
+
+pub fn sign(purpose: Purpose, payload: &[u8], secret: &Secret<String>) -> String {
+    let tag = hex::encode(mac(purpose, payload, secret).finalize().into_bytes());
+    format!("{}.{}", URL_SAFE_NO_PAD.encode(payload), tag)
+}
+
+/// Returns the payload of a token signed for this purpose.
+pub fn verify(
+    purpose: Purpose,
+    token: &str,
+    secret: &Secret<String>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let (payload, tag) = token
+        .split_once('.')
+        .context("The token is missing its signature.")?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .context("Failed to base64-decode the token.")?;
+    let tag = hex::decode(tag).context("Failed to hex-decode the token signature.")?;
+    mac(purpose, &payload, secret)
+        .verify_slice(&tag)
+        .context("The token signature is invalid.")?;
+    Ok(payload)
+}
+
+fn mac(purpose: Purpose, payload: &[u8], secret: &Secret<String>) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(purpose.prefix());
+    mac.update(payload);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, verify, Purpose};
+    use claims::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+
+    fn secret() -> Secret<String> {
+        Secret::new("a-signing-secret".to_string())
+    }
+
+    #[test]
+    fn a_signed_payload_is_verified() {
+        let token = sign(Purpose::Tracking, b"a payload", &secret());
+        assert_ok_eq!(
+            verify(Purpose::Tracking, &token, &secret()),
+            b"a payload".to_vec()
+        );
+    }
+
+    #[test]
+    fn a_token_signed_for_another_purpose_is_rejected() {
+        let token = sign(Purpose::Tracking, b"a payload", &secret());
+        assert_err!(verify(Purpose::Invitation, &token, &secret()));
+    }
+
+    #[test]
+    fn a_token_signed_with_another_secret_is_rejected() {
+        let token = sign(
+            Purpose::Csrf,
+            b"a payload",
+            &Secret::new("another-secret".to_string()),
+        );
+        assert_err!(verify(Purpose::Csrf, &token, &secret()));
+    }
+
+    #[test]
+    fn a_tampered_payload_is_rejected() {
+        let token = sign(Purpose::Csrf, b"a payload", &secret());
+        let other_token = sign(Purpose::Csrf, b"another payload", &secret());
+        let (_, tag) = token.split_once('.').unwrap();
+        let (other_payload, _) = other_token.split_once('.').unwrap();
+        let forged = format!("{}.{}", other_payload, tag);
+        assert_err!(verify(Purpose::Csrf, &forged, &secret()));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        assert_err!(verify(Purpose::Csrf, "", &secret()));
+        assert_err!(verify(Purpose::Csrf, "no-signature", &secret()));
+        assert_err!(verify(Purpose::Csrf, "cGF5bG9hZA.not-hex", &secret()));
+    }
+}
@@ -1,14 +1,23 @@
 //! src/startup.rs
 
-use std::{net::TcpListener, sync::Arc};
+use std::{
+    future::Future,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use axum::{
-    routing::{get, post, IntoMakeService},
+    extract::{connect_info::IntoMakeServiceWithConnectInfo, DefaultBodyLimit},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
+use axum_extra::extract::cookie::Key;
 use hyper::server::conn::AddrIncoming;
-use secrecy::Secret;
+use secrecy::{ExposeSecret, Secret};
 use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::{sync::oneshot, time::Instant};
 use tower::ServiceBuilder;
 
 use tower_http::{
@@ -18,35 +27,80 @@ use tower_http::{
 };
 
 use crate::{
-    application_state::ApplicationState,
+    application_state::{ApplicationState, BaseUrlState, EmailClientState, OidcClientState},
+    authentication::PasswordHashing,
+    background_tasks::BackgroundTasks,
+    client_ip::{resolve_client_ip, TrustedProxies},
     configuration::{DatabaseSettings, Settings},
+    csrf::require_csrf_token,
     email_client::EmailClient,
-    routes::{confirm, health_check, home, login, login_form, publish_newsletter, subscribe},
+    login_throttle::LoginThrottle,
+    metrics::{record_http_metrics, Metrics},
+    oidc::OidcClient,
+    routes::{
+        accept_invitation, confirm, confirm_two_factor_enrollment, create_api_token, email_webhook,
+        export_metrics, failed_newsletter_issue_deliveries, forgot_password_form, health_check,
+        home, invitation_form, invite_user, list_api_tokens, list_auth_events, list_invitations,
+        list_subscribers, liveness, login, login_form, newsletter_issue_deliveries,
+        newsletter_issue_stats, oidc_callback, oidc_login, password_reset_form, preview_newsletter,
+        publish_newsletter, readiness, request_password_reset, reset_password, revoke_api_token,
+        revoke_invitation, set_user_role, start_two_factor_enrollment, subscribe,
+        subscription_challenge, track_click, track_open, two_factor_form, verify_two_factor,
+        MAX_PUBLISH_REQUEST_SIZE,
+    },
+    security_headers::{set_security_headers, SecurityHeaders},
+    subscription_protection::SubscriptionProtection,
 };
 
 use tracing::Level;
 use uuid::Uuid;
 
-type AppServer = axum::Server<AddrIncoming, IntoMakeService<Router>>;
+type AppServer = axum::Server<AddrIncoming, IntoMakeServiceWithConnectInfo<Router, SocketAddr>>;
 pub struct Application {
     port: u16,
     server: AppServer,
+    db_pool: PgPool,
+    background_tasks: BackgroundTasks,
+    shutdown_deadline: Duration,
 }
 
 impl Application {
     pub async fn build(configuration: Settings) -> hyper::Result<Self> {
         let connection_pool = get_connection_pool(&configuration.database);
-        let sender_email = configuration
+        let sender_identities = configuration
             .email_client
-            .sender()
-            .expect("Invalid sender email address");
+            .sender_identities()
+            .expect("Invalid sender identity");
+        let default_sender = sender_identities
+            .get(&configuration.email_client.default_sender)
+            .cloned()
+            .expect("The default sender is not a configured sender identity");
+        if let Some(sender) = &configuration.newsletter.sender {
+            assert!(
+                sender_identities.contains_key(sender),
+                "The newsletter sender is not a configured sender identity"
+            );
+        }
+        let password_hashing = PasswordHashing::new(configuration.password_hashing)
+            .expect("Invalid password hashing parameters");
+        let metrics = Arc::new(Metrics::new());
         let timeout = configuration.email_client.timeout();
         let email_client = EmailClient::new(
             configuration.email_client.base_url,
-            sender_email,
+            default_sender,
             configuration.email_client.authorization_token,
             timeout,
-        );
+        )
+        .with_sender_identities(sender_identities)
+        .with_rate_limit(
+            configuration.email_client.max_concurrent_requests,
+            configuration.email_client.max_requests_per_second,
+        )
+        .with_message_streams(
+            configuration.email_client.transactional_message_stream,
+            configuration.email_client.broadcast_message_stream,
+        )
+        .with_metrics(metrics.clone());
 
         let address = format!(
             "{}:{}",
@@ -54,23 +108,143 @@ impl Application {
         );
         let listener = TcpListener::bind(address).expect("Failed to bind address");
         let port = listener.local_addr().unwrap().port();
-        let server = run(
-            listener,
-            connection_pool,
-            email_client,
-            configuration.application.base_url,
-            configuration.application.hmac_secret,
-        )?;
-
-        Ok(Self { port, server })
+        let cookie_key = Key::derive_from(
+            configuration
+                .application
+                .hmac_secret
+                .expose_secret()
+                .as_bytes(),
+        );
+        let shutdown_deadline = configuration.application.shutdown_deadline();
+        let trusted_proxies = TrustedProxies::parse(&configuration.application.trusted_proxies)
+            .expect("Invalid trusted proxies");
+        let oidc = configuration.application.oidc.map(|settings| {
+            Arc::new(OidcClient::new(
+                settings,
+                &configuration.application.base_url,
+            ))
+        });
+        let background_tasks = BackgroundTasks::default();
+        let app_state = ApplicationState {
+            db_pool: connection_pool.clone(),
+            email_client: EmailClientState::new(Arc::new(email_client)),
+            base_url: BaseUrlState::new(Arc::new(ApplicationBaseUrl(
+                configuration.application.base_url,
+            ))),
+            hmac_secret: HmacSecret(configuration.application.hmac_secret),
+            newsletter: configuration.newsletter,
+            webhook_secret: WebhookSecret(configuration.email_client.webhook_secret),
+            metrics_token: MetricsToken(configuration.application.metrics_token),
+            subscription_protection: Arc::new(SubscriptionProtection::new(
+                &configuration.subscriptions,
+            )),
+            login_throttle: LoginThrottle::new(configuration.login_throttle),
+            password_hashing,
+            cookie_key,
+            oidc: OidcClientState(oidc),
+            health_checks: configuration.health_checks,
+            metrics,
+            background_tasks: background_tasks.clone(),
+        };
+        let security_headers = SecurityHeaders::new(&configuration.security_headers)
+            .expect("Invalid security headers");
+        let server = run(listener, app_state, security_headers, trusted_proxies)?;
+
+        Ok(Self {
+            port,
+            server,
+            db_pool: connection_pool,
+            background_tasks,
+            shutdown_deadline,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// Serves requests until the process is asked to stop, with SIGTERM or Ctrl+C.
     pub async fn run_until_stopped(self) -> hyper::Result<()> {
-        self.server.await
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Serves requests until `signal` completes. The server then stops
+    /// accepting connections and lets the requests in flight, newsletter
+    /// deliveries included, and the background tasks they started complete
+    /// before closing the connection pool. Whatever is still running once the
+    /// shutdown deadline has passed is abandoned.
+    pub async fn run_until(self, signal: impl Future<Output = ()>) -> hyper::Result<()> {
+        let shutdown_deadline = self.shutdown_deadline;
+        // Set once, when the signal arrives, for the requests and the pool alike.
+        let deadline = Arc::new(OnceLock::new());
+        let (deadline_sender, deadline_receiver) = oneshot::channel();
+        let server = self.server.with_graceful_shutdown({
+            let deadline = deadline.clone();
+            async move {
+                signal.await;
+                tracing::info!("Shutting down: waiting for the requests in flight to complete");
+                let at = *deadline.get_or_init(|| Instant::now() + shutdown_deadline);
+                let _ = deadline_sender.send(at);
+            }
+        });
+        let deadline_passed = async {
+            match deadline_receiver.await {
+                Ok(deadline) => tokio::time::sleep_until(deadline).await,
+                // The server stopped on its own.
+                Err(_) => std::future::pending().await,
+            }
+        };
+
+        let outcome = tokio::select! {
+            outcome = server => outcome,
+            _ = deadline_passed => {
+                tracing::warn!("Shutting down: the deadline passed before every request completed");
+                Ok(())
+            }
+        };
+        let deadline = *deadline.get_or_init(|| Instant::now() + shutdown_deadline);
+        if tokio::time::timeout_at(deadline, self.background_tasks.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "Shutting down: the deadline passed before every background task completed"
+            );
+        }
+        // Abandoned requests may never give their connection back: only wait
+        // for them until the deadline, after closing the idle ones.
+        if tokio::time::timeout_at(deadline, self.db_pool.close())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Shutting down: some database connections were still in use");
+        }
+        tracing::info!("Shut down");
+        outcome
+    }
+}
+
+/// Completes when the process is asked to stop: with SIGTERM, as container
+/// runtimes do, or with Ctrl+C.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
 }
 
@@ -96,27 +270,110 @@ pub struct ApplicationBaseUrl(pub String);
 
 fn run(
     listener: TcpListener,
-    db_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
-    hmac_secret: Secret<String>,
+    app_state: ApplicationState,
+    security_headers: SecurityHeaders,
+    trusted_proxies: TrustedProxies,
 ) -> hyper::Result<AppServer> {
-    let app_state = ApplicationState::new(
-        db_pool,
-        Arc::new(email_client),
-        Arc::new(ApplicationBaseUrl(base_url)),
-        HmacSecret(hmac_secret),
-    );
+    // The pages browsers post forms from, which are all protected against
+    // cross-site request forgery.
+    let forms = Router::new()
+        .route("/login", get(login_form).post(login))
+        .route(
+            "/login/forgot-password",
+            get(forgot_password_form).post(request_password_reset),
+        )
+        .route(
+            "/login/reset-password",
+            get(password_reset_form).post(reset_password),
+        )
+        .route(
+            "/login/two-factor",
+            get(two_factor_form).post(verify_two_factor),
+        )
+        .route(
+            "/invitations/accept",
+            get(invitation_form).post(accept_invitation),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            app_state.hmac_secret.clone(),
+            require_csrf_token,
+        ));
 
     Ok(axum::Server::from_tcp(listener)?.serve(
         axum::Router::new()
             .route("/health_check", get(health_check))
+            .route("/health/live", get(liveness))
+            .route("/health/ready", get(readiness))
+            .route("/metrics", get(export_metrics))
+            // Left out of the CSRF protected forms on purpose: subscribing
+            // acts on no account, a forged request can only get an email
+            // confirmation link sent, and it is posted from the static home
+            // page and from scripts, which have no token to send.
             .route("/subscriptions", post(subscribe))
+            .route("/subscriptions/challenge", get(subscription_challenge))
             .route("/subscriptions/confirm", get(confirm))
-            .route("/newsletters", post(publish_newsletter))
+            .route(
+                "/newsletters",
+                post(publish_newsletter).layer(DefaultBodyLimit::max(MAX_PUBLISH_REQUEST_SIZE)),
+            )
+            .route(
+                "/newsletters/preview",
+                post(preview_newsletter).layer(DefaultBodyLimit::max(MAX_PUBLISH_REQUEST_SIZE)),
+            )
+            .route(
+                "/admin/newsletters/:newsletter_issue_id/stats",
+                get(newsletter_issue_stats),
+            )
+            .route(
+                "/admin/newsletters/:newsletter_issue_id/deliveries",
+                get(newsletter_issue_deliveries),
+            )
+            .route(
+                "/admin/newsletters/:newsletter_issue_id/deliveries/failed",
+                get(failed_newsletter_issue_deliveries),
+            )
+            .route("/webhooks/email", post(email_webhook))
+            .route("/t/open/:token", get(track_open))
+            .route("/t/:token", get(track_click))
             .route("/", get(home))
-            .route("/login", get(login_form).post(login))
+            .route("/admin/subscribers", get(list_subscribers))
+            .route(
+                "/admin/api-tokens",
+                get(list_api_tokens).post(create_api_token),
+            )
+            .route("/admin/api-tokens/:token_id", delete(revoke_api_token))
+            .route("/admin/users/:username/role", put(set_user_role))
+            .route("/admin/auth-events", get(list_auth_events))
+            .route(
+                "/admin/invitations",
+                get(list_invitations).post(invite_user),
+            )
+            .route(
+                "/admin/invitations/:invitation_id",
+                delete(revoke_invitation),
+            )
+            .route("/admin/two-factor", post(start_two_factor_enrollment))
+            .route(
+                "/admin/two-factor/confirm",
+                post(confirm_two_factor_enrollment),
+            )
+            .route("/login/oidc", get(oidc_login))
+            .route("/login/oidc/callback", get(oidc_callback))
+            .merge(forms)
+            // Only matched routes are recorded, labelled with their route.
+            .route_layer(middleware::from_fn_with_state(
+                app_state.metrics.clone(),
+                record_http_metrics,
+            ))
             .with_state(app_state)
+            .layer(middleware::from_fn_with_state(
+                Arc::new(security_headers),
+                set_security_headers,
+            ))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(trusted_proxies),
+                resolve_client_ip,
+            ))
             .layer(
                 ServiceBuilder::new()
                     .set_x_request_id(MakeRequestUuid)
@@ -131,9 +388,16 @@ fn run(
                     )
                     .propagate_x_request_id(),
             )
-            .into_make_service(),
+            // The client address is used to rate limit subscriptions and logins.
+            .into_make_service_with_connect_info::<SocketAddr>(),
     ))
 }
 
 #[derive(Clone)]
 pub struct HmacSecret(pub Secret<String>);
+
+#[derive(Clone)]
+pub struct WebhookSecret(pub Secret<String>);
+
+#[derive(Clone)]
+pub struct MetricsToken(pub Secret<String>);
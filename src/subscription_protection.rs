@@ -0,0 +1,213 @@
+//! src/subscription_protection.rs
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+use secrecy::Secret;
+use sha2::{Digest, Sha256};
+
+use crate::client_ip::network_key;
+use crate::configuration::SubscriptionSettings;
+use crate::domain::EmailDomainBlocklist;
+use crate::rate_limit::RateLimiter;
+use crate::signed_token::{self, Purpose};
+
+/// How long a proof-of-work challenge can be solved for.
+const CHALLENGE_LIFETIME_SECONDS: i64 = 10 * 60;
+
+/// The layers protecting the subscription form, which sends an email to any
+/// address posted, from being abused to spam third parties.
+pub struct SubscriptionProtection {
+    attempts_per_ip: RateLimiter,
+    subscriptions_per_domain: RateLimiter,
+    proof_of_work_difficulty: Option<u8>,
+    blocked_domains: EmailDomainBlocklist,
+    /// Solved challenges, with when they expire, so that a solution is only used once.
+    redeemed_challenges: Mutex<HashMap<String, i64>>,
+}
+
+impl SubscriptionProtection {
+    pub fn new(settings: &SubscriptionSettings) -> Self {
+        let hour = Duration::from_secs(60 * 60);
+        Self {
+            attempts_per_ip: RateLimiter::new(settings.max_attempts_per_ip_per_hour, hour),
+            subscriptions_per_domain: RateLimiter::new(
+                settings.max_subscriptions_per_domain_per_hour,
+                hour,
+            ),
+            proof_of_work_difficulty: settings.proof_of_work_difficulty,
+            blocked_domains: EmailDomainBlocklist::new(settings.blocked_email_domains.clone()),
+            redeemed_challenges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a subscription attempt, returning `false` if the IP, or its
+    /// IPv6 network, made too many.
+    pub fn check_ip(&self, ip: IpAddr) -> bool {
+        self.attempts_per_ip.check(&network_key(ip))
+    }
+
+    /// Records a subscription, returning `false` if the domain got too many.
+    pub fn check_domain(&self, domain: &str) -> bool {
+        self.subscriptions_per_domain.check(&domain.to_lowercase())
+    }
+
+    pub fn blocked_domains(&self) -> &EmailDomainBlocklist {
+        &self.blocked_domains
+    }
+
+    /// Leading zero bits asked from proof-of-work solutions, `None` if disabled.
+    pub fn proof_of_work_difficulty(&self) -> Option<u8> {
+        self.proof_of_work_difficulty
+    }
+
+    /// Checks a proof-of-work solution, which is accepted only once.
+    pub fn redeem_challenge(
+        &self,
+        challenge: &str,
+        nonce: &str,
+        secret: &Secret<String>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(difficulty) = self.proof_of_work_difficulty else {
+            return Ok(());
+        };
+        let expires_at = verify_solution(challenge, nonce, difficulty, secret)?;
+
+        let now = Utc::now().timestamp();
+        let mut redeemed = self.redeemed_challenges.lock().unwrap();
+        redeemed.retain(|_, expires_at| *expires_at > now);
+        if redeemed.insert(challenge.to_owned(), expires_at).is_some() {
+            anyhow::bail!("The challenge has already been used.");
+        }
+        Ok(())
+    }
+}
+
+/// A proof-of-work challenge: clients look for a nonce such that the SHA-256
+/// of `{challenge}:{nonce}` starts with as many zero bits as the difficulty.
+///
+/// Challenges are signed with the application HMAC secret and carry when they
+/// were issued, so that they do not need to be stored.
+pub fn issue_challenge(secret: &Secret<String>) -> String {
+    let random: [u8; 16] = thread_rng().gen();
+    let payload = format!("{}:{}", Utc::now().timestamp(), hex::encode(random));
+    signed_token::sign(Purpose::SubscriptionChallenge, payload.as_bytes(), secret)
+}
+
+/// Returns when the challenge expires if the solution is valid.
+fn verify_solution(
+    challenge: &str,
+    nonce: &str,
+    difficulty: u8,
+    secret: &Secret<String>,
+) -> Result<i64, anyhow::Error> {
+    let payload = signed_token::verify(Purpose::SubscriptionChallenge, challenge, secret)
+        .context("Invalid challenge.")?;
+    let payload = String::from_utf8(payload).context("The challenge is not valid UTF8.")?;
+    let issued_at: i64 = payload
+        .split_once(':')
+        .context("The challenge is missing its issue time.")?
+        .0
+        .parse()
+        .context("Invalid challenge issue time.")?;
+    let expires_at = issued_at + CHALLENGE_LIFETIME_SECONDS;
+    if expires_at <= Utc::now().timestamp() {
+        anyhow::bail!("The challenge has expired.");
+    }
+
+    let hash = Sha256::digest(format!("{}:{}", challenge, nonce));
+    if leading_zero_bits(&hash) < u32::from(difficulty) {
+        anyhow::bail!("The solution does not meet the difficulty.");
+    }
+    Ok(expires_at)
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{issue_challenge, leading_zero_bits, SubscriptionProtection};
+    use crate::configuration::SubscriptionSettings;
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use sha2::{Digest, Sha256};
+
+    fn secret() -> Secret<String> {
+        Secret::new("a-challenge-secret".to_string())
+    }
+
+    /// Looks for a solution to a challenge, the way clients are expected to.
+    fn solve_challenge(challenge: &str, difficulty: u8) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| {
+                let hash = Sha256::digest(format!("{}:{}", challenge, nonce));
+                leading_zero_bits(&hash) >= u32::from(difficulty)
+            })
+            .expect("Ran out of nonces")
+    }
+
+    fn protection(difficulty: u8) -> SubscriptionProtection {
+        SubscriptionProtection::new(&SubscriptionSettings {
+            max_attempts_per_ip_per_hour: 10,
+            max_subscriptions_per_domain_per_hour: 10,
+            proof_of_work_difficulty: Some(difficulty),
+            blocked_email_domains: vec![],
+        })
+    }
+
+    #[test]
+    fn leading_zero_bits_are_counted_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn a_solved_challenge_is_accepted_once() {
+        let protection = protection(8);
+        let challenge = issue_challenge(&secret());
+        let nonce = solve_challenge(&challenge, 8);
+
+        assert_ok!(protection.redeem_challenge(&challenge, &nonce, &secret()));
+        assert_err!(protection.redeem_challenge(&challenge, &nonce, &secret()));
+    }
+
+    #[test]
+    fn a_wrong_solution_is_rejected() {
+        let protection = protection(8);
+        let challenge = issue_challenge(&secret());
+        let wrong_nonce = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| {
+                let hash = Sha256::digest(format!("{}:{}", challenge, nonce));
+                leading_zero_bits(&hash) < 8
+            })
+            .unwrap();
+
+        assert_err!(protection.redeem_challenge(&challenge, &wrong_nonce, &secret()));
+    }
+
+    #[test]
+    fn a_challenge_signed_with_another_secret_is_rejected() {
+        let protection = protection(1);
+        let challenge = issue_challenge(&Secret::new("another-secret".to_string()));
+        let nonce = solve_challenge(&challenge, 1);
+
+        assert_err!(protection.redeem_challenge(&challenge, &nonce, &secret()));
+    }
+}
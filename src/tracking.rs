@@ -0,0 +1,260 @@
+//! src/tracking.rs
+
+use anyhow::Context;
+use linkify::{LinkFinder, LinkKind};
+use secrecy::Secret;
+use uuid::Uuid;
+
+use crate::signed_token::{self, Purpose};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrackedEvent {
+    Open,
+    Click(String),
+}
+
+/// The payload embedded in tracking links: who received which issue and,
+/// for clicks, where the link was pointing to.
+///
+/// Tokens are signed with the application HMAC secret, so that the click
+/// endpoint cannot be abused as an open redirect and opens cannot be forged.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TrackingToken {
+    pub newsletter_issue_id: Uuid,
+    pub subscriber_id: Uuid,
+    pub event: TrackedEvent,
+}
+
+impl TrackingToken {
+    pub fn encode(&self, secret: &Secret<String>) -> String {
+        let payload = match &self.event {
+            TrackedEvent::Open => format!("o:{}:{}", self.newsletter_issue_id, self.subscriber_id),
+            TrackedEvent::Click(url) => format!(
+                "c:{}:{}:{}",
+                self.newsletter_issue_id, self.subscriber_id, url
+            ),
+        };
+        signed_token::sign(Purpose::Tracking, payload.as_bytes(), secret)
+    }
+
+    pub fn decode(token: &str, secret: &Secret<String>) -> Result<Self, anyhow::Error> {
+        let payload = signed_token::verify(Purpose::Tracking, token, secret)
+            .context("Invalid tracking token.")?;
+        let payload =
+            String::from_utf8(payload).context("The tracking token is not valid UTF8.")?;
+        let mut segments = payload.splitn(4, ':');
+        let kind = segments.next().context("Missing event kind.")?;
+        let newsletter_issue_id = segments
+            .next()
+            .context("Missing newsletter issue id.")?
+            .parse()
+            .context("Invalid newsletter issue id.")?;
+        let subscriber_id = segments
+            .next()
+            .context("Missing subscriber id.")?
+            .parse()
+            .context("Invalid subscriber id.")?;
+        let event = match (kind, segments.next()) {
+            ("o", None) => TrackedEvent::Open,
+            ("c", Some(url)) => TrackedEvent::Click(url.to_string()),
+            _ => anyhow::bail!("Unknown tracking event."),
+        };
+
+        Ok(Self {
+            newsletter_issue_id,
+            subscriber_id,
+            event,
+        })
+    }
+}
+
+/// Rewrites the content of a newsletter issue for a single recipient:
+/// links go through `/t/{token}` and the HTML body gets an open-tracking pixel.
+pub struct Tracker<'a> {
+    pub base_url: &'a str,
+    pub hmac_secret: &'a Secret<String>,
+    pub newsletter_issue_id: Uuid,
+    pub subscriber_id: Uuid,
+}
+
+impl Tracker<'_> {
+    fn token(&self, event: TrackedEvent) -> String {
+        TrackingToken {
+            newsletter_issue_id: self.newsletter_issue_id,
+            subscriber_id: self.subscriber_id,
+            event,
+        }
+        .encode(self.hmac_secret)
+    }
+
+    pub fn open_url(&self) -> String {
+        format!(
+            "{}/t/open/{}",
+            self.base_url,
+            self.token(TrackedEvent::Open)
+        )
+    }
+
+    pub fn click_url(&self, url: &str) -> String {
+        format!(
+            "{}/t/{}",
+            self.base_url,
+            self.token(TrackedEvent::Click(url.to_string()))
+        )
+    }
+
+    pub fn rewrite_html(&self, html: &str) -> String {
+        const HREF: &str = "href=\"";
+
+        let mut output = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find(HREF) {
+            let value_start = start + HREF.len();
+            output.push_str(&rest[..value_start]);
+            rest = &rest[value_start..];
+
+            let Some(end) = rest.find('"') else {
+                break;
+            };
+            let href = &rest[..end];
+            if is_trackable(href) {
+                let url = htmlescape::decode_html(href).unwrap_or_else(|_| href.to_string());
+                output.push_str(&self.click_url(&url));
+            } else {
+                output.push_str(href);
+            }
+            rest = &rest[end..];
+        }
+        output.push_str(rest);
+
+        let pixel = format!(
+            r#"<img src="{}" width="1" height="1" alt="" />"#,
+            self.open_url()
+        );
+        match output.rfind("</body>") {
+            Some(index) => output.insert_str(index, &pixel),
+            None => output.push_str(&pixel),
+        }
+        output
+    }
+
+    pub fn rewrite_text(&self, text: &str) -> String {
+        let mut finder = LinkFinder::new();
+        finder.kinds(&[LinkKind::Url]);
+
+        finder
+            .spans(text)
+            .map(|span| match span.kind() {
+                Some(LinkKind::Url) if is_trackable(span.as_str()) => self.click_url(span.as_str()),
+                _ => span.as_str().to_string(),
+            })
+            .collect()
+    }
+}
+
+fn is_trackable(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TrackedEvent, Tracker, TrackingToken};
+    use claims::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn secret() -> Secret<String> {
+        Secret::new("a-tracking-secret".to_string())
+    }
+
+    fn tracker(secret: &Secret<String>) -> Tracker<'_> {
+        Tracker {
+            base_url: "http://127.0.0.1",
+            hmac_secret: secret,
+            newsletter_issue_id: Uuid::new_v4(),
+            subscriber_id: Uuid::new_v4(),
+        }
+    }
+
+    fn token_of(url: &str) -> &str {
+        url.rsplit('/').next().unwrap()
+    }
+
+    #[test]
+    fn a_token_round_trips() {
+        let token = TrackingToken {
+            newsletter_issue_id: Uuid::new_v4(),
+            subscriber_id: Uuid::new_v4(),
+            event: TrackedEvent::Click("https://example.com/a:b?c=d".into()),
+        };
+        let encoded = token.encode(&secret());
+
+        assert_ok_eq!(TrackingToken::decode(&encoded, &secret()), token);
+    }
+
+    #[test]
+    fn a_token_signed_with_another_secret_is_rejected() {
+        let token = TrackingToken {
+            newsletter_issue_id: Uuid::new_v4(),
+            subscriber_id: Uuid::new_v4(),
+            event: TrackedEvent::Open,
+        };
+        let encoded = token.encode(&Secret::new("another-secret".to_string()));
+
+        assert_err!(TrackingToken::decode(&encoded, &secret()));
+    }
+
+    #[test]
+    fn a_tampered_token_is_rejected() {
+        let secret = secret();
+        let url = tracker(&secret).click_url("https://example.com");
+        let (_, tag) = token_of(&url).split_once('.').unwrap();
+        let forged = TrackingToken {
+            newsletter_issue_id: Uuid::new_v4(),
+            subscriber_id: Uuid::new_v4(),
+            event: TrackedEvent::Click("https://evil.com".into()),
+        }
+        .encode(&secret);
+        let (payload, _) = forged.split_once('.').unwrap();
+
+        assert_err!(TrackingToken::decode(
+            &format!("{}.{}", payload, tag),
+            &secret
+        ));
+    }
+
+    #[test]
+    fn html_links_are_rewritten_and_a_pixel_is_added() {
+        let secret = secret();
+        let tracker = tracker(&secret);
+        let html = r##"<body><a href="https://example.com/?a=1&amp;b=2">x</a><a href="#top">y</a></body>"##;
+
+        let rewritten = tracker.rewrite_html(html);
+
+        assert!(!rewritten.contains("https://example.com"));
+        assert!(rewritten.contains(r##"href="#top""##));
+        assert!(rewritten.ends_with(r#"alt="" /></body>"#));
+        let click_url = rewritten
+            .split('"')
+            .find(|s| s.starts_with("http://127.0.0.1/t/") && !s.contains("/open/"))
+            .unwrap();
+        let token = TrackingToken::decode(token_of(click_url), &secret).unwrap();
+        assert_eq!(
+            token.event,
+            TrackedEvent::Click("https://example.com/?a=1&b=2".into())
+        );
+    }
+
+    #[test]
+    fn text_links_are_rewritten() {
+        let secret = secret();
+        let tracker = tracker(&secret);
+
+        let rewritten = tracker.rewrite_text("Read more at https://example.com/post.");
+
+        assert!(rewritten.starts_with("Read more at http://127.0.0.1/t/"));
+        assert!(rewritten.ends_with('.'));
+        assert!(!rewritten.contains("example.com"));
+    }
+}
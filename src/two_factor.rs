@@ -0,0 +1,366 @@
+//! src/two_factor.rs
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha1::Sha1;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::api_tokens::hash_token;
+
+/// Shown by authenticator apps next to the account name.
+const TOTP_ISSUER: &str = "Blog Backend";
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Codes of the steps right before and after the current one are accepted,
+/// to make up for clocks being slightly off.
+const TOTP_ALLOWED_SKEW: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TwoFactorError {
+    #[error("Two-factor authentication is already enabled.")]
+    AlreadyEnabled,
+    #[error("Two-factor enrollment has not been started.")]
+    NotEnrolled,
+    #[error("The code is invalid.")]
+    InvalidCode,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// The secret shared with the authenticator app of a user (RFC 6238).
+pub struct TotpSecret(Vec<u8>);
+
+impl TotpSecret {
+    pub fn generate() -> Self {
+        let bytes: [u8; 20] = thread_rng().gen();
+        Self(bytes.to_vec())
+    }
+
+    pub fn from_base32(s: &str) -> Option<Self> {
+        let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in s.bytes().filter(|c| *c != b'=') {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|a| *a == c.to_ascii_uppercase())?;
+            buffer = (buffer << 5) | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+        Some(Self(bytes))
+    }
+
+    /// The secret as typed in by hand, or embedded in a provisioning URI.
+    pub fn to_base32(&self) -> String {
+        let mut encoded = String::with_capacity(self.0.len() * 8 / 5 + 1);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for byte in &self.0 {
+            buffer = (buffer << 8) | u32::from(*byte);
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+        }
+        encoded
+    }
+
+    /// The `otpauth://` URI that authenticator apps enroll from, usually as a QR code.
+    pub fn provisioning_uri(&self, account: &str) -> String {
+        format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}\
+            &algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECONDS}",
+            issuer = urlencoding::encode(TOTP_ISSUER),
+            account = urlencoding::encode(account),
+            secret = self.to_base32(),
+        )
+    }
+
+    /// The code for a time step (RFC 4226, with the step as the counter).
+    pub fn code(&self, step: i64) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.0).expect("HMAC can take a key of any size");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+        let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(TOTP_DIGITS),
+            width = TOTP_DIGITS as usize
+        )
+    }
+}
+
+pub fn time_step(now: DateTime<Utc>) -> i64 {
+    now.timestamp().div_euclid(TOTP_STEP_SECONDS)
+}
+
+/// The step of the current code matching `code`, if any.
+fn matching_step(secret: &TotpSecret, code: &str, now: DateTime<Utc>) -> Option<i64> {
+    let current = time_step(now);
+    (current - TOTP_ALLOWED_SKEW..=current + TOTP_ALLOWED_SKEW)
+        .find(|step| secret.code(*step) == code)
+}
+
+/// Recovery codes are random enough for a fast hash to be safe to store.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+fn generate_recovery_code() -> String {
+    let code: String = thread_rng()
+        .sample_iter(Alphanumeric)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .take(10)
+        .collect();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Stores a new secret for a user without two-factor authentication.
+/// It is only enabled once a first code is confirmed.
+#[tracing::instrument(name = "Start two-factor enrollment", skip(pool))]
+pub async fn start_enrollment(pool: &PgPool, user_id: Uuid) -> Result<TotpSecret, TwoFactorError> {
+    let secret = TotpSecret::generate();
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_secret = $2, totp_last_used_step = NULL
+        WHERE user_id = $1 AND NOT totp_enabled
+        "#,
+        user_id,
+        secret.to_base32()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store a TOTP secret.")?;
+
+    if result.rows_affected() == 0 {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+    Ok(secret)
+}
+
+/// Enables two-factor authentication once the user proved that their
+/// authenticator app is set up, returning their recovery codes.
+#[tracing::instrument(name = "Confirm two-factor enrollment", skip(pool, code))]
+pub async fn confirm_enrollment(
+    pool: &PgPool,
+    user_id: Uuid,
+    code: &str,
+) -> Result<Vec<String>, TwoFactorError> {
+    let row = sqlx::query!(
+        "SELECT totp_secret, totp_enabled FROM users WHERE user_id = $1",
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to retrieve the TOTP secret.")?;
+    if row.totp_enabled {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+    let secret = row
+        .totp_secret
+        .as_deref()
+        .and_then(TotpSecret::from_base32)
+        .ok_or(TwoFactorError::NotEnrolled)?;
+    let step =
+        matching_step(&secret, code.trim(), Utc::now()).ok_or(TwoFactorError::InvalidCode)?;
+
+    let recovery_codes: Vec<_> = std::iter::repeat_with(generate_recovery_code)
+        .take(RECOVERY_CODES)
+        .collect();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query!(
+        "UPDATE users SET totp_enabled = true, totp_last_used_step = $2 WHERE user_id = $1",
+        user_id,
+        step
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to enable two-factor authentication.")?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to delete previous recovery codes.")?;
+    for code in &recovery_codes {
+        sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+            user_id,
+            hash_recovery_code(code)
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to store a recovery code.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to enable two-factor authentication.")?;
+
+    Ok(recovery_codes)
+}
+
+#[tracing::instrument(name = "Check if two-factor authentication is enabled", skip(pool))]
+pub async fn is_enabled(pool: &PgPool, user_id: Uuid) -> Result<bool, anyhow::Error> {
+    sqlx::query_scalar!("SELECT totp_enabled FROM users WHERE user_id = $1", user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to check if two-factor authentication is enabled.")
+}
+
+/// How many times a code from the authenticator app can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeReuse {
+    /// Once: a code seen over the shoulder of someone logging in must not
+    /// open a second session.
+    Forbidden,
+    /// Until the next code comes up. Basic authentication clients send the
+    /// code with their password on every request, so a script publishing
+    /// twice in the same 30 seconds must not be turned away.
+    WithinTimeStep,
+}
+
+/// Checks a code from the authenticator app, or an unused recovery code.
+/// Recovery codes can only be used once; whether authenticator codes can be
+/// used again is up to the caller.
+#[tracing::instrument(name = "Verify a two-factor code", skip(pool, code))]
+pub async fn verify_code(
+    pool: &PgPool,
+    user_id: Uuid,
+    code: &str,
+    reuse: CodeReuse,
+) -> Result<bool, anyhow::Error> {
+    let code = code.trim();
+    let is_totp_code =
+        code.len() == TOTP_DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit());
+
+    if is_totp_code {
+        let secret = sqlx::query_scalar!(
+            "SELECT totp_secret FROM users WHERE user_id = $1 AND totp_enabled",
+            user_id
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to retrieve the TOTP secret.")?
+        .flatten()
+        .and_then(|secret| TotpSecret::from_base32(&secret));
+        let Some(step) = secret.and_then(|secret| matching_step(&secret, code, Utc::now())) else {
+            return Ok(false);
+        };
+        // Codes older than the last one used are never accepted again.
+        let result = sqlx::query!(
+            r#"
+            UPDATE users SET totp_last_used_step = $2
+            WHERE user_id = $1
+                AND (totp_last_used_step IS NULL OR totp_last_used_step < $2
+                    OR ($3 AND totp_last_used_step = $2))
+            "#,
+            user_id,
+            step,
+            reuse == CodeReuse::WithinTimeStep
+        )
+        .execute(pool)
+        .await
+        .context("Failed to record the use of a TOTP code.")?;
+        Ok(result.rows_affected() == 1)
+    } else {
+        let result = sqlx::query!(
+            r#"
+            UPDATE recovery_codes SET used_at = $3
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            hash_recovery_code(code),
+            Utc::now()
+        )
+        .execute(pool)
+        .await
+        .context("Failed to record the use of a recovery code.")?;
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_recovery_code, matching_step, time_step, TotpSecret};
+    use chrono::{TimeZone, Utc};
+
+    /// The secret of the RFC 6238 test vectors, "12345678901234567890".
+    fn rfc_secret() -> TotpSecret {
+        TotpSecret::from_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap()
+    }
+
+    #[test]
+    fn codes_match_the_rfc_test_vectors() {
+        let secret = rfc_secret();
+        let at = |timestamp| time_step(Utc.timestamp_opt(timestamp, 0).unwrap());
+        assert_eq!(secret.code(at(59)), "287082");
+        assert_eq!(secret.code(at(1111111109)), "081804");
+        assert_eq!(secret.code(at(2000000000)), "279037");
+    }
+
+    #[test]
+    fn secrets_survive_a_base32_round_trip() {
+        let secret = TotpSecret::generate();
+        let decoded = TotpSecret::from_base32(&secret.to_base32()).unwrap();
+        assert_eq!(decoded.0, secret.0);
+        assert_eq!(rfc_secret().0, b"12345678901234567890");
+    }
+
+    #[test]
+    fn codes_of_the_neighbouring_steps_are_accepted() {
+        let secret = rfc_secret();
+        let now = Utc::now();
+        let step = time_step(now);
+        assert_eq!(
+            matching_step(&secret, &secret.code(step - 1), now),
+            Some(step - 1)
+        );
+        assert_eq!(
+            matching_step(&secret, &secret.code(step + 1), now),
+            Some(step + 1)
+        );
+        assert_eq!(matching_step(&secret, &secret.code(step - 3), now), None);
+    }
+
+    #[test]
+    fn provisioning_uris_carry_the_secret_and_the_account() {
+        let uri = rfc_secret().provisioning_uri("ursula");
+        assert!(uri.starts_with("otpauth://totp/Blog%20Backend:ursula?"));
+        assert!(uri.contains("secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+    }
+
+    #[test]
+    fn recovery_codes_are_hashed_regardless_of_formatting() {
+        assert_eq!(
+            hash_recovery_code("abcde-12345"),
+            hash_recovery_code(" ABCDE12345 ")
+        );
+        assert_ne!(
+            hash_recovery_code("abcde-12345"),
+            hash_recovery_code("abcde-12346")
+        );
+    }
+}
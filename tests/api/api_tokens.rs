@@ -0,0 +1,186 @@
+//! tests/api/api_tokens.rs
+
+use blog_backend::{api_tokens::ApiTokenScope, authentication::TWO_FACTOR_CODE_HEADER};
+use hyper::StatusCode;
+use wiremock::{
+    matchers::{method, path},
+    Mock,
+};
+
+use crate::helper::{batch_response, create_confirmed_subscriber, spawn_app, TestApp};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn publish_with_token(app: &TestApp, token: &str) -> reqwest::Response {
+    app.post_newsletters_with_token(&newsletter_request_body(), token)
+        .await
+}
+
+async fn list_subscribers_with_token(app: &TestApp, token: &str) -> reqwest::Response {
+    app.api_client
+        .get(&format!("{}/admin/subscribers", &app.address))
+        .bearer_auth(token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn api_tokens_are_created_with_a_second_factor() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(&format!("{}/admin/api-tokens", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .header(TWO_FACTOR_CODE_HEADER, app.test_user.two_factor_code())
+        .json(&serde_json::json!({ "name": "CI", "scopes": ["publish"] }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["token"].as_str().unwrap().starts_with("bb_"));
+    assert_eq!(body["scopes"], serde_json::json!(["publish"]));
+}
+
+#[tokio::test]
+async fn api_tokens_cannot_be_created_with_a_password_only() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_admin(
+            "api-tokens",
+            &serde_json::json!({ "name": "CI", "scopes": ["publish"] }),
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn api_tokens_with_unknown_scopes_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(&format!("{}/admin/api-tokens", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .header(TWO_FACTOR_CODE_HEADER, app.test_user.two_factor_code())
+        .json(&serde_json::json!({ "name": "CI", "scopes": ["everything"] }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn newsletters_can_be_published_with_an_api_token() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let token = app.create_api_token(&[ApiTokenScope::Publish]).await;
+
+    let response = publish_with_token(&app, &token).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn the_last_use_of_api_tokens_is_recorded() {
+    let app = spawn_app().await;
+    let token = app.create_api_token(&[ApiTokenScope::Publish]).await;
+
+    publish_with_token(&app, &token).await;
+    let response = app.get_admin("api-tokens").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let tokens = body["tokens"].as_array().unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert!(tokens[0]["last_used_at"].is_string());
+    assert!(tokens[0].get("token").is_none());
+}
+
+#[tokio::test]
+async fn api_tokens_without_the_publish_scope_cannot_publish() {
+    let app = spawn_app().await;
+    let token = app
+        .create_api_token(&[ApiTokenScope::ReadSubscribers])
+        .await;
+
+    let response = publish_with_token(&app, &token).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn unknown_api_tokens_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = publish_with_token(&app, "bb_not-a-token").await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn revoked_api_tokens_are_rejected() {
+    let app = spawn_app().await;
+    let token = app.create_api_token(&[ApiTokenScope::Publish]).await;
+    let body: serde_json::Value = app.get_admin("api-tokens").await.json().await.unwrap();
+    let token_id = body["tokens"][0]["token_id"].as_str().unwrap().to_owned();
+
+    let response = app
+        .api_client
+        .delete(&format!("{}/admin/api-tokens/{}", &app.address, token_id))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = publish_with_token(&app, &token).await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn subscribers_can_be_read_with_an_api_token() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = app
+        .create_api_token(&[ApiTokenScope::ReadSubscribers])
+        .await;
+
+    let response = list_subscribers_with_token(&app, &token).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let subscribers = body["subscribers"].as_array().unwrap();
+    assert_eq!(subscribers.len(), 1);
+    assert_eq!(subscribers[0]["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn subscribers_cannot_be_read_with_a_publishing_token() {
+    let app = spawn_app().await;
+    let token = app.create_api_token(&[ApiTokenScope::Publish]).await;
+
+    let response = list_subscribers_with_token(&app, &token).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
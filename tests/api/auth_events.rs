@@ -0,0 +1,167 @@
+//! tests/api/auth_events.rs
+
+use blog_backend::authentication::TWO_FACTOR_CODE_HEADER;
+use hyper::StatusCode;
+
+use crate::helper::{spawn_app_with, TestApp};
+
+/// No delay after failed logins, so that the admin view can be used right away.
+async fn spawn_app() -> TestApp {
+    spawn_app_with(|c| c.login_throttle.base_delay_milliseconds = 0).await
+}
+
+async fn login_with(app: &TestApp, username: &str, password: &str) -> reqwest::Response {
+    app.form_post(
+        "/login",
+        serde_json::json!({
+            "username": username,
+            "password": password
+        }),
+    )
+    .header("User-Agent", "auth-events-test")
+    .send()
+    .await
+    .expect("Failed to execute request.")
+}
+
+/// The events listed by the admin view for a query string.
+async fn get_events(app: &TestApp, query: &str) -> Vec<serde_json::Value> {
+    let response = app.get_admin(&format!("auth-events?{}", query)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["events"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn failed_logins_are_recorded_with_the_request_they_came_from() {
+    let app = spawn_app().await;
+
+    let response = login_with(&app, &app.test_user.username, "wrong-password").await;
+
+    let request_id = response.headers()["x-request-id"].to_str().unwrap();
+    let events = get_events(&app, "kind=login_failed").await;
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(event["user_id"], app.test_user.user_id.to_string());
+    assert_eq!(event["username"], app.test_user.username);
+    assert_eq!(event["ip"], "127.0.0.1");
+    assert_eq!(event["user_agent"], "auth-events-test");
+    assert_eq!(event["request_id"], request_id);
+    assert_eq!(event["detail"], "Invalid password.");
+}
+
+#[tokio::test]
+async fn logins_succeed_once_the_second_factor_is_checked() {
+    let app = spawn_app().await;
+
+    login_with(&app, &app.test_user.username, &app.test_user.password).await;
+    assert!(get_events(&app, "kind=login_succeeded").await.is_empty());
+    app.post_two_factor_code(&app.test_user.two_factor_code())
+        .await;
+
+    let events = get_events(&app, "kind=login_succeeded").await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["user_id"], app.test_user.user_id.to_string());
+}
+
+#[tokio::test]
+async fn authenticated_api_requests_are_not_recorded_as_logins() {
+    let app = spawn_app().await;
+
+    get_events(&app, "").await;
+
+    assert!(get_events(&app, "").await.is_empty());
+}
+
+#[tokio::test]
+async fn lockouts_are_recorded() {
+    let app = spawn_app_with(|c| {
+        c.login_throttle.base_delay_milliseconds = 0;
+        c.login_throttle.max_failed_attempts_per_username = 2;
+    })
+    .await;
+
+    for _ in 0..2 {
+        login_with(&app, "someone-else", "a-password").await;
+    }
+
+    let events = get_events(&app, "kind=locked_out").await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["username"], "someone-else");
+    assert_eq!(events[0]["detail"], "username someone-else");
+}
+
+#[tokio::test]
+async fn throttled_attempts_are_counted_on_a_single_event() {
+    let app = spawn_app_with(|c| {
+        c.login_throttle.base_delay_milliseconds = 0;
+        c.login_throttle.max_failed_attempts_per_username = 2;
+    })
+    .await;
+
+    for _ in 0..5 {
+        login_with(&app, "someone-else", "a-password").await;
+    }
+
+    let events = get_events(&app, "kind=login_failed").await;
+    assert_eq!(events.len(), 3);
+    let throttled = &events[0];
+    assert_eq!(throttled["detail"], "Too many failed login attempts");
+    assert_eq!(throttled["occurrences"], 3);
+    assert!(!throttled["last_occurred_at"].is_null());
+}
+
+#[tokio::test]
+async fn api_tokens_are_recorded_when_created_and_revoked() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(&format!("{}/admin/api-tokens", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .header(TWO_FACTOR_CODE_HEADER, app.test_user.two_factor_code())
+        .json(&serde_json::json!({ "name": "CI", "scopes": ["publish"] }))
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let token_id = body["token_id"].as_str().unwrap();
+    app.api_client
+        .delete(&format!("{}/admin/api-tokens/{}", &app.address, token_id))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+
+    let events = get_events(&app, &format!("user_id={}", app.test_user.user_id)).await;
+    let kinds: Vec<_> = events.iter().map(|e| e["kind"].as_str().unwrap()).collect();
+    assert_eq!(kinds, ["api_token_revoked", "api_token_created"]);
+    assert_eq!(events[0]["detail"], token_id);
+    assert_eq!(events[1]["detail"], format!("{} (CI)", token_id));
+}
+
+#[tokio::test]
+async fn events_can_be_filtered_by_username_and_time() {
+    let app = spawn_app().await;
+    login_with(&app, "someone-else", "a-password").await;
+    login_with(&app, &app.test_user.username, "wrong-password").await;
+
+    let events = get_events(&app, "username=someone-else").await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["user_id"], serde_json::Value::Null);
+    assert_eq!(get_events(&app, "").await.len(), 2);
+    assert!(get_events(&app, "since=2100-01-01T00:00:00Z")
+        .await
+        .is_empty());
+    assert_eq!(get_events(&app, "limit=1").await.len(), 1);
+}
+
+#[tokio::test]
+async fn only_owners_view_authentication_events() {
+    let app = spawn_app().await;
+    app.set_test_user_role("editor").await;
+
+    let response = app.get_admin("auth-events").await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
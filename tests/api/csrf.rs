@@ -0,0 +1,129 @@
+//! tests/api/csrf.rs
+
+use hyper::StatusCode;
+use wiremock::{matchers::path, Mock, ResponseTemplate};
+
+use crate::helper::{assert_is_redirect_to, spawn_app};
+
+fn login_body() -> serde_json::Value {
+    serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    })
+}
+
+#[tokio::test]
+async fn forms_posted_with_their_csrf_token_are_accepted() {
+    let app = spawn_app().await;
+
+    let response = app.post_login(&login_body()).await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn forms_posted_without_a_csrf_token_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(&format!("{}/login", &app.address))
+        .form(&login_body())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn forms_posted_with_a_forged_csrf_token_are_rejected() {
+    let app = spawn_app().await;
+    let mut body = login_body();
+    body["csrf_token"] = "00".repeat(32).into();
+
+    let response = app
+        .api_client
+        .post(&format!("{}/login", &app.address))
+        .form(&body)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn csrf_tokens_only_work_with_the_cookie_they_were_issued_with() {
+    let app = spawn_app().await;
+    let mut body = login_body();
+    body["csrf_token"] = app.csrf_token.clone().into();
+    // Another site can get a token of its own, but not the browser's cookie.
+    let client_without_cookie = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let response = client_without_cookie
+        .post(&format!("{}/login", &app.address))
+        .form(&body)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn csrf_tokens_can_be_sent_in_a_header() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(&format!("{}/login", &app.address))
+        .header("X-CSRF-Token", &app.csrf_token)
+        .form(&login_body())
+        .send()
+        .await
+        .unwrap();
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn the_csrf_cookie_is_only_set_once() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(&format!("{}/login/forgot-password", &app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response.cookies().all(|c| c.name() != "_csrf"));
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(&app.csrf_token));
+}
+
+#[tokio::test]
+async fn subscribing_needs_no_csrf_token() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // A client without the cookie, like the static home page or a script.
+    let response = reqwest::Client::new()
+        .post(&format!("{}/subscriptions", &app.address))
+        .form(&serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com"
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,146 @@
+//! tests/api/email_webhooks.rs
+
+use hyper::StatusCode;
+use wiremock::{matchers::any, Mock, ResponseTemplate};
+
+use crate::helper::{create_confirmed_subscriber, spawn_app, TestApp};
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .status
+}
+
+#[tokio::test]
+async fn a_hard_bounce_marks_the_subscriber_as_bounced() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = app
+        .post_email_webhook(&serde_json::json!({
+            "RecordType": "Bounce",
+            "Type": "HardBounce",
+            "Email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn a_spam_complaint_marks_the_subscriber_as_complained() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = app
+        .post_email_webhook(&serde_json::json!({
+            "RecordType": "SpamComplaint",
+            "Email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn a_soft_bounce_is_acknowledged_but_ignored() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = app
+        .post_email_webhook(&serde_json::json!({
+            "RecordType": "Bounce",
+            "Type": "SoftBounce",
+            "Email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_bounced_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.post_email_webhook(&serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "HardBounce",
+        "Email": "ursula_le_guin@gmail.com"
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn webhooks_with_an_invalid_signature_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let test_cases = vec![
+        (None, "missing signature"),
+        (Some("not-hex"), "malformed signature"),
+        (Some("deadbeef"), "wrong signature"),
+    ];
+
+    for (signature, description) in test_cases {
+        let mut request = app
+            .api_client
+            .post(format!("{}/webhooks/email", &app.address))
+            .json(&serde_json::json!({
+                "RecordType": "SpamComplaint",
+                "Email": "ursula_le_guin@gmail.com"
+            }));
+        if let Some(signature) = signature {
+            request = request.header("X-Webhook-Signature", signature);
+        }
+
+        // Act
+        let response = request.send().await.unwrap();
+
+        // Assert
+        assert_eq!(
+            response.status(),
+            StatusCode::UNAUTHORIZED,
+            "The webhook did not fail with 401 Unauthorized for a {}.",
+            description
+        );
+    }
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
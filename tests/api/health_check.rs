@@ -1,4 +1,11 @@
-use crate::helper::spawn_app;
+use std::time::Duration;
+
+use wiremock::{
+    matchers::{header_exists, method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helper::{spawn_app, spawn_app_with, TestApp};
 
 #[tokio::test]
 async fn health_check_works() {
@@ -14,3 +21,100 @@ async fn health_check_works() {
     assert!(response.status().is_success());
     assert_eq!(Some(0), response.content_length())
 }
+
+/// Has the email API answer its health checks.
+async fn mount_email_api_health(app: &TestApp, response: ResponseTemplate) {
+    Mock::given(path("/server"))
+        .and(method("GET"))
+        .and(header_exists("X-Postmark-Server-Token"))
+        .respond_with(response)
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn get_health(app: &TestApp, check: &str) -> reqwest::Response {
+    app.api_client
+        .get(&format!("{}/health/{}", &app.address, check))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn the_application_is_live_whatever_its_dependencies() {
+    let app = spawn_app().await;
+    mount_email_api_health(&app, ResponseTemplate::new(500)).await;
+
+    let response = get_health(&app, "live").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, serde_json::json!({ "status": "ok" }));
+}
+
+#[tokio::test]
+async fn the_application_is_ready_when_every_dependency_is() {
+    let app = spawn_app().await;
+    mount_email_api_health(&app, ResponseTemplate::new(200)).await;
+
+    let response = get_health(&app, "ready").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ok");
+    for check in ["database", "migrations", "email_api"] {
+        assert_eq!(body["checks"][check]["status"], "ok", "{} is not ok", check);
+        assert!(body["checks"][check]["duration_milliseconds"].is_u64());
+    }
+}
+
+#[tokio::test]
+async fn the_application_is_not_ready_when_the_email_api_fails() {
+    let app = spawn_app().await;
+    mount_email_api_health(&app, ResponseTemplate::new(401)).await;
+
+    let response = get_health(&app, "ready").await;
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "unavailable");
+    assert_eq!(body["checks"]["email_api"]["status"], "unavailable");
+    assert_eq!(body["checks"]["email_api"]["error"], "failed");
+    assert_eq!(body["checks"]["database"]["status"], "ok");
+}
+
+#[tokio::test]
+async fn the_application_is_not_ready_when_the_email_api_is_too_slow() {
+    let app = spawn_app_with(|c| c.health_checks.timeout_milliseconds = 200).await;
+    mount_email_api_health(
+        &app,
+        ResponseTemplate::new(200).set_delay(Duration::from_secs(2)),
+    )
+    .await;
+
+    let response = get_health(&app, "ready").await;
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["checks"]["email_api"]["error"], "timed out");
+}
+
+#[tokio::test]
+async fn the_application_is_not_ready_with_pending_migrations() {
+    let app = spawn_app().await;
+    mount_email_api_health(&app, ResponseTemplate::new(200)).await;
+    sqlx::query(
+        "DELETE FROM _sqlx_migrations
+        WHERE version = (SELECT max(version) FROM _sqlx_migrations)",
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let response = get_health(&app, "ready").await;
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["checks"]["migrations"]["status"], "unavailable");
+    assert_eq!(body["checks"]["database"]["status"], "ok");
+}
@@ -1,15 +1,27 @@
 use argon2::{password_hash::SaltString, Argon2, Params, PasswordHasher};
 use blog_backend::{
-    configuration::{get_configuration, DatabaseSettings},
+    api_tokens::{create_token, ApiTokenScope},
+    authentication::TWO_FACTOR_CODE_HEADER,
+    configuration::{get_configuration, DatabaseSettings, SenderSettings, Settings},
     startup::{get_connection_pool, Application},
     telemetry::{get_subscriber, init_subscriber},
+    two_factor::{time_step, TotpSecret},
 };
+use chrono::Utc;
+use std::future::Future;
+use tokio::task::JoinHandle;
 
+use hmac::{Hmac, Mac};
 use hyper::{header, StatusCode};
 use once_cell::sync::Lazy;
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 use uuid::Uuid;
-use wiremock::MockServer;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
 
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
@@ -36,6 +48,10 @@ pub struct TestApp {
     pub email_server: MockServer,
     pub test_user: TestUser,
     pub api_client: reqwest::Client,
+    pub webhook_secret: String,
+    pub metrics_token: String,
+    /// The CSRF token the forms served to `api_client` carry.
+    pub csrf_token: String,
 }
 
 pub struct ConfirmationLinks {
@@ -54,23 +70,177 @@ impl TestApp {
             .expect("Failed to execute request.")
     }
 
+    pub async fn post_subscriptions_json(&self, body: serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(&format!("{}/subscriptions", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_subscription_challenge(&self) -> serde_json::Value {
+        self.api_client
+            .get(&format!("{}/subscriptions/challenge", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .json()
+            .await
+            .unwrap()
+    }
+
     pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
         self.api_client
             .post(&format!("{}/newsletters", &self.address))
             .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header(TWO_FACTOR_CODE_HEADER, self.test_user.two_factor_code())
             .json(&body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
 
+    pub async fn post_newsletter_preview(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(&format!("{}/newsletters/preview", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_with_token(
+        &self,
+        body: &serde_json::Value,
+        token: &str,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(&format!("{}/newsletters", &self.address))
+            .bearer_auth(token)
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_multipart(
+        &self,
+        form: reqwest::multipart::Form,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(&format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header(TWO_FACTOR_CODE_HEADER, self.test_user.two_factor_code())
+            .multipart(form)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Create an API token for the test user, straight in the database.
+    pub async fn create_api_token(&self, scopes: &[ApiTokenScope]) -> String {
+        let (_, token) = create_token(&self.db_pool, self.test_user.user_id, "test", scopes)
+            .await
+            .expect("Failed to create an API token.");
+        token.expose_secret().clone()
+    }
+
+    /// Give the test user another role, straight in the database.
+    pub async fn set_test_user_role(&self, role: &str) {
+        sqlx::query!(
+            "UPDATE users SET role = $1 WHERE user_id = $2",
+            role,
+            self.test_user.user_id
+        )
+        .execute(&self.db_pool)
+        .await
+        .expect("Failed to change the role of the test user.");
+    }
+
+    /// Issue an authenticated `GET` against the admin API.
+    pub async fn get_admin(&self, path: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_newsletter_issue_stats(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        self.get_admin(&format!("newsletters/{}/stats", newsletter_issue_id))
+            .await
+    }
+
+    pub async fn get_newsletter_issue_deliveries(
+        &self,
+        newsletter_issue_id: &str,
+    ) -> reqwest::Response {
+        self.get_admin(&format!("newsletters/{}/deliveries", newsletter_issue_id))
+            .await
+    }
+
+    pub async fn get_failed_newsletter_issue_deliveries(
+        &self,
+        newsletter_issue_id: &str,
+    ) -> reqwest::Response {
+        self.get_admin(&format!(
+            "newsletters/{}/deliveries/failed",
+            newsletter_issue_id
+        ))
+        .await
+    }
+
+    /// Post an event to the email provider webhook, signed with the configured secret.
+    pub async fn post_email_webhook(&self, body: &serde_json::Value) -> reqwest::Response {
+        let body = serde_json::to_vec(body).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.webhook_secret.as_bytes()).unwrap();
+        mac.update(&body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        self.api_client
+            .post(format!("{}/webhooks/email", &self.address))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Signature", signature)
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Build a `POST` of a form, carrying the CSRF token like a browser would.
+    pub fn form_post(&self, path: &str, mut body: serde_json::Value) -> reqwest::RequestBuilder {
+        body["csrf_token"] = self.csrf_token.clone().into();
+        self.api_client
+            .post(&format!("{}{}", &self.address, path))
+            .form(&body)
+    }
+
     pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
     where
         Body: serde::Serialize,
     {
+        self.form_post("/login", serde_json::to_value(body).unwrap())
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_two_factor_code(&self, code: &str) -> reqwest::Response {
+        self.form_post("/login/two-factor", serde_json::json!({ "code": code }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Issue an authenticated `POST` against the admin API.
+    pub async fn post_admin(&self, path: &str, body: &serde_json::Value) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/login", &self.address))
-            .form(body)
+            .post(format!("{}/admin/{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
             .send()
             .await
             .expect("Failed to execute request.")
@@ -113,6 +283,20 @@ impl TestApp {
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawns the application after `configure` tweaked its test configuration.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
+    spawn_app_until(configure, std::future::pending()).await.0
+}
+
+/// Spawns the application, which shuts down once `shutdown` completes. The
+/// handle resolves when it is done shutting down.
+pub async fn spawn_app_until(
+    configure: impl FnOnce(&mut Settings),
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> (TestApp, JoinHandle<hyper::Result<()>>) {
     Lazy::force(&TRACING);
 
     // Launch a mock server to stand in for Postmark's API
@@ -126,6 +310,17 @@ pub async fn spawn_app() -> TestApp {
         c.application.port = 0;
         // Use the mock server as email API
         c.email_client.base_url = email_server.uri();
+        // Allow issues to opt into open and click tracking
+        c.newsletter.tracking_enabled = true;
+        // A second identity that issues can be sent from
+        c.email_client.senders.insert(
+            "editorial".into(),
+            SenderSettings {
+                display_name: Some("The Editors".into()),
+                email: "editors@example.com".into(),
+            },
+        );
+        configure(&mut c);
         c
     };
 
@@ -138,14 +333,14 @@ pub async fn spawn_app() -> TestApp {
     let application_port = application.port();
     let address = format!("http://localhost:{}", application.port());
 
-    #[allow(clippy::let_underscore_future)]
-    let _ = tokio::spawn(application.run_until_stopped());
+    let server = tokio::spawn(application.run_until(shutdown));
 
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .cookie_store(true)
         .build()
         .unwrap();
+    let csrf_token = get_csrf_token(&client, &address).await;
 
     let test_app = TestApp {
         address,
@@ -154,10 +349,38 @@ pub async fn spawn_app() -> TestApp {
         email_server,
         test_user: TestUser::generate(),
         api_client: client,
+        webhook_secret: configuration
+            .email_client
+            .webhook_secret
+            .expose_secret()
+            .to_owned(),
+        metrics_token: configuration
+            .application
+            .metrics_token
+            .expose_secret()
+            .to_owned(),
+        csrf_token,
     };
     test_app.test_user.store(&test_app.db_pool).await;
 
-    test_app
+    (test_app, server)
+}
+
+/// Get a form like a browser would, so that the client has a CSRF cookie,
+/// and reads the token out of it.
+async fn get_csrf_token(client: &reqwest::Client, address: &str) -> String {
+    let html = client
+        .get(&format!("{}/login", address))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .text()
+        .await
+        .unwrap();
+    let (_, rest) = html
+        .split_once(r#"name="csrf_token" value=""#)
+        .expect("The login form has no CSRF token");
+    rest.split('"').next().unwrap().to_owned()
 }
 
 async fn configure_database(config: &DatabaseSettings) -> PgPool {
@@ -182,10 +405,60 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
     pool
 }
 
+/// Use the public API of the application under test to create
+/// an unconfirmed subscriber
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    create_unconfirmed_subscriber_with_email(app, "ursula_le_guin@gmail.com").await
+}
+
+pub async fn create_unconfirmed_subscriber_with_email(
+    app: &TestApp,
+    email: &str,
+) -> ConfirmationLinks {
+    let body = format!("name=le%20guin&email={}", urlencoding::encode(email));
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    create_confirmed_subscriber_with_email(app, "ursula_le_guin@gmail.com").await
+}
+
+pub async fn create_confirmed_subscriber_with_email(app: &TestApp, email: &str) {
+    let confirmation_link = create_unconfirmed_subscriber_with_email(app, email).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+/// An owner with two-factor authentication enabled.
 pub struct TestUser {
     pub user_id: Uuid,
     pub username: String,
     pub password: String,
+    pub email: String,
+    pub totp_secret: String,
 }
 
 impl TestUser {
@@ -194,10 +467,19 @@ impl TestUser {
             user_id: Uuid::new_v4(),
             username: Uuid::new_v4().to_string(),
             password: Uuid::new_v4().to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
+            totp_secret: TotpSecret::generate().to_base32(),
         }
     }
 
-    async fn store(&self, pool: &PgPool) {
+    /// The current code of the authenticator app of the user.
+    pub fn two_factor_code(&self) -> String {
+        TotpSecret::from_base32(&self.totp_secret)
+            .unwrap()
+            .code(time_step(Utc::now()))
+    }
+
+    pub async fn store(&self, pool: &PgPool) {
         let salt = SaltString::generate(&mut rand::thread_rng());
 
         let password_hash = Argon2::new(
@@ -210,11 +492,14 @@ impl TestUser {
         .to_string();
 
         sqlx::query!(
-            "INSERT INTO users (user_id, username, password_hash)
-            VALUES ($1, $2, $3)",
+            "INSERT INTO users
+                (user_id, username, password_hash, email, totp_secret, totp_enabled, role)
+            VALUES ($1, $2, $3, $4, $5, true, 'owner')",
             self.user_id,
             self.username,
-            password_hash
+            password_hash,
+            self.email,
+            self.totp_secret
         )
         .execute(pool)
         .await
@@ -222,6 +507,37 @@ impl TestUser {
     }
 }
 
+/// A successful response of the email batch API for `n_messages` messages.
+pub fn batch_response(n_messages: usize) -> ResponseTemplate {
+    let results: serde_json::Value = (0..n_messages)
+        .map(|_| {
+            serde_json::json!({
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": Uuid::new_v4().to_string()
+            })
+        })
+        .collect();
+    ResponseTemplate::new(200).set_body_json(results)
+}
+
+/// Looks for a nonce such that the SHA-256 of `{challenge}:{nonce}` starts
+/// with `difficulty` zero bits, the way clients solve subscription challenges.
+pub fn solve_challenge(challenge: &str, difficulty: u8) -> String {
+    let leading_zero_bits = |hash: &[u8]| {
+        let zero_bytes = hash.iter().take_while(|byte| **byte == 0).count();
+        let next_byte = hash.get(zero_bytes).map_or(0, |byte| byte.leading_zeros());
+        zero_bytes as u32 * 8 + next_byte
+    };
+    (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|nonce| {
+            let hash = Sha256::digest(format!("{}:{}", challenge, nonce));
+            leading_zero_bits(&hash) >= u32::from(difficulty)
+        })
+        .expect("Ran out of nonces")
+}
+
 pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
     assert_eq!(response.status(), StatusCode::SEE_OTHER);
     assert_eq!(response.headers().get("Location").unwrap(), location);
@@ -0,0 +1,255 @@
+//! tests/api/invitations.rs
+
+use hyper::StatusCode;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helper::{assert_is_redirect_to, spawn_app, TestApp};
+
+const PASSWORD: &str = "an-invited-password";
+
+fn flash_message(response: &reqwest::Response) -> String {
+    response
+        .cookies()
+        .find(|c| c.name() == "_flash")
+        .unwrap()
+        .value()
+        .to_owned()
+}
+
+/// Invites someone as an editor, returning the invitation id and link.
+async fn invite(app: &TestApp, email: &str) -> (String, reqwest::Url) {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let response = app
+        .post_admin(
+            "invitations",
+            &serde_json::json!({ "email": email, "role": "editor" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body: serde_json::Value = response.json().await.unwrap();
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let link = app.get_confirmation_links(email_request).html;
+    (body["invitation_id"].as_str().unwrap().to_owned(), link)
+}
+
+fn token(link: &reqwest::Url) -> String {
+    link.query_pairs()
+        .find(|(name, _)| name == "token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+async fn accept(app: &TestApp, token: &str, username: &str) -> reqwest::Response {
+    app.form_post(
+        "/invitations/accept",
+        serde_json::json!({
+            "token": token,
+            "username": username,
+            "password": PASSWORD,
+            "password_check": PASSWORD,
+        }),
+    )
+    .send()
+    .await
+    .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn owners_can_invite_users_by_email() {
+    let app = spawn_app().await;
+
+    let (_, link) = invite(&app, "ursula@example.com").await;
+
+    assert_eq!(link.path(), "/invitations/accept");
+    let html_page = reqwest::get(link).await.unwrap().text().await.unwrap();
+    assert!(html_page.contains("invited to join as editor"));
+}
+
+#[tokio::test]
+async fn editors_cannot_invite_users() {
+    let app = spawn_app().await;
+    app.set_test_user_role("editor").await;
+
+    let response = app
+        .post_admin(
+            "invitations",
+            &serde_json::json!({ "email": "ursula@example.com", "role": "editor" }),
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn addresses_of_existing_users_cannot_be_invited() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_admin(
+            "invitations",
+            &serde_json::json!({ "email": app.test_user.email, "role": "viewer" }),
+        )
+        .await;
+
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn the_user_is_created_when_the_invitation_is_accepted() {
+    let app = spawn_app().await;
+    let (_, link) = invite(&app, "ursula@example.com").await;
+    let users_before: i64 = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM users"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(users_before, 1);
+
+    let response = accept(&app, &token(&link), "ursula").await;
+
+    assert_is_redirect_to(&response, "/login");
+    let user = sqlx::query!("SELECT email, role FROM users WHERE username = 'ursula'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(user.email.as_deref(), Some("ursula@example.com"));
+    assert_eq!(user.role, "editor");
+    let response = app
+        .post_login(&serde_json::json!({ "username": "ursula", "password": PASSWORD }))
+        .await;
+    assert_is_redirect_to(&response, "/");
+}
+
+#[tokio::test]
+async fn addresses_taken_in_another_case_keep_the_invitation_pending() {
+    let app = spawn_app().await;
+    let (_, link) = invite(&app, "ursula@example.com").await;
+    sqlx::query!(
+        "UPDATE users SET email = 'Ursula@Example.com' WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let response = accept(&app, &token(&link), "ursula").await;
+
+    assert_eq!(
+        flash_message(&response),
+        "A user already has this email address"
+    );
+    let users: i64 = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM users"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(users, 1);
+}
+
+#[tokio::test]
+async fn invitations_can_only_be_accepted_once() {
+    let app = spawn_app().await;
+    let (_, link) = invite(&app, "ursula@example.com").await;
+    accept(&app, &token(&link), "ursula").await;
+
+    let response = accept(&app, &token(&link), "ursula-again").await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert_eq!(
+        flash_message(&response),
+        "The invitation is invalid or has expired."
+    );
+}
+
+#[tokio::test]
+async fn revoked_invitations_cannot_be_accepted() {
+    let app = spawn_app().await;
+    let (invitation_id, link) = invite(&app, "ursula@example.com").await;
+
+    let response = app
+        .api_client
+        .delete(&format!(
+            "{}/admin/invitations/{}",
+            &app.address, invitation_id
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = accept(&app, &token(&link), "ursula").await;
+
+    assert_eq!(
+        flash_message(&response),
+        "The invitation is invalid or has expired."
+    );
+    let pending: serde_json::Value = app.get_admin("invitations").await.json().await.unwrap();
+    assert_eq!(pending["invitations"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn expired_invitations_cannot_be_accepted() {
+    let app = spawn_app().await;
+    let (_, link) = invite(&app, "ursula@example.com").await;
+    sqlx::query!("UPDATE invitations SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = accept(&app, &token(&link), "ursula").await;
+
+    assert_eq!(
+        flash_message(&response),
+        "The invitation is invalid or has expired."
+    );
+}
+
+#[tokio::test]
+async fn forged_invitation_links_are_rejected() {
+    let app = spawn_app().await;
+    let (_, link) = invite(&app, "ursula@example.com").await;
+    let token = token(&link);
+    let (payload, _) = token.split_once('.').unwrap();
+    let forged = format!("{}.{}", payload, "00".repeat(32));
+
+    let response = accept(&app, &forged, "ursula").await;
+
+    assert_eq!(
+        flash_message(&response),
+        "The invitation is invalid or has expired."
+    );
+    let users: i64 = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM users"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(users, 1);
+}
+
+#[tokio::test]
+async fn taken_usernames_keep_the_invitation_pending() {
+    let app = spawn_app().await;
+    let (invitation_id, link) = invite(&app, "ursula@example.com").await;
+
+    let response = accept(&app, &token(&link), &app.test_user.username).await;
+
+    assert_eq!(flash_message(&response), "This username is already taken");
+    let pending: serde_json::Value = app.get_admin("invitations").await.json().await.unwrap();
+    assert_eq!(
+        pending["invitations"][0]["invitation_id"]
+            .as_str()
+            .unwrap()
+            .parse::<Uuid>()
+            .unwrap()
+            .to_string(),
+        invitation_id
+    );
+}
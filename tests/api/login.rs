@@ -1,6 +1,11 @@
 //! tests/api/login.rs
 
-use crate::helper::{assert_is_redirect_to, spawn_app};
+use blog_backend::configuration::Settings;
+use blog_backend::login_throttle::{unlock, AttemptKey};
+use hyper::StatusCode;
+use uuid::Uuid;
+
+use crate::helper::{assert_is_redirect_to, spawn_app, spawn_app_with, TestApp};
 
 #[tokio::test]
 async fn an_error_flash_message_is_set_on_failure() {
@@ -25,3 +30,211 @@ async fn an_error_flash_message_is_set_on_failure() {
 
     assert!(!html_page.contains(r#"<p><i>Authentication failed</i></p>"#));
 }
+
+#[tokio::test]
+async fn flash_messages_are_escaped() {
+    let app = spawn_app().await;
+
+    let html_page = reqwest::Client::new()
+        .get(&format!("{}/login", &app.address))
+        .header("Cookie", "_flash=<script>alert(1)</script>")
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .text()
+        .await
+        .unwrap();
+
+    assert!(!html_page.contains("<script>"));
+    assert!(html_page.contains("<p><i>&lt;script&gt;alert(1)&lt;/script&gt;</i></p>"));
+}
+
+/// No delay between attempts, so that only lockouts get in the way.
+fn without_delay(c: &mut Settings) {
+    c.login_throttle.base_delay_milliseconds = 0;
+}
+
+async fn login_with(app: &TestApp, username: &str, password: &str) -> reqwest::Response {
+    app.post_login(&serde_json::json!({
+        "username": username,
+        "password": password
+    }))
+    .await
+}
+
+fn flash_message(response: &reqwest::Response) -> String {
+    response
+        .cookies()
+        .find(|c| c.name() == "_flash")
+        .unwrap()
+        .value()
+        .to_owned()
+}
+
+#[tokio::test]
+async fn a_username_is_locked_out_after_too_many_failures() {
+    let app = spawn_app_with(|c| {
+        without_delay(c);
+        c.login_throttle.max_failed_attempts_per_username = 3;
+    })
+    .await;
+    let username = &app.test_user.username;
+
+    for _ in 0..3 {
+        let response = login_with(&app, username, "wrong-password").await;
+        assert_eq!(flash_message(&response), "Authentication failed");
+    }
+    let response = login_with(&app, username, &app.test_user.password).await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert_eq!(
+        flash_message(&response),
+        "Too many failed attempts. Try again later"
+    );
+}
+
+#[tokio::test]
+async fn a_client_ip_is_locked_out_after_too_many_failures() {
+    let app = spawn_app_with(|c| {
+        without_delay(c);
+        c.login_throttle.max_failed_attempts_per_ip = 3;
+    })
+    .await;
+
+    for _ in 0..3 {
+        login_with(&app, &Uuid::new_v4().to_string(), "wrong-password").await;
+    }
+    let response = login_with(&app, &app.test_user.username, &app.test_user.password).await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn attempts_right_after_a_failure_are_delayed() {
+    let app = spawn_app_with(|c| c.login_throttle.base_delay_milliseconds = 60_000).await;
+
+    login_with(&app, &app.test_user.username, "wrong-password").await;
+    let response = login_with(&app, &app.test_user.username, &app.test_user.password).await;
+
+    assert_eq!(
+        flash_message(&response),
+        "Too many failed attempts. Try again later"
+    );
+}
+
+#[tokio::test]
+async fn a_successful_login_forgets_previous_failures() {
+    let app = spawn_app_with(|c| {
+        without_delay(c);
+        c.login_throttle.max_failed_attempts_per_username = 3;
+    })
+    .await;
+    let username = &app.test_user.username;
+
+    for _ in 0..2 {
+        login_with(&app, username, "wrong-password").await;
+    }
+    login_with(&app, username, &app.test_user.password).await;
+    let response = app
+        .post_two_factor_code(&app.test_user.two_factor_code())
+        .await;
+    assert_is_redirect_to(&response, "/");
+    for _ in 0..2 {
+        login_with(&app, username, "wrong-password").await;
+    }
+    let response = login_with(&app, username, &app.test_user.password).await;
+
+    assert_is_redirect_to(&response, "/login/two-factor");
+}
+
+#[tokio::test]
+async fn login_failures_lock_basic_auth_out_as_well() {
+    let app = spawn_app_with(|c| {
+        without_delay(c);
+        c.login_throttle.max_failed_attempts_per_username = 2;
+    })
+    .await;
+
+    for _ in 0..2 {
+        login_with(&app, &app.test_user.username, "wrong-password").await;
+    }
+    let response = app
+        .get_admin("newsletters/00000000-0000-0000-0000-000000000000/stats")
+        .await;
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().get("Retry-After").is_some());
+}
+
+#[tokio::test]
+async fn unlocking_a_username_lifts_its_lockout() {
+    let app = spawn_app_with(|c| {
+        without_delay(c);
+        c.login_throttle.max_failed_attempts_per_username = 1;
+    })
+    .await;
+    let username = &app.test_user.username;
+    login_with(&app, username, "wrong-password").await;
+
+    let unlocked = unlock(&app.db_pool, &AttemptKey::Username(username.clone()))
+        .await
+        .unwrap();
+    let response = login_with(&app, username, &app.test_user.password).await;
+
+    assert!(unlocked);
+    assert_is_redirect_to(&response, "/login/two-factor");
+}
+
+async fn stored_password_hash(app: &TestApp) -> String {
+    sqlx::query_scalar!(
+        "SELECT password_hash FROM users WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn password_hashes_are_upgraded_to_the_configured_parameters_on_login() {
+    let app = spawn_app_with(|c| {
+        c.password_hashing.memory_size_kib = 8192;
+        c.password_hashing.iterations = 1;
+    })
+    .await;
+    let username = &app.test_user.username;
+
+    let response = login_with(&app, username, &app.test_user.password).await;
+    assert_is_redirect_to(&response, "/login/two-factor");
+
+    assert!(stored_password_hash(&app)
+        .await
+        .starts_with("$argon2id$v=19$m=8192,t=1,p=1$"));
+    let response = login_with(&app, username, &app.test_user.password).await;
+    assert_is_redirect_to(&response, "/login/two-factor");
+}
+
+#[tokio::test]
+async fn password_hashes_with_the_configured_parameters_are_kept() {
+    let app = spawn_app_with(|c| {
+        c.password_hashing.memory_size_kib = 15000;
+        c.password_hashing.iterations = 2;
+        c.password_hashing.parallelism = 1;
+    })
+    .await;
+    let password_hash = stored_password_hash(&app).await;
+
+    login_with(&app, &app.test_user.username, &app.test_user.password).await;
+
+    assert_eq!(stored_password_hash(&app).await, password_hash);
+}
+
+#[tokio::test]
+async fn password_hashes_are_not_upgraded_on_failed_logins() {
+    let app = spawn_app_with(|c| c.password_hashing.memory_size_kib = 8192).await;
+    let password_hash = stored_password_hash(&app).await;
+
+    login_with(&app, &app.test_user.username, "wrong-password").await;
+
+    assert_eq!(stored_password_hash(&app).await, password_hash);
+}
@@ -1,6 +1,19 @@
+mod api_tokens;
+mod auth_events;
+mod csrf;
+mod email_webhooks;
 mod health_check;
 mod helper;
+mod invitations;
 mod login;
+mod metrics;
 mod newsletter;
+mod oidc;
+mod password_reset;
+mod roles;
+mod security_headers;
+mod shutdown;
 mod subscriptions;
 mod subscriptions_confirm;
+mod tracking;
+mod two_factor;
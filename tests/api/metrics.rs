@@ -0,0 +1,150 @@
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helper::{
+    batch_response, create_confirmed_subscriber, create_confirmed_subscriber_with_email,
+    create_unconfirmed_subscriber, spawn_app, TestApp,
+};
+
+async fn get_metrics(app: &TestApp) -> String {
+    let response = app
+        .api_client
+        .get(&format!("{}/metrics", &app.address))
+        .bearer_auth(&app.metrics_token)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/plain; version=0.0.4"
+    );
+    response.text().await.unwrap()
+}
+
+#[tokio::test]
+async fn metrics_require_the_metrics_token() {
+    let app = spawn_app().await;
+
+    for token in [None, Some("a-wrong-token")] {
+        let mut request = app.api_client.get(&format!("{}/metrics", &app.address));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.expect("Failed to execute request.");
+
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(
+            response.headers()["WWW-Authenticate"],
+            r#"Bearer realm="metrics""#
+        );
+    }
+}
+
+#[tokio::test]
+async fn requests_are_counted_by_route_and_status() {
+    let app = spawn_app().await;
+    let newsletter_issue_id = Uuid::new_v4();
+
+    app.api_client
+        .get(&format!("{}/health_check", &app.address))
+        .send()
+        .await
+        .unwrap();
+    let response = app
+        .get_newsletter_issue_stats(&newsletter_issue_id.to_string())
+        .await;
+    assert_eq!(response.status().as_u16(), 404);
+
+    let metrics = get_metrics(&app).await;
+    assert!(metrics
+        .contains(r#"http_requests_total{method="GET",route="/health_check",status="200"} 1"#));
+    // The route, rather than the path of the request.
+    assert!(metrics.contains(
+        r#"http_requests_total{method="GET",route="/admin/newsletters/:newsletter_issue_id/stats",status="404"} 1"#
+    ));
+    assert!(!metrics.contains(&newsletter_issue_id.to_string()));
+    assert!(metrics.contains(
+        r#"http_request_duration_seconds_count{method="GET",route="/health_check",status="200"} 1"#
+    ));
+}
+
+#[tokio::test]
+async fn email_sends_are_counted_by_outcome() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let metrics = get_metrics(&app).await;
+    // The confirmation email, then the issue.
+    assert!(metrics.contains(r#"email_messages_total{outcome="sent"} 2"#));
+    assert!(metrics.contains(r#"email_messages_total{outcome="failed"} 0"#));
+    assert!(metrics.contains(
+        r#"email_api_request_duration_seconds_count{endpoint="email",outcome="sent"} 1"#
+    ));
+    assert!(metrics.contains(
+        r#"email_api_request_duration_seconds_count{endpoint="email_batch",outcome="sent"} 1"#
+    ));
+}
+
+#[tokio::test]
+async fn failed_email_sends_are_counted() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let metrics = get_metrics(&app).await;
+    assert!(metrics.contains(r#"email_messages_total{outcome="failed"} 1"#));
+    assert!(metrics.contains(
+        r#"email_api_request_duration_seconds_count{endpoint="email",outcome="failed"} 1"#
+    ));
+}
+
+#[tokio::test]
+async fn subscribers_are_counted_by_status() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    create_confirmed_subscriber_with_email(&app, "octavia_butler@gmail.com").await;
+
+    let metrics = get_metrics(&app).await;
+
+    assert!(metrics.contains(r#"subscribers{status="pending_confirmation"} 1"#));
+    assert!(metrics.contains(r#"subscribers{status="confirmed"} 1"#));
+    assert!(metrics.contains("newsletter_delivery_queue_depth 0"));
+}
+
+#[tokio::test]
+async fn the_database_pool_is_reported() {
+    let app = spawn_app().await;
+
+    let metrics = get_metrics(&app).await;
+
+    assert!(metrics.contains(r#"db_pool_connections{state="idle"}"#));
+    assert!(metrics.contains(r#"db_pool_connections{state="in_use"}"#));
+    assert!(metrics.contains("db_pool_max_connections 10"));
+}
@@ -1,13 +1,18 @@
 //! tests/api/newsletter.rs
 
+use blog_backend::routes::MAX_CONTENT_SIZE;
 use hyper::StatusCode;
+use reqwest::multipart::{Form, Part};
 use uuid::Uuid;
 use wiremock::{
     matchers::{any, method, path},
     Mock, ResponseTemplate,
 };
 
-use crate::helper::{spawn_app, ConfirmationLinks, TestApp};
+use crate::helper::{
+    batch_response, create_confirmed_subscriber, create_confirmed_subscriber_with_email,
+    create_unconfirmed_subscriber, spawn_app,
+};
 
 #[tokio::test]
 async fn newsletter_are_not_delivered_to_unconfirmed_subscribers() {
@@ -46,9 +51,9 @@ async fn newsletters_are_delivered_to_confired_susbcribers() {
     let app = spawn_app().await;
     create_confirmed_subscriber(&app).await;
 
-    Mock::given(path("/email"))
+    Mock::given(path("/email/batch"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(batch_response(1))
         .expect(1)
         .mount(&app.email_server)
         .await;
@@ -65,6 +70,430 @@ async fn newsletters_are_delivered_to_confired_susbcribers() {
     assert_eq!(response.status().as_u16(), 200);
 }
 
+#[tokio::test]
+async fn newsletter_issues_are_tagged_and_sent_through_the_broadcast_stream() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // Assert
+    let body: serde_json::Value = response.json().await.unwrap();
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let messages: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(messages[0]["From"], "Blog Backend <test@gmail.com>");
+    assert_eq!(messages[0]["MessageStream"], "broadcast");
+    assert_eq!(messages[0]["Tag"], "newsletter");
+    assert_eq!(
+        messages[0]["Metadata"]["newsletter_issue_id"],
+        body["newsletter_issue_id"]
+    );
+}
+
+#[tokio::test]
+async fn issues_can_be_sent_from_another_sender_identity() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let mut body = newsletter_request_body();
+    body["sender"] = "editorial".into();
+
+    // Act
+    let response = app.post_newsletters(body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let messages: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(messages[0]["From"], "The Editors <editors@example.com>");
+}
+
+#[tokio::test]
+async fn issues_from_an_unknown_sender_identity_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let mut body = newsletter_request_body();
+    body["sender"] = "marketing".into();
+
+    // Act
+    let response = app.post_newsletters(body).await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn successful_deliveries_are_recorded() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817"
+            }])),
+        )
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let newsletter_issue_id = body["newsletter_issue_id"].as_str().unwrap();
+
+    let progress: serde_json::Value = app
+        .get_newsletter_issue_deliveries(newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(progress["total"], 1);
+    assert_eq!(progress["sent"], 1);
+    assert_eq!(progress["failed"], 0);
+
+    let saved = sqlx::query!("SELECT n_attempts, status, provider_message_id FROM deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved delivery.");
+    assert_eq!(saved.n_attempts, 1);
+    assert_eq!(saved.status, "sent");
+    assert_eq!(
+        saved.provider_message_id.as_deref(),
+        Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+    );
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_recorded_and_reported() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        // The failure is transient, so the delivery is retried before giving up
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let newsletter_issue_id = body["newsletter_issue_id"].as_str().unwrap();
+
+    let progress: serde_json::Value = app
+        .get_newsletter_issue_deliveries(newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(progress["total"], 1);
+    assert_eq!(progress["failed"], 1);
+
+    let failures: serde_json::Value = app
+        .get_failed_newsletter_issue_deliveries(newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(failures[0]["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(failures[0]["n_attempts"], 3);
+    assert!(failures[0]["last_error"].is_string());
+}
+
+#[tokio::test]
+async fn only_failed_messages_of_a_batch_are_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "ursula_le_guin@gmail.com").await;
+    create_confirmed_subscriber_with_email(&app, "octavia_butler@gmail.com").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            {"ErrorCode": 0, "Message": "OK", "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817"},
+            {"ErrorCode": 100, "Message": "Maintenance"}
+        ])))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let requests = app.email_server.received_requests().await.unwrap();
+    let first_batch: serde_json::Value = serde_json::from_slice(&requests[2].body).unwrap();
+    let retried_batch: serde_json::Value = serde_json::from_slice(&requests[3].body).unwrap();
+    assert_eq!(first_batch.as_array().unwrap().len(), 2);
+    assert_eq!(retried_batch.as_array().unwrap().len(), 1);
+    assert_eq!(retried_batch[0]["To"], first_batch[1]["To"]);
+
+    let saved = sqlx::query!("SELECT n_attempts, status FROM deliveries ORDER BY n_attempts")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved deliveries.");
+    assert_eq!(saved.len(), 2);
+    assert!(saved.iter().all(|d| d.status == "sent"));
+    assert_eq!(saved[0].n_attempts, 1);
+    assert_eq!(saved[1].n_attempts, 2);
+}
+
+#[tokio::test]
+async fn delivery_progress_of_an_unknown_issue_is_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .get_newsletter_issue_deliveries(&Uuid::new_v4().to_string())
+        .await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn newsletters_can_be_published_with_attachments() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let form = newsletter_form()
+        .part(
+            "attachment",
+            Part::bytes(&b"Hello"[..])
+                .file_name("notes.txt")
+                .mime_str("text/plain")
+                .unwrap(),
+        )
+        .part(
+            "inline",
+            Part::bytes(&[137, 80, 78, 71][..])
+                .file_name("logo.png")
+                .mime_str("image/png")
+                .unwrap(),
+        );
+
+    // Act
+    let response = app.post_newsletters_multipart(form).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body[0]["Subject"], "Newsletter title");
+    assert_eq!(
+        body[0]["Attachments"],
+        serde_json::json!([
+            { "Name": "notes.txt", "Content": "SGVsbG8=", "ContentType": "text/plain" },
+            {
+                "Name": "logo.png",
+                "Content": "iVBORw==",
+                "ContentType": "image/png",
+                "ContentID": "cid:logo.png"
+            },
+        ])
+    );
+
+    let saved = sqlx::query!("SELECT name FROM newsletter_issue_attachments ORDER BY name")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved attachments.");
+    let names: Vec<_> = saved.into_iter().map(|r| r.name).collect();
+    assert_eq!(names, vec!["logo.png", "notes.txt"]);
+}
+
+#[tokio::test]
+async fn attachments_over_the_size_limit_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // 10MB of raw content takes more than 10MB once base64-encoded.
+    let form = newsletter_form().part(
+        "attachment",
+        Part::bytes(vec![0; 10 * 1024 * 1024])
+            .file_name("report.pdf")
+            .mime_str("application/pdf")
+            .unwrap(),
+    );
+
+    // Act
+    let response = app.post_newsletters_multipart(form).await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn content_within_the_limit_is_accepted_however_large_its_encoding() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Control characters take 6 bytes each once escaped in JSON.
+    let text = "\u{1}".repeat(MAX_CONTENT_SIZE - 1024);
+    assert!(serde_json::to_vec(&text).unwrap().len() > 5 * MAX_CONTENT_SIZE);
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "text": text, "html": "<p>Newsletter body as HTML</p>" }
+    });
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn content_over_the_size_limit_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "a".repeat(MAX_CONTENT_SIZE),
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn only_images_can_be_inlined() {
+    // Arrange
+    let app = spawn_app().await;
+
+    let form = newsletter_form().part(
+        "inline",
+        Part::bytes(&b"Hello"[..])
+            .file_name("notes.txt")
+            .mime_str("text/plain")
+            .unwrap(),
+    );
+
+    // Act
+    let response = app.post_newsletters_multipart(form).await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+fn newsletter_form() -> Form {
+    Form::new()
+        .text("title", "Newsletter title")
+        .text("text", "Newsletter body as plain text")
+        .text(
+            "html",
+            r#"<p>Newsletter body as HTML</p><img src="cid:logo.png">"#,
+        )
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
 #[tokio::test]
 async fn newsletters_returns_422_for_invalid_data() {
     let app = spawn_app().await;
@@ -96,42 +525,6 @@ async fn newsletters_returns_422_for_invalid_data() {
     }
 }
 
-/// Use the public API of the application under test to create
-/// an unconfirmed subscriber
-async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
-    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
-
-    let _mock_guard = Mock::given(path("/email"))
-        .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
-        .expect(1)
-        .mount_as_scoped(&app.email_server)
-        .await;
-
-    app.post_subscriptions(body.into())
-        .await
-        .error_for_status()
-        .unwrap();
-
-    let email_request = &app
-        .email_server
-        .received_requests()
-        .await
-        .unwrap()
-        .pop()
-        .unwrap();
-    app.get_confirmation_links(email_request)
-}
-
-async fn create_confirmed_subscriber(app: &TestApp) {
-    let confirmation_link = create_unconfirmed_subscriber(app).await;
-    reqwest::get(confirmation_link.html)
-        .await
-        .unwrap()
-        .error_for_status()
-        .unwrap();
-}
-
 #[tokio::test]
 async fn requests_missing_authorization_are_rejected() {
     // Arrange
@@ -0,0 +1,419 @@
+use base64::Engine;
+use blog_backend::configuration::OidcSettings;
+use chrono::{Duration, Utc};
+use secrecy::Secret;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use wiremock::{
+    matchers::{header_exists, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use crate::helper::{assert_is_redirect_to, spawn_app, spawn_app_with, TestApp};
+
+const CLIENT_ID: &str = "blog";
+
+/// The application, logging in through an identity provider mocked by the server.
+async fn spawn_app_with_provider() -> (TestApp, MockServer) {
+    spawn_app_with_provider_named("Example ID").await
+}
+
+async fn spawn_app_with_provider_named(display_name: &str) -> (TestApp, MockServer) {
+    let provider = MockServer::start().await;
+    let issuer_url = provider.uri();
+    let app = spawn_app_with(|c| {
+        c.application.oidc = Some(OidcSettings {
+            issuer_url,
+            client_id: CLIENT_ID.into(),
+            client_secret: Secret::new("a-client-secret".into()),
+            display_name: display_name.into(),
+            timeout_milliseconds: 2000,
+        })
+    })
+    .await;
+
+    Mock::given(path("/.well-known/openid-configuration"))
+        .and(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "issuer": provider.uri(),
+            "authorization_endpoint": format!("{}/authorize", provider.uri()),
+            "token_endpoint": format!("{}/token", provider.uri()),
+        })))
+        .mount(&provider)
+        .await;
+    (app, provider)
+}
+
+/// A login the application sent the browser to the provider for.
+struct StartedLogin {
+    state: String,
+    nonce: String,
+    code_challenge: String,
+}
+
+async fn start_login(app: &TestApp) -> StartedLogin {
+    let response = app
+        .api_client
+        .get(&format!("{}/login/oidc", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status().as_u16(), 303);
+    let location = response.headers()["Location"].to_str().unwrap();
+    let url = reqwest::Url::parse(location).unwrap();
+    let parameter = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap()
+    };
+    StartedLogin {
+        state: parameter("state"),
+        nonce: parameter("nonce"),
+        code_challenge: parameter("code_challenge"),
+    }
+}
+
+/// The claims a provider vouches for on a successful login.
+fn claims(provider: &MockServer, login: &StartedLogin, email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "iss": provider.uri(),
+        "sub": Uuid::new_v4().to_string(),
+        "aud": CLIENT_ID,
+        "exp": (Utc::now() + Duration::minutes(5)).timestamp(),
+        "iat": Utc::now().timestamp(),
+        "nonce": login.nonce,
+        "email": email,
+        "email_verified": true,
+    })
+}
+
+/// An unsigned JWT carrying the claims, which is all the application reads.
+fn id_token(claims: &serde_json::Value) -> String {
+    let encode = |value: &serde_json::Value| {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+    };
+    format!(
+        "{}.{}.c2lnbmF0dXJl",
+        encode(&serde_json::json!({ "alg": "RS256", "typ": "JWT" })),
+        encode(claims)
+    )
+}
+
+/// Has the token endpoint hand out an ID token with the claims, once.
+async fn mount_token_endpoint(provider: &MockServer, claims: &serde_json::Value) {
+    Mock::given(path("/token"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "access_token": "an-access-token",
+            "token_type": "Bearer",
+            "id_token": id_token(claims),
+        })))
+        .up_to_n_times(1)
+        .mount(provider)
+        .await;
+}
+
+async fn get_callback(app: &TestApp, query: &[(&str, &str)]) -> reqwest::Response {
+    app.api_client
+        .get(&format!("{}/login/oidc/callback", &app.address))
+        .query(query)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn oidc_subject(app: &TestApp) -> Option<String> {
+    sqlx::query_scalar!(
+        "SELECT oidc_subject FROM users WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn logging_in_through_a_provider_is_disabled_unless_configured() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(&format!("{}/login/oidc", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 404);
+    assert!(!app.get_login_html().await.contains("/login/oidc"));
+}
+
+#[tokio::test]
+async fn the_login_form_links_to_the_provider() {
+    let (app, _provider) = spawn_app_with_provider().await;
+
+    let html_page = app.get_login_html().await;
+
+    assert!(html_page.contains(r#"<a href="/login/oidc">Log in with Example ID</a>"#));
+}
+
+#[tokio::test]
+async fn the_provider_name_is_escaped_in_the_login_form() {
+    let (app, _provider) = spawn_app_with_provider_named("<b>Example</b> & co").await;
+
+    let html_page = app.get_login_html().await;
+
+    assert!(html_page.contains("Log in with &lt;b&gt;Example&lt;/b&gt; &amp; co</a>"));
+}
+
+#[tokio::test]
+async fn the_provider_metadata_is_fetched_once_for_several_logins() {
+    let (app, provider) = spawn_app_with_provider().await;
+
+    for _ in 0..2 {
+        let login = start_login(&app).await;
+        let claims = claims(&provider, &login, &app.test_user.email);
+        mount_token_endpoint(&provider, &claims).await;
+        get_callback(&app, &[("code", "a-code"), ("state", &login.state)]).await;
+    }
+
+    let discoveries = provider
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|request| request.url.path() == "/.well-known/openid-configuration")
+        .count();
+    assert_eq!(discoveries, 1);
+}
+
+#[tokio::test]
+async fn users_are_sent_to_the_authorization_endpoint_with_a_pkce_challenge() {
+    let (app, provider) = spawn_app_with_provider().await;
+
+    let response = app
+        .api_client
+        .get(&format!("{}/login/oidc", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 303);
+    let url = reqwest::Url::parse(response.headers()["Location"].to_str().unwrap()).unwrap();
+    assert!(url
+        .as_str()
+        .starts_with(&format!("{}/authorize?", provider.uri())));
+    let parameters: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(parameters["response_type"], "code");
+    assert_eq!(parameters["client_id"], CLIENT_ID);
+    assert_eq!(
+        parameters["redirect_uri"],
+        "http://127.0.0.1/login/oidc/callback"
+    );
+    assert_eq!(parameters["code_challenge_method"], "S256");
+    assert!(parameters["scope"]
+        .split(' ')
+        .any(|scope| scope == "openid"));
+    assert!(!parameters["state"].is_empty());
+    assert!(!parameters["nonce"].is_empty());
+}
+
+#[tokio::test]
+async fn a_first_login_links_the_identity_through_the_verified_email() {
+    let (app, provider) = spawn_app_with_provider().await;
+    let login = start_login(&app).await;
+    let claims = claims(&provider, &login, &app.test_user.email);
+    mount_token_endpoint(&provider, &claims).await;
+
+    let response = get_callback(&app, &[("code", "a-code"), ("state", &login.state)]).await;
+
+    assert_is_redirect_to(&response, "/");
+    assert_eq!(oidc_subject(&app).await.as_deref(), claims["sub"].as_str());
+    let event =
+        sqlx::query!("SELECT username, detail FROM auth_events WHERE kind = 'login_succeeded'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        event.username.as_deref(),
+        Some(app.test_user.username.as_str())
+    );
+    assert_eq!(event.detail.as_deref(), Some("with OpenID Connect"));
+}
+
+#[tokio::test]
+async fn later_logins_are_matched_by_subject() {
+    let (app, provider) = spawn_app_with_provider().await;
+    let login = start_login(&app).await;
+    let first_claims = claims(&provider, &login, &app.test_user.email);
+    mount_token_endpoint(&provider, &first_claims).await;
+    get_callback(&app, &[("code", "a-code"), ("state", &login.state)]).await;
+
+    // The provider no longer vouches for the email address.
+    let login = start_login(&app).await;
+    let mut claims = claims(&provider, &login, "another@example.com");
+    claims["sub"] = first_claims["sub"].clone();
+    claims["email_verified"] = false.into();
+    mount_token_endpoint(&provider, &claims).await;
+    let response = get_callback(&app, &[("code", "a-code"), ("state", &login.state)]).await;
+
+    assert_is_redirect_to(&response, "/");
+}
+
+#[tokio::test]
+async fn the_code_is_redeemed_with_the_client_secret_and_the_pkce_verifier() {
+    let (app, provider) = spawn_app_with_provider().await;
+    let login = start_login(&app).await;
+    Mock::given(path("/token"))
+        .and(method("POST"))
+        .and(header_exists("Authorization"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "id_token": id_token(&claims(&provider, &login, &app.test_user.email)),
+        })))
+        .expect(1)
+        .mount(&provider)
+        .await;
+
+    get_callback(&app, &[("code", "a-code"), ("state", &login.state)]).await;
+
+    let token_request = provider
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|request| request.url.path() == "/token")
+        .unwrap();
+    let form: std::collections::HashMap<_, _> = std::str::from_utf8(&token_request.body)
+        .unwrap()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key, urlencoding::decode(value).unwrap().into_owned()))
+        .collect();
+    assert_eq!(form["grant_type"], "authorization_code");
+    assert_eq!(form["code"], "a-code");
+    assert_eq!(form["redirect_uri"], "http://127.0.0.1/login/oidc/callback");
+    let challenge = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(Sha256::digest(form["code_verifier"].as_bytes()));
+    assert_eq!(challenge, login.code_challenge);
+}
+
+#[tokio::test]
+async fn identities_without_an_account_are_rejected() {
+    let (app, provider) = spawn_app_with_provider().await;
+    let test_cases = [
+        ("nobody@example.com", true, "an unknown email address"),
+        (
+            app.test_user.email.as_str(),
+            false,
+            "an unverified email address",
+        ),
+    ];
+
+    for (email, email_verified, description) in test_cases {
+        let login = start_login(&app).await;
+        let mut claims = claims(&provider, &login, email);
+        claims["email_verified"] = email_verified.into();
+        mount_token_endpoint(&provider, &claims).await;
+
+        let response = get_callback(&app, &[("code", "a-code"), ("state", &login.state)]).await;
+
+        assert_is_redirect_to(&response, "/login");
+        assert!(
+            app.get_login_html()
+                .await
+                .contains("There is no account for this identity."),
+            "The login was not rejected for {}.",
+            description
+        );
+    }
+    assert_eq!(oidc_subject(&app).await, None);
+}
+
+/// Spoils claims that would otherwise be valid.
+type Tamper = fn(&mut serde_json::Value);
+
+#[tokio::test]
+async fn invalid_id_tokens_are_rejected() {
+    let (app, provider) = spawn_app_with_provider().await;
+    let test_cases: [(Tamper, &str); 4] = [
+        (|c| c["nonce"] = "another-nonce".into(), "another nonce"),
+        (|c| c["aud"] = "another-client".into(), "another audience"),
+        (
+            |c| c["exp"] = (Utc::now() - Duration::minutes(1)).timestamp().into(),
+            "an expired token",
+        ),
+        (
+            |c| c["iss"] = "https://evil.example.com".into(),
+            "another issuer",
+        ),
+    ];
+
+    for (tamper, description) in test_cases {
+        let login = start_login(&app).await;
+        let mut claims = claims(&provider, &login, &app.test_user.email);
+        tamper(&mut claims);
+        mount_token_endpoint(&provider, &claims).await;
+
+        let response = get_callback(&app, &[("code", "a-code"), ("state", &login.state)]).await;
+
+        assert_is_redirect_to(&response, "/login");
+        assert!(
+            app.get_login_html()
+                .await
+                .contains("The identity provider sent an invalid ID token."),
+            "The ID token was not rejected for {}.",
+            description
+        );
+    }
+    assert_eq!(oidc_subject(&app).await, None);
+}
+
+#[tokio::test]
+async fn a_callback_with_another_state_is_rejected() {
+    let (app, provider) = spawn_app_with_provider().await;
+    Mock::given(path("/token"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&provider)
+        .await;
+    start_login(&app).await;
+
+    let response = get_callback(&app, &[("code", "a-code"), ("state", "another-state")]).await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert!(app
+        .get_login_html()
+        .await
+        .contains("The login with the identity provider is invalid or has expired."));
+}
+
+#[tokio::test]
+async fn a_callback_without_a_started_login_is_rejected() {
+    let (app, _provider) = spawn_app_with_provider().await;
+
+    let response = get_callback(&app, &[("code", "a-code"), ("state", "a-state")]).await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn a_login_refused_by_the_provider_is_rejected() {
+    let (app, _provider) = spawn_app_with_provider().await;
+    let login = start_login(&app).await;
+
+    let response = get_callback(&app, &[("error", "access_denied"), ("state", &login.state)]).await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert!(app
+        .get_login_html()
+        .await
+        .contains("The identity provider refused the login."));
+    let detail = sqlx::query_scalar!("SELECT detail FROM auth_events WHERE kind = 'login_failed'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        detail.as_deref(),
+        Some("with OpenID Connect: The identity provider refused the login.")
+    );
+}
@@ -0,0 +1,291 @@
+//! tests/api/password_reset.rs
+
+use std::time::Duration;
+
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helper::{assert_is_redirect_to, spawn_app, TestApp};
+
+const NEW_PASSWORD: &str = "a-brand-new-password";
+
+fn flash_message(response: &reqwest::Response) -> String {
+    response
+        .cookies()
+        .find(|c| c.name() == "_flash")
+        .unwrap()
+        .value()
+        .to_owned()
+}
+
+async fn request_reset(app: &TestApp, email: &str) -> reqwest::Response {
+    app.form_post(
+        "/login/forgot-password",
+        serde_json::json!({ "email": email }),
+    )
+    .send()
+    .await
+    .expect("Failed to execute request.")
+}
+
+/// Waits for the reset emails, which are sent after answering the request.
+async fn received_emails(app: &TestApp, count: usize) -> Vec<wiremock::Request> {
+    for _ in 0..100 {
+        let requests = app.email_server.received_requests().await.unwrap();
+        if requests.len() >= count {
+            return requests;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Expected {} emails to be sent", count);
+}
+
+/// Requests a reset for the test user and returns the token from the email.
+async fn reset_token(app: &TestApp) -> String {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    request_reset(app, &app.test_user.email).await;
+
+    let email_request = &received_emails(app, 1).await[0];
+    let link = app.get_confirmation_links(email_request).html;
+    assert_eq!(link.path(), "/login/reset-password");
+    link.query_pairs()
+        .find(|(name, _)| name == "token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+async fn reset_password(app: &TestApp, token: &str, password: &str) -> reqwest::Response {
+    app.form_post(
+        "/login/reset-password",
+        serde_json::json!({
+            "token": token,
+            "new_password": password,
+            "new_password_check": password,
+        }),
+    )
+    .send()
+    .await
+    .expect("Failed to execute request.")
+}
+
+async fn login_with(app: &TestApp, password: &str) -> reqwest::Response {
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": password
+    }))
+    .await
+}
+
+#[tokio::test]
+async fn a_reset_link_is_emailed_to_known_addresses() {
+    let app = spawn_app().await;
+
+    let token = reset_token(&app).await;
+
+    assert_eq!(token.len(), 32);
+}
+
+#[tokio::test]
+async fn unknown_addresses_get_the_same_answer_and_no_email() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = request_reset(&app, "nobody@example.com").await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert_eq!(
+        flash_message(&response),
+        "If this address belongs to an account a password reset link has been sent to it"
+    );
+}
+
+#[tokio::test]
+async fn only_a_few_links_are_sent_to_an_address_per_hour() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    for _ in 0..4 {
+        let response = request_reset(&app, &app.test_user.email).await;
+        assert_is_redirect_to(&response, "/login");
+    }
+
+    let links = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM password_reset_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(links, 3);
+    assert_eq!(received_emails(&app, 3).await.len(), 3);
+}
+
+#[tokio::test]
+async fn concurrent_requests_cannot_exceed_the_links_per_hour() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let responses =
+        futures::future::join_all((0..8).map(|_| request_reset(&app, &app.test_user.email))).await;
+
+    for response in &responses {
+        assert_is_redirect_to(response, "/login");
+    }
+    let links = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM password_reset_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(links, 3);
+}
+
+#[tokio::test]
+async fn the_reset_form_embeds_the_token() {
+    let app = spawn_app().await;
+    let token = reset_token(&app).await;
+
+    let html_page = app
+        .api_client
+        .get(&format!(
+            "{}/login/reset-password?token={}",
+            &app.address, token
+        ))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    assert!(html_page.contains(&format!(r#"name="token" value="{}""#, token)));
+}
+
+#[tokio::test]
+async fn malformed_tokens_are_not_embedded_in_the_reset_form() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(&format!(
+            "{}/login/reset-password?token=%22%3E%3Cscript%3E",
+            &app.address
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_is_redirect_to(&response, "/login/forgot-password");
+}
+
+#[tokio::test]
+async fn the_password_can_be_reset_with_the_link() {
+    let app = spawn_app().await;
+    let token = reset_token(&app).await;
+
+    let response = reset_password(&app, &token, NEW_PASSWORD).await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert_eq!(
+        flash_message(&response),
+        "Your password has been reset. Please log in"
+    );
+    assert_is_redirect_to(&login_with(&app, NEW_PASSWORD).await, "/login/two-factor");
+    assert_is_redirect_to(&login_with(&app, &app.test_user.password).await, "/login");
+}
+
+#[tokio::test]
+async fn reset_links_can_only_be_used_once() {
+    let app = spawn_app().await;
+    let token = reset_token(&app).await;
+    reset_password(&app, &token, NEW_PASSWORD).await;
+
+    let response = reset_password(&app, &token, "yet-another-password").await;
+
+    assert_is_redirect_to(&response, "/login/forgot-password");
+    assert_is_redirect_to(&login_with(&app, NEW_PASSWORD).await, "/login/two-factor");
+}
+
+#[tokio::test]
+async fn expired_reset_links_are_rejected() {
+    let app = spawn_app().await;
+    let token = reset_token(&app).await;
+    sqlx::query!("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reset_password(&app, &token, NEW_PASSWORD).await;
+
+    assert_is_redirect_to(&response, "/login/forgot-password");
+    assert_eq!(
+        flash_message(&response),
+        "The password reset link is invalid or has expired."
+    );
+}
+
+#[tokio::test]
+async fn passwords_must_follow_the_password_policy() {
+    let app = spawn_app().await;
+    let token = reset_token(&app).await;
+
+    let response = reset_password(&app, &token, "short").await;
+
+    assert_is_redirect_to(&response, &format!("/login/reset-password?token={}", token));
+    assert_eq!(
+        flash_message(&response),
+        "Passwords must be at least 12 characters long"
+    );
+    // The link can still be used.
+    let response = reset_password(&app, &token, NEW_PASSWORD).await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn both_passwords_must_match() {
+    let app = spawn_app().await;
+    let token = reset_token(&app).await;
+
+    let response = app
+        .form_post(
+            "/login/reset-password",
+            serde_json::json!({
+                "token": token,
+                "new_password": NEW_PASSWORD,
+                "new_password_check": "another-new-password",
+            }),
+        )
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(flash_message(&response), "The two passwords do not match");
+}
+
+#[tokio::test]
+async fn a_reset_ends_the_logins_in_progress() {
+    let app = spawn_app().await;
+    let token = reset_token(&app).await;
+    let response = login_with(&app, &app.test_user.password).await;
+    assert_is_redirect_to(&response, "/login/two-factor");
+
+    reset_password(&app, &token, NEW_PASSWORD).await;
+    let response = app
+        .post_two_factor_code(&app.test_user.two_factor_code())
+        .await;
+
+    assert_is_redirect_to(&response, "/login");
+}
@@ -0,0 +1,171 @@
+//! tests/api/roles.rs
+
+use blog_backend::api_tokens::ApiTokenScope;
+use hyper::StatusCode;
+
+use crate::helper::{spawn_app, TestApp, TestUser};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn put_role(app: &TestApp, username: &str, role: &str) -> reqwest::Response {
+    app.api_client
+        .put(&format!("{}/admin/users/{}/role", &app.address, username))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({ "role": role }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn get_role(app: &TestApp, username: &str) -> String {
+    sqlx::query_scalar!("SELECT role FROM users WHERE username = $1", username)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn editors_cannot_publish() {
+    let app = spawn_app().await;
+    app.set_test_user_role("editor").await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn viewers_cannot_publish() {
+    let app = spawn_app().await;
+    app.set_test_user_role("viewer").await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn publishing_tokens_stop_working_once_their_owner_is_demoted() {
+    let app = spawn_app().await;
+    let token = app.create_api_token(&[ApiTokenScope::Publish]).await;
+    app.set_test_user_role("editor").await;
+
+    let response = app
+        .post_newsletters_with_token(&newsletter_request_body(), &token)
+        .await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn editors_can_preview_issues_but_viewers_cannot() {
+    let app = spawn_app().await;
+
+    app.set_test_user_role("editor").await;
+    let response = app
+        .post_newsletter_preview(&newsletter_request_body())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let preview: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(preview["title"], "Newsletter title");
+    assert_eq!(preview["content"]["html"], "<p>Newsletter body as HTML</p>");
+
+    app.set_test_user_role("viewer").await;
+    let response = app
+        .post_newsletter_preview(&newsletter_request_body())
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn previews_are_not_stored() {
+    let app = spawn_app().await;
+    app.set_test_user_role("editor").await;
+
+    let response = app
+        .post_newsletter_preview(&newsletter_request_body())
+        .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let issues = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues, 0);
+}
+
+#[tokio::test]
+async fn editors_can_read_subscribers_but_viewers_cannot() {
+    let app = spawn_app().await;
+
+    app.set_test_user_role("editor").await;
+    assert_eq!(app.get_admin("subscribers").await.status(), StatusCode::OK);
+    app.set_test_user_role("viewer").await;
+    assert_eq!(
+        app.get_admin("subscribers").await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[tokio::test]
+async fn viewers_can_view_newsletter_stats() {
+    let app = spawn_app().await;
+    app.set_test_user_role("viewer").await;
+
+    let response = app
+        .get_newsletter_issue_stats("00000000-0000-0000-0000-000000000000")
+        .await;
+
+    // Past authorization, the issue is looked up.
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn owners_can_change_the_role_of_users() {
+    let app = spawn_app().await;
+    let user = TestUser::generate();
+    user.store(&app.db_pool).await;
+
+    let response = put_role(&app, &user.username, "editor").await;
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(get_role(&app, &user.username).await, "editor");
+}
+
+#[tokio::test]
+async fn editors_cannot_change_the_role_of_users() {
+    let app = spawn_app().await;
+    app.set_test_user_role("editor").await;
+
+    let response = put_role(&app, &app.test_user.username, "owner").await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(get_role(&app, &app.test_user.username).await, "editor");
+}
+
+#[tokio::test]
+async fn the_last_owner_cannot_be_demoted() {
+    let app = spawn_app().await;
+
+    let response = put_role(&app, &app.test_user.username, "viewer").await;
+
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(get_role(&app, &app.test_user.username).await, "owner");
+}
+
+#[tokio::test]
+async fn unknown_roles_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = put_role(&app, &app.test_user.username, "superuser").await;
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
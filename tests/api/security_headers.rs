@@ -0,0 +1,87 @@
+//! tests/api/security_headers.rs
+
+use hyper::header;
+
+use crate::helper::{spawn_app, spawn_app_with};
+
+#[tokio::test]
+async fn pages_are_served_with_security_headers() {
+    let app = spawn_app().await;
+
+    for path in ["/", "/login"] {
+        let response = app
+            .api_client
+            .get(&format!("{}{}", &app.address, path))
+            .send()
+            .await
+            .unwrap();
+
+        let headers = response.headers();
+        assert!(headers.contains_key(header::CONTENT_SECURITY_POLICY));
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(
+            headers[header::REFERRER_POLICY],
+            "strict-origin-when-cross-origin"
+        );
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    }
+}
+
+#[tokio::test]
+async fn there_is_no_hsts_locally() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!("{}/login", &app.address))
+        .await
+        .unwrap();
+
+    assert!(!response
+        .headers()
+        .contains_key(header::STRICT_TRANSPORT_SECURITY));
+}
+
+#[tokio::test]
+async fn hsts_is_sent_when_configured() {
+    let app = spawn_app_with(|c| c.security_headers.hsts_max_age_seconds = Some(600)).await;
+
+    let response = reqwest::get(&format!("{}/health_check", &app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        response.headers()[header::STRICT_TRANSPORT_SECURITY],
+        "max-age=600; includeSubDomains"
+    );
+}
+
+#[tokio::test]
+async fn cookies_are_http_only_and_same_site() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": "random-username",
+            "password": "random-password"
+        }))
+        .await;
+
+    let flash = response.cookies().find(|c| c.name() == "_flash").unwrap();
+    assert!(flash.http_only());
+    assert!(flash.same_site_lax());
+    assert!(!flash.secure());
+}
+
+#[tokio::test]
+async fn cookies_are_secure_when_configured() {
+    let app = spawn_app_with(|c| c.security_headers.secure_cookies = true).await;
+
+    // A fresh client, since the CSRF cookie of the test client is not sent over HTTP anymore.
+    let response = reqwest::get(&format!("{}/login", &app.address))
+        .await
+        .unwrap();
+
+    let csrf = response.cookies().find(|c| c.name() == "_csrf").unwrap();
+    assert!(csrf.secure());
+    assert!(csrf.http_only());
+    assert!(csrf.same_site_strict());
+}
@@ -0,0 +1,167 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helper::{
+    assert_is_redirect_to, batch_response, create_confirmed_subscriber, spawn_app_until, TestApp,
+};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+/// Waits for the newsletter being published to reach the email API.
+async fn wait_for_delivery_to_start(app: &TestApp) {
+    // Confirming the subscriber already sent an email: look for the issue itself.
+    while !app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .any(|request| request.url.path() == "/email/batch")
+    {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn the_server_stops_accepting_connections_once_asked_to_stop() {
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (app, server) = spawn_app_until(|_| {}, async move {
+        let _ = stopped.await;
+    })
+    .await;
+
+    stop.send(()).unwrap();
+    let outcome = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("The server did not shut down in time.");
+
+    assert!(outcome.unwrap().is_ok());
+    let response = app
+        .api_client
+        .get(&format!("{}/health_check", &app.address))
+        .send()
+        .await;
+    assert!(response.is_err());
+}
+
+#[tokio::test]
+async fn requests_in_flight_complete_before_shutting_down() {
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (app, server) = spawn_app_until(|_| {}, async move {
+        let _ = stopped.await;
+    })
+    .await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1).set_delay(Duration::from_secs(1)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let (response, _) = tokio::join!(app.post_newsletters(newsletter_request_body()), async {
+        wait_for_delivery_to_start(&app).await;
+        stop.send(()).unwrap();
+    });
+
+    assert_eq!(response.status().as_u16(), 200);
+    let outcome = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("The server did not shut down in time.");
+    assert!(outcome.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn emails_sent_in_the_background_are_waited_for_before_shutting_down() {
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (app, server) = spawn_app_until(|_| {}, async move {
+        let _ = stopped.await;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(1)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // The password reset email is sent after answering the request.
+    let response = app
+        .form_post(
+            "/login/forgot-password",
+            serde_json::json!({ "email": app.test_user.email }),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_is_redirect_to(&response, "/login");
+    while app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty()
+    {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let stopping = Instant::now();
+    stop.send(()).unwrap();
+
+    let outcome = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("The server did not shut down in time.");
+    assert!(outcome.unwrap().is_ok());
+    assert!(stopping.elapsed() >= Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn requests_still_in_flight_at_the_deadline_are_abandoned() {
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (app, server) = spawn_app_until(
+        |c| c.application.shutdown_deadline_seconds = 1,
+        async move {
+            let _ = stopped.await;
+        },
+    )
+    .await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+        .mount(&app.email_server)
+        .await;
+
+    let publishing = tokio::spawn({
+        let request = app
+            .api_client
+            .post(&format!("{}/newsletters", &app.address))
+            .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+            .header(
+                blog_backend::authentication::TWO_FACTOR_CODE_HEADER,
+                app.test_user.two_factor_code(),
+            )
+            .json(&newsletter_request_body());
+        async move { request.send().await }
+    });
+    wait_for_delivery_to_start(&app).await;
+    stop.send(()).unwrap();
+
+    let outcome = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("The server did not give up on the request at the deadline.");
+    assert!(outcome.unwrap().is_ok());
+    publishing.abort();
+}
@@ -4,7 +4,7 @@ use wiremock::{
     Mock, ResponseTemplate,
 };
 
-use crate::helper::spawn_app;
+use crate::helper::{solve_challenge, spawn_app, spawn_app_with};
 
 #[tokio::test]
 async fn susbcribe_returns_a_200_for_valid_form_data() {
@@ -86,6 +86,108 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
 
+#[tokio::test]
+async fn confirmation_emails_are_tagged_and_sent_through_the_transactional_stream() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["MessageStream"], "outbound");
+    assert_eq!(body["Tag"], "confirmation");
+}
+
+#[tokio::test]
+async fn subscribing_again_with_an_expired_link_sends_a_new_one() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '73 hours'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_link = app.get_confirmation_links(&email_requests[1]).html;
+    let response = reqwest::get(confirmation_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_sends_nothing() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request).html;
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn subscribe_asks_to_check_the_inbox() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<h1>Check your inbox</h1>"));
+    assert!(html.contains("ursula_le_guin@gmail.com"));
+}
+
 #[tokio::test]
 async fn subscribe_returns_a_422_when_data_is_missing() {
     let app = spawn_app().await;
@@ -130,3 +232,253 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
         );
     }
 }
+
+#[tokio::test]
+async fn subscribe_lists_the_invalid_fields_on_the_form_error_page() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=Ursula&email=definitely-not-an-email".into())
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<li>definitely-not-an-email is not a valid subscriber email.</li>"));
+}
+
+#[tokio::test]
+async fn subscribe_accepts_json() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({ "status": "pending_confirmation" })
+    );
+}
+
+#[tokio::test]
+async fn subscribe_returns_field_errors_for_invalid_json() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "",
+            "email": "definitely-not-an-email"
+        }))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "invalid_details");
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0]["field"], "name");
+    assert_eq!(errors[1]["field"], "email");
+    assert_eq!(
+        errors[1]["message"],
+        "definitely-not-an-email is not a valid subscriber email."
+    );
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_422_when_json_fields_are_missing() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({ "name": "le guin" }))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "status": "invalid_details",
+            "errors": [{ "field": "email", "message": "The email is missing." }]
+        })
+    );
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_field_error_for_malformed_json() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(&format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/json")
+        .body(r#"{"name": "le guin", "email": "#)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "invalid_details");
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["field"], "body");
+    assert!(errors[0]["message"].as_str().unwrap().contains("EOF"));
+}
+
+#[tokio::test]
+async fn subscribe_pretends_to_succeed_when_the_honeypot_is_filled_in() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&website=http%3A%2F%2Fspam.com";
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn subscribe_rejects_disposable_email_domains() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40mailinator.com".into())
+        .await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("Addresses from mailinator.com cannot be used to subscribe."));
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_429_when_an_ip_makes_too_many_attempts() {
+    let app = spawn_app_with(|c| c.subscriptions.max_attempts_per_ip_per_hour = 3).await;
+
+    for _ in 0..3 {
+        let response = app.post_subscriptions("name=&email=".into()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    let response = app.post_subscriptions("name=&email=".into()).await;
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn attempts_are_counted_per_client_behind_a_trusted_proxy() {
+    let app = spawn_app_with(|c| {
+        c.subscriptions.max_attempts_per_ip_per_hour = 1;
+        c.application.trusted_proxies = vec!["127.0.0.1".into()];
+    })
+    .await;
+    let post_from = |client: &'static str| {
+        app.api_client
+            .post(&format!("{}/subscriptions", &app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("X-Forwarded-For", client)
+            .body("name=&email=")
+            .send()
+    };
+
+    let response = post_from("203.0.113.1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = post_from("203.0.113.2").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = post_from("203.0.113.1").await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn forwarded_addresses_are_ignored_unless_the_proxy_is_trusted() {
+    let app = spawn_app_with(|c| c.subscriptions.max_attempts_per_ip_per_hour = 1).await;
+    let post_from = |client: &'static str| {
+        app.api_client
+            .post(&format!("{}/subscriptions", &app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("X-Forwarded-For", client)
+            .body("name=&email=")
+            .send()
+    };
+
+    let response = post_from("203.0.113.1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = post_from("203.0.113.2").await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_429_when_a_domain_gets_too_many_subscriptions() {
+    let app = spawn_app_with(|c| c.subscriptions.max_subscriptions_per_domain_per_hour = 1).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .post_subscriptions("name=butler&email=octavia%40EXAMPLE.com".into())
+        .await;
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn subscribe_requires_a_solved_challenge_when_proof_of_work_is_enabled() {
+    let app = spawn_app_with(|c| c.subscriptions.proof_of_work_difficulty = Some(8)).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let body = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com"
+    });
+    let response = app.post_subscriptions_json(body.clone()).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let challenge = app.get_subscription_challenge().await;
+    assert_eq!(challenge["difficulty"], 8);
+    let challenge = challenge["challenge"].as_str().unwrap();
+    let mut body = body;
+    body["challenge"] = challenge.into();
+    body["nonce"] = solve_challenge(challenge, 8).into();
+    let response = app.post_subscriptions_json(body.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // A solution is only accepted once.
+    let response = app.post_subscriptions_json(body).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,211 @@
+//! tests/api/tracking.rs
+
+use hyper::StatusCode;
+use wiremock::{
+    matchers::{method, path},
+    Mock,
+};
+
+use crate::helper::{batch_response, create_confirmed_subscriber, spawn_app, TestApp};
+
+struct TrackingLinks {
+    open: reqwest::Url,
+    clicks: Vec<reqwest::Url>,
+}
+
+/// Extract the tracking links embedded in the HTML body of a newsletter issue.
+fn get_tracking_links(app: &TestApp, email_request: &wiremock::Request) -> TrackingLinks {
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let links: Vec<_> = linkify::LinkFinder::new()
+        .links(body[0]["HtmlBody"].as_str().unwrap())
+        .filter(|l| *l.kind() == linkify::LinkKind::Url)
+        .map(|l| {
+            let mut link = reqwest::Url::parse(l.as_str()).unwrap();
+            assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+            link.set_port(Some(app.port)).unwrap();
+            link
+        })
+        .collect();
+
+    let (open, clicks): (Vec<_>, Vec<_>) = links
+        .into_iter()
+        .partition(|l| l.path().starts_with("/t/open/"));
+    assert_eq!(open.len(), 1);
+
+    TrackingLinks {
+        open: open.into_iter().next().unwrap(),
+        clicks,
+    }
+}
+
+async fn publish_tracked_issue(app: &TestApp, tracking: bool) -> (String, wiremock::Request) {
+    create_confirmed_subscriber(app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_response(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Read it at https://example.com/post",
+                "html": r#"<p>Read it <a href="https://example.com/post">here</a></p>"#,
+            },
+            "tracking": tracking,
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    (
+        body["newsletter_issue_id"].as_str().unwrap().to_owned(),
+        email_request,
+    )
+}
+
+#[tokio::test]
+async fn opening_an_issue_is_recorded() {
+    // Arrange
+    let app = spawn_app().await;
+    let (newsletter_issue_id, email_request) = publish_tracked_issue(&app, true).await;
+    let links = get_tracking_links(&app, &email_request);
+
+    // Act
+    let response = reqwest::get(links.open).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "image/gif");
+
+    let stats: serde_json::Value = app
+        .get_newsletter_issue_stats(&newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["opens"], 1);
+    assert_eq!(stats["unique_opens"], 1);
+    assert_eq!(stats["clicks"], 0);
+}
+
+#[tokio::test]
+async fn clicking_a_link_redirects_to_the_original_target_and_is_recorded() {
+    // Arrange
+    let app = spawn_app().await;
+    let (newsletter_issue_id, email_request) = publish_tracked_issue(&app, true).await;
+    let links = get_tracking_links(&app, &email_request);
+    assert_eq!(links.clicks.len(), 1);
+
+    // Act
+    let response = app
+        .api_client
+        .get(links.clicks[0].clone())
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()["Location"], "https://example.com/post");
+
+    let stats: serde_json::Value = app
+        .get_newsletter_issue_stats(&newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["clicks"], 1);
+    assert_eq!(stats["links"][0]["url"], "https://example.com/post");
+    assert_eq!(stats["links"][0]["unique_clicks"], 1);
+}
+
+#[tokio::test]
+async fn plain_text_links_are_tracked_too() {
+    // Arrange
+    let app = spawn_app().await;
+    let (_, email_request) = publish_tracked_issue(&app, true).await;
+
+    // Assert
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let text = body[0]["TextBody"].as_str().unwrap();
+    assert!(text.starts_with("Read it at http://127.0.0.1/t/"));
+    assert!(!text.contains("example.com"));
+}
+
+#[tokio::test]
+async fn issues_can_opt_out_of_tracking() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (newsletter_issue_id, email_request) = publish_tracked_issue(&app, false).await;
+
+    // Assert
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(
+        body[0]["HtmlBody"],
+        r#"<p>Read it <a href="https://example.com/post">here</a></p>"#
+    );
+    assert_eq!(body[0]["TextBody"], "Read it at https://example.com/post");
+
+    let stats: serde_json::Value = app
+        .get_newsletter_issue_stats(&newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["tracking_enabled"], false);
+}
+
+#[tokio::test]
+async fn tampered_tracking_tokens_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let (_, email_request) = publish_tracked_issue(&app, true).await;
+    let mut link = get_tracking_links(&app, &email_request).clicks.remove(0);
+    let token = link.path().trim_start_matches("/t/").to_owned();
+    let (payload, signature) = token.split_once('.').unwrap();
+    let forged_signature = signature.chars().rev().collect::<String>();
+    link.set_path(&format!("/t/{}.{}", payload, forged_signature));
+
+    // Act
+    let response = app.api_client.get(link).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn issue_stats_require_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+    let (newsletter_issue_id, _) = publish_tracked_issue(&app, true).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/newsletters/{}/stats",
+            &app.address, newsletter_issue_id
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
@@ -0,0 +1,244 @@
+//! tests/api/two_factor.rs
+
+use blog_backend::authentication::TWO_FACTOR_CODE_HEADER;
+use blog_backend::two_factor::{time_step, TotpSecret};
+use chrono::Utc;
+use hyper::StatusCode;
+
+use crate::helper::{assert_is_redirect_to, spawn_app, TestApp};
+
+async fn log_in_with_password(app: &TestApp) {
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &app.test_user.password
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/login/two-factor");
+}
+
+fn flash_message(response: &reqwest::Response) -> String {
+    response
+        .cookies()
+        .find(|c| c.name() == "_flash")
+        .unwrap()
+        .value()
+        .to_owned()
+}
+
+async fn disable_two_factor(app: &TestApp) {
+    sqlx::query!(
+        "UPDATE users SET totp_enabled = false, totp_secret = NULL WHERE user_id = $1",
+        app.test_user.user_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+/// Enrolls the test user again, returning their new secret and recovery codes.
+async fn enroll(app: &TestApp) -> (TotpSecret, Vec<String>) {
+    disable_two_factor(app).await;
+    let response = app.post_admin("two-factor", &serde_json::json!({})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let enrollment: serde_json::Value = response.json().await.unwrap();
+    let secret = TotpSecret::from_base32(enrollment["secret"].as_str().unwrap()).unwrap();
+
+    let code = secret.code(time_step(Utc::now()));
+    let response = app
+        .post_admin("two-factor/confirm", &serde_json::json!({ "code": code }))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let recovery_codes = body["recovery_codes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|code| code.as_str().unwrap().to_owned())
+        .collect();
+    (secret, recovery_codes)
+}
+
+#[tokio::test]
+async fn users_with_two_factor_authentication_log_in_with_a_code_after_their_password() {
+    let app = spawn_app().await;
+
+    log_in_with_password(&app).await;
+    let response = app
+        .post_two_factor_code(&app.test_user.two_factor_code())
+        .await;
+
+    assert_is_redirect_to(&response, "/");
+}
+
+#[tokio::test]
+async fn users_without_two_factor_authentication_log_in_with_their_password_only() {
+    let app = spawn_app().await;
+    disable_two_factor(&app).await;
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &app.test_user.password
+        }))
+        .await;
+
+    assert_is_redirect_to(&response, "/");
+}
+
+#[tokio::test]
+async fn a_wrong_code_is_rejected() {
+    let app = spawn_app().await;
+    let secret = TotpSecret::from_base32(&app.test_user.totp_secret).unwrap();
+    let stale_code = secret.code(time_step(Utc::now()) - 10);
+
+    log_in_with_password(&app).await;
+    let response = app.post_two_factor_code(&stale_code).await;
+
+    assert_is_redirect_to(&response, "/login/two-factor");
+    assert_eq!(flash_message(&response), "Authentication failed");
+}
+
+#[tokio::test]
+async fn a_code_cannot_be_used_twice() {
+    let app = spawn_app().await;
+    let code = app.test_user.two_factor_code();
+
+    log_in_with_password(&app).await;
+    let response = app.post_two_factor_code(&code).await;
+    assert_is_redirect_to(&response, "/");
+    log_in_with_password(&app).await;
+    let response = app.post_two_factor_code(&code).await;
+
+    assert_is_redirect_to(&response, "/login/two-factor");
+}
+
+#[tokio::test]
+async fn the_second_step_requires_a_password_login_first() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_two_factor_code(&app.test_user.two_factor_code())
+        .await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn enrollment_provides_a_provisioning_uri_and_recovery_codes() {
+    let app = spawn_app().await;
+    disable_two_factor(&app).await;
+
+    let response = app.post_admin("two-factor", &serde_json::json!({})).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let enrollment: serde_json::Value = response.json().await.unwrap();
+    let uri = enrollment["provisioning_uri"].as_str().unwrap();
+    assert!(uri.starts_with("otpauth://totp/"));
+    assert!(uri.contains(&app.test_user.username));
+    assert!(uri.contains(enrollment["secret"].as_str().unwrap()));
+
+    let (_, recovery_codes) = enroll(&app).await;
+    assert_eq!(recovery_codes.len(), 10);
+}
+
+#[tokio::test]
+async fn enrollment_is_only_enabled_with_a_valid_code() {
+    let app = spawn_app().await;
+    disable_two_factor(&app).await;
+    app.post_admin("two-factor", &serde_json::json!({})).await;
+
+    let response = app
+        .post_admin("two-factor/confirm", &serde_json::json!({ "code": "abc" }))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn enrollment_cannot_start_over_once_enabled() {
+    let app = spawn_app().await;
+
+    let response = app.post_admin("two-factor", &serde_json::json!({})).await;
+
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn a_recovery_code_can_replace_a_code_once() {
+    let app = spawn_app().await;
+    let (_, recovery_codes) = enroll(&app).await;
+
+    log_in_with_password(&app).await;
+    let response = app.post_two_factor_code(&recovery_codes[0]).await;
+    assert_is_redirect_to(&response, "/");
+    log_in_with_password(&app).await;
+    let response = app.post_two_factor_code(&recovery_codes[0]).await;
+
+    assert_is_redirect_to(&response, "/login/two-factor");
+}
+
+#[tokio::test]
+async fn publishing_requires_a_two_factor_code() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(&format!("{}/newsletters", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn publishing_requires_two_factor_authentication_to_be_enabled() {
+    let app = spawn_app().await;
+    disable_two_factor(&app).await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn publishing_scripts_can_reuse_a_code_until_the_next_one() {
+    let app = spawn_app().await;
+    let code = app.test_user.two_factor_code();
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    for _ in 0..2 {
+        let response = reqwest::Client::new()
+            .post(&format!("{}/newsletters", &app.address))
+            .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+            .header(TWO_FACTOR_CODE_HEADER, &code)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}